@@ -81,20 +81,23 @@ $(function () {
                 collections = data.names;
                 console.assert(data.names.length > 0);
                 console.assert(collections.length == data.count, "Invalid collections response - count does not fit");
+                // collections not accessible to user are not listed, so position in list
+                // is not collection number
+                let numbers = data.numbers || data.names.map((_name, i) => i);
                 let cselect = $("#collections select").empty();
                 for (let i = 0; i < data.names.length; i++) {
-                    $("<option>").attr("value", i).text(data.names[i]).appendTo(cselect);
+                    $("<option>").attr("value", numbers[i]).text(data.names[i]).appendTo(cselect);
                 }
                 if (data.names.length > 1) {
                     $("#collections").show();
-                    let storedIndex = parseInt(window.localStorage.getItem("audioserve_collection") || 0);
-                    let collIndex = storedIndex < data.names.length ? storedIndex : 0;
+                    let storedIndex = parseInt(window.localStorage.getItem("audioserve_collection") || numbers[0]);
+                    let collIndex = numbers.includes(storedIndex) ? storedIndex : numbers[0];
                     cselect.val(collIndex);
                     setCollection(collIndex);
 
                 } else {
                     $("#collections").hide();
-                    setCollection(0);
+                    setCollection(numbers[0]);
                     window.localStorage.removeItem("audioserve_collection");
 
                 }
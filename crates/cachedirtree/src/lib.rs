@@ -12,7 +12,8 @@ use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
 use std::borrow;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::Duration;
@@ -73,7 +74,7 @@ impl DirCache {
                         watcher(tx, Duration::from_secs(options.watch_delay)).unwrap();
                     watcher
                         .watch(
-                            root,
+                            &root,
                             if options.watch_recursively {
                                 RecursiveMode::Recursive
                             } else {
@@ -83,7 +84,13 @@ impl DirCache {
                         .unwrap();
 
                     loop {
-                        match rx.recv() {
+                        if dc.inner.stopped.load(Ordering::Relaxed) {
+                            debug!("Stopped watching directory {:?}", root);
+                            // wake up updater, so it can finish too
+                            cond.notify();
+                            break;
+                        }
+                        match rx.recv_timeout(Duration::from_secs(1)) {
                             Ok(event) => {
                                 debug!("directory change - event {:?}", event);
                                 match event {
@@ -94,6 +101,7 @@ impl DirCache {
                                     _ => (),
                                 }
                             }
+                            Err(RecvTimeoutError::Timeout) => (),
                             Err(e) => error!("watch error: {:?}", e),
                         }
                     }
@@ -103,6 +111,9 @@ impl DirCache {
 
             let _updater = thread::spawn(move || loop {
                 cond2.wait();
+                if dc2.inner.stopped.load(Ordering::Relaxed) {
+                    break;
+                }
                 match dc2.load() {
                     Ok(_) => debug!("Directory cache updated"),
                     Err(e) => error!("Failed to update directory cache: error {}", e),
@@ -139,12 +150,18 @@ impl DirCache {
     pub fn wait_ready(&self) {
         self.inner.wait_ready()
     }
+
+    /// Stops watching changes, so background threads can finish and cache can be dropped
+    pub fn stop(&self) {
+        self.inner.stopped.store(true, Ordering::Relaxed)
+    }
 }
 struct DirCacheInner {
     cache: RwLock<Option<DirTree>>,
     root: PathBuf,
     options: Options,
     ready_cond: CondAll,
+    stopped: AtomicBool,
 }
 
 impl DirCacheInner {
@@ -154,6 +171,7 @@ impl DirCacheInner {
             cache: RwLock::new(None),
             options,
             ready_cond: CondAll::new(),
+            stopped: AtomicBool::new(false),
         }
     }
 
@@ -223,6 +241,18 @@ mod tests {
         assert_eq!(4, res.len())
     }
 
+    #[test]
+    fn test_stop() {
+        let opts = OptionsBuilder::default().watch_changes(true).build().unwrap();
+        let c = DirCache::new_with_options("test_data", opts);
+        c.wait_ready();
+        assert!(Arc::strong_count(&c.inner) > 1);
+        c.stop();
+        thread::sleep(Duration::from_secs(2));
+        // background threads finished
+        assert_eq!(1, Arc::strong_count(&c.inner));
+    }
+
     #[test]
     fn test_search_collected() {
         let c = DirCache::new("test_data");
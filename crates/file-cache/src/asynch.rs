@@ -1,10 +1,19 @@
-use super::{error::Error, CacheInner};
+use super::{error::Error, CacheInner, CacheOptions, EntryInfo};
+use std::collections::HashMap;
 use std::fs;
+use std::io;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use tokio;
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
 use tokio::task::spawn_blocking;
 
+/// Writes to entry are not notified, so tail reader checks for new data in this interval
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 impl From<tokio::task::JoinError> for Error {
     fn from(_f: tokio::task::JoinError) -> Self {
         Error::Executor
@@ -17,17 +26,42 @@ fn invert<T>(x: Option<Result<T>>) -> Result<Option<T>> {
 
 type Result<T> = std::result::Result<T, Error>;
 type CacheInnerType = Arc<RwLock<CacheInner>>;
+type AddingType = Arc<Mutex<HashMap<String, Adding>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Progress {
+    Writing,
+    Finished,
+    Aborted,
+}
+
+// entry which is being added, shared by its Finisher and tail readers
+struct Adding {
+    progress: watch::Receiver<Progress>,
+    readers: Arc<AtomicUsize>,
+}
 
 #[derive(Clone)]
 pub struct Cache {
     inner: CacheInnerType,
+    adding: AddingType,
 }
 
 impl Cache {
     pub fn new<P: AsRef<Path>>(root: P, max_size: u64, max_files: u64) -> Result<Self> {
+        Cache::with_options(root, max_size, max_files, CacheOptions::default())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(
+        root: P,
+        max_size: u64,
+        max_files: u64,
+        options: CacheOptions,
+    ) -> Result<Self> {
         let root = root.as_ref().into();
-        CacheInner::new(root, max_size, max_files).map(|cache| Cache {
+        CacheInner::new(root, max_size, max_files, options).map(|cache| Cache {
             inner: Arc::new(RwLock::new(cache)),
+            adding: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
@@ -37,20 +71,38 @@ impl Cache {
         (c.max_files - c.num_files, c.max_size - c.size)
     }
 
+    /// Adds new entry, while it's being written it can be read by `tail`
     pub async fn add<S: AsRef<str>>(&self, key: S) -> Result<(tokio::fs::File, Finisher)> {
         let cache = self.inner.clone();
+        let adding = self.adding.clone();
         let key = key.as_ref().to_string();
         spawn_blocking(move || {
             let mut c = cache.write().expect("Cannot lock cache");
             c.add(key.clone())
                 .and_then(|f| f.try_clone().map_err(|e| e.into()).map(|f2| (f, f2)))
                 .map(|(f, f2)| {
+                    let (sender, progress) = watch::channel(Progress::Writing);
+                    let readers = Arc::new(AtomicUsize::new(0));
+                    adding.lock().unwrap().insert(
+                        key.clone(),
+                        Adding {
+                            progress,
+                            readers: readers.clone(),
+                        },
+                    );
                     (
                         tokio::fs::File::from_std(f),
                         Finisher {
                             cache: cache.clone(),
-                            key: key,
+                            key: key.clone(),
                             file: f2,
+                            notifier: Notifier {
+                                adding,
+                                key,
+                                sender,
+                                readers,
+                                done: false,
+                            },
                         },
                     )
                 })
@@ -58,6 +110,33 @@ impl Cache {
         .await?
     }
 
+    /// Opens reader of entry, which is being added - reader follows writes to the entry
+    /// till it's committed, returns None if entry is not being added
+    pub async fn tail<S: AsRef<str>>(&self, key: S) -> Result<Option<TailReader>> {
+        let cache = self.inner.clone();
+        let adding = self.adding.clone();
+        let key = key.as_ref().to_string();
+        let r = spawn_blocking(move || {
+            // read lock prevents entry from being committed (and moved) meanwhile
+            let c = cache.read().expect("Cannot lock cache");
+            let adding = adding.lock().unwrap();
+            match (c.opened.get(&key), adding.get(&key)) {
+                (Some(file_key), Some(entry)) => {
+                    let f = fs::File::open(c.partial_path(file_key))?;
+                    entry.readers.fetch_add(1, Ordering::SeqCst);
+                    Ok(Some(TailReader {
+                        file: tokio::fs::File::from_std(f),
+                        progress: entry.progress.clone(),
+                        readers: entry.readers.clone(),
+                    }))
+                }
+                _ => Ok(None),
+            }
+        })
+        .await?;
+        r
+    }
+
     pub async fn get<S: AsRef<str>>(&self, key: S) -> Result<Option<tokio::fs::File>> {
         let key = key.as_ref().to_string();
         let inner = self.inner.clone();
@@ -84,6 +163,74 @@ impl Cache {
         invert(r)
     }
 
+    /// Removes all entries with key matching predicate, returns number of removed entries
+    pub async fn remove_matching<F>(&self, predicate: F) -> Result<u64>
+    where
+        F: Fn(&str) -> bool + Send + 'static,
+    {
+        let cache = self.inner.clone();
+        spawn_blocking(move || super::remove_matching(&cache, predicate)).await?
+    }
+
+    pub async fn remove<S: AsRef<str>>(&self, key: S) -> Result<()> {
+        let cache = self.inner.clone();
+        let key = key.as_ref().to_string();
+        spawn_blocking(move || {
+            let mut c = cache.write().expect("Cannot lock cache");
+            c.remove(key)
+        })
+        .await?
+    }
+
+    /// Lists cached entries from least recently used
+    pub async fn list(&self) -> Result<Vec<EntryInfo>> {
+        let cache = self.inner.clone();
+        spawn_blocking(move || cache.read().unwrap().list())
+            .await
+            .map_err(From::from)
+    }
+
+    /// Removes expired entries and evicts entries over high watermark,
+    /// returns tuple (expired, evicted)
+    pub async fn sweep(&self) -> Result<(u64, u64)> {
+        let cache = self.inner.clone();
+        spawn_blocking(move || {
+            let mut c = cache.write().expect("Cannot lock cache");
+            c.sweep()
+        })
+        .await?
+    }
+
+    /// Runs `sweep` periodically, never ends, so it should be spawned as separate task
+    pub async fn sweep_periodically(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        // first tick is immediate
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match self.sweep().await {
+                Ok((0, 0)) => (),
+                Ok((expired, evicted)) => debug!(
+                    "Cache sweep removed {} expired and {} evicted entries",
+                    expired, evicted
+                ),
+                Err(e) => error!("Cache sweep failed: {}", e),
+            }
+        }
+    }
+
+    /// returns tuple (number of files, total size)
+    pub fn usage(&self) -> (u64, u64) {
+        let c = self.inner.read().unwrap();
+        (c.num_files, c.size)
+    }
+
+    /// returns tuple (max files, max size)
+    pub fn limits(&self) -> (u64, u64) {
+        let c = self.inner.read().unwrap();
+        (c.max_files, c.max_size)
+    }
+
     pub async fn save_index(&self) -> Result<()> {
         let cache = self.inner.clone();
         spawn_blocking(move || {
@@ -99,25 +246,122 @@ impl Cache {
     }
 }
 
+// informs tail readers about end of writing, if dropped without commit entry is aborted
+struct Notifier {
+    adding: AddingType,
+    key: String,
+    sender: watch::Sender<Progress>,
+    readers: Arc<AtomicUsize>,
+    done: bool,
+}
+
+impl Notifier {
+    fn finish(mut self, progress: Progress) {
+        self.sender.send(progress).ok();
+        self.done = true;
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.adding.lock().unwrap().remove(&self.key);
+        if !self.done {
+            self.sender.send(Progress::Aborted).ok();
+        }
+    }
+}
+
 pub struct Finisher {
     pub(crate) cache: CacheInnerType,
     pub(crate) key: String,
     pub(crate) file: fs::File,
+    notifier: Notifier,
 }
 
 impl Finisher {
-    pub async fn commit(mut self) -> Result<()> {
-        spawn_blocking(move || {
-            let mut c = self.cache.write().expect("Cannot lock cache");
-            c.finish(self.key, &mut self.file)
+    pub async fn commit(self) -> Result<()> {
+        let Finisher {
+            cache,
+            key,
+            mut file,
+            notifier,
+        } = self;
+        let res = spawn_blocking(move || {
+            let mut c = cache.write().expect("Cannot lock cache");
+            c.finish(key, &mut file)
         })
-        .await?
+        .await?;
+        // all data were already written, so tail readers can read them to the end,
+        // even if entry could not be added to cache
+        notifier.finish(Progress::Finished);
+        res
     }
 
     pub async fn roll_back(self) -> Result<()> {
-        spawn_blocking(move || super::cleanup(&self.cache, self.key))
-            .await
-            .map_err(From::from)
+        let Finisher {
+            cache,
+            key,
+            notifier,
+            ..
+        } = self;
+        spawn_blocking(move || super::cleanup(&cache, key)).await?;
+        notifier.finish(Progress::Aborted);
+        Ok(())
+    }
+
+    /// true if somebody reads the entry with `tail`
+    pub fn has_tail_readers(&self) -> bool {
+        self.notifier.readers.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// Reads entry while it's being written, on end of data waits for more, until entry is committed
+pub struct TailReader {
+    file: tokio::fs::File,
+    progress: watch::Receiver<Progress>,
+    readers: Arc<AtomicUsize>,
+}
+
+impl TailReader {
+    /// Reads available data, returns 0 only when whole entry was read,
+    /// fails if writing of entry was aborted
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // state must be checked before reading, finished entry is then read completely
+            let progress = *self.progress.borrow();
+            let n = self.file.read(buf).await?;
+            if n > 0 {
+                return Ok(n);
+            }
+            match progress {
+                Progress::Finished => return Ok(0),
+                Progress::Aborted => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "writing of cache entry was aborted",
+                    ))
+                }
+                Progress::Writing => {
+                    let changed =
+                        tokio::time::timeout(TAIL_POLL_INTERVAL, self.progress.changed()).await;
+                    if let Ok(Err(_)) = changed {
+                        // sender is gone, so progress cannot change any more
+                        if *self.progress.borrow() == Progress::Writing {
+                            return Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                "writer of cache entry disappeared",
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for TailReader {
+    fn drop(&mut self) {
+        self.readers.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
@@ -160,4 +404,78 @@ mod tests {
         assert_eq!(MSG, s);
         ()
     }
+
+    async fn read_all(reader: &mut TailReader) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 8];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tail() {
+        env_logger::try_init().ok();
+        let temp_dir = tempdir().unwrap();
+        let c = Cache::new(temp_dir.path(), 10000, 10).unwrap();
+        assert!(c.tail(MY_KEY).await.unwrap().is_none());
+        let (mut f, fin) = c.add(MY_KEY).await.unwrap();
+        f.write_all(&MSG.as_bytes()[..10]).await.unwrap();
+        f.flush().await.unwrap();
+        let mut reader = c.tail(MY_KEY).await.unwrap().unwrap();
+        assert!(fin.has_tail_readers());
+        let read = tokio::spawn(async move { read_all(&mut reader).await });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        f.write_all(&MSG.as_bytes()[10..]).await.unwrap();
+        f.flush().await.unwrap();
+        fin.commit().await.unwrap();
+        let data = read.await.unwrap().unwrap();
+        assert_eq!(MSG.as_bytes(), &data[..]);
+        assert!(c.tail(MY_KEY).await.unwrap().is_none());
+        assert!(c.get(MY_KEY).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tail_commit_failed() {
+        env_logger::try_init().ok();
+        let temp_dir = tempdir().unwrap();
+        // entry will be too big for cache
+        let c = Cache::new(temp_dir.path(), 20, 10).unwrap();
+        let (mut f, fin) = c.add(MY_KEY).await.unwrap();
+        f.write_all(&MSG.as_bytes()[..10]).await.unwrap();
+        f.flush().await.unwrap();
+        let mut reader = c.tail(MY_KEY).await.unwrap().unwrap();
+        let read = tokio::spawn(async move { read_all(&mut reader).await });
+        f.write_all(&MSG.as_bytes()[10..]).await.unwrap();
+        f.flush().await.unwrap();
+        assert!(matches!(fin.commit().await, Err(Error::FileTooBig)));
+        let data = read.await.unwrap().unwrap();
+        assert_eq!(MSG.as_bytes(), &data[..]);
+        assert!(c.get(MY_KEY).await.unwrap().is_none());
+        assert_eq!(
+            0,
+            std::fs::read_dir(temp_dir.path().join(crate::PARTIAL))
+                .unwrap()
+                .count()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tail_aborted() {
+        env_logger::try_init().ok();
+        let temp_dir = tempdir().unwrap();
+        let c = Cache::new(temp_dir.path(), 10000, 10).unwrap();
+        let (mut f, fin) = c.add(MY_KEY).await.unwrap();
+        f.write_all(MSG.as_bytes()).await.unwrap();
+        f.flush().await.unwrap();
+        let mut reader = c.tail(MY_KEY).await.unwrap().unwrap();
+        let read = tokio::spawn(async move { read_all(&mut reader).await });
+        fin.roll_back().await.unwrap();
+        assert!(read.await.unwrap().is_err());
+        assert!(c.get(MY_KEY).await.unwrap().is_none());
+    }
 }
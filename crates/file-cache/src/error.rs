@@ -21,6 +21,12 @@ pub enum Error {
     #[error("key {0} is being added")]
     KeyOpened(String),
 
+    #[error("invalid cache options: {0}")]
+    InvalidOptions(String),
+
+    #[error("cache directory {0:?} is used by another process")]
+    Locked(std::path::PathBuf),
+
     #[error("invalid cache state: {0}")]
     InvalidCacheState(String),
 
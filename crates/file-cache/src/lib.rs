@@ -4,21 +4,22 @@ extern crate rand;
 #[macro_use]
 extern crate log;
 extern crate byteorder;
+extern crate filetime;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use data_encoding::BASE64URL_NOPAD;
 use linked_hash_map::LinkedHashMap;
 use rand::RngCore;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
-//use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 pub use self::error::Error;
 
 #[cfg(feature = "asynch")]
-pub use asynch::{Cache as AsyncCache, Finisher};
+pub use asynch::{Cache as AsyncCache, Finisher, TailReader};
 
 #[cfg(feature = "asynch")]
 mod asynch;
@@ -27,12 +28,66 @@ mod error;
 const PARTIAL: &str = "partial";
 const ENTRIES: &str = "entries";
 const INDEX: &str = "index";
+const JOURNAL: &str = "journal";
+const LOCK: &str = "lock";
+const KEY_SUFFIX: &str = ".key";
+const JOURNAL_ADD: u8 = b'A';
+const JOURNAL_REMOVE: u8 = b'R';
 const MAX_KEY_SIZE: usize = 4096;
 const FILE_KEY_LEN: usize = 32;
 
 type Result<T> = std::result::Result<T, Error>;
 type CacheInnerType = Arc<RwLock<CacheInner>>;
 
+/// Information about cached file
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub key: String,
+    pub size: u64,
+    /// modification time of entry file, which is updated on each access
+    pub last_access: Option<SystemTime>,
+}
+
+/// Optional behaviour of cache, default is just LRU eviction when size or number of files
+/// limit is reached
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    /// entries not accessed for longer time are removed by `sweep`
+    pub max_age: Option<Duration>,
+    /// when limit is reached, entries are evicted until size and number of files
+    /// are below this fraction of limits, so that eviction does not happen on each addition
+    pub low_watermark: f32,
+    /// `sweep` evicts entries (down to low watermark), when size or number of files
+    /// is above this fraction of limits
+    pub high_watermark: f32,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            max_age: None,
+            low_watermark: 1.0,
+            high_watermark: 1.0,
+        }
+    }
+}
+
+impl CacheOptions {
+    fn check(&self) -> Result<()> {
+        if self.low_watermark <= 0.0 || self.low_watermark > 1.0 {
+            return Err(Error::InvalidOptions(
+                "low watermark must be in (0, 1]".into(),
+            ));
+        }
+        if self.high_watermark < self.low_watermark || self.high_watermark > 1.0 {
+            return Err(Error::InvalidOptions(
+                "high watermark must be between low watermark and 1".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct Cache {
     inner: CacheInnerType,
@@ -40,8 +95,17 @@ pub struct Cache {
 
 impl Cache {
     pub fn new<P: AsRef<Path>>(root: P, max_size: u64, max_files: u64) -> Result<Self> {
+        Cache::with_options(root, max_size, max_files, CacheOptions::default())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(
+        root: P,
+        max_size: u64,
+        max_files: u64,
+        options: CacheOptions,
+    ) -> Result<Self> {
         let root = root.as_ref().into();
-        CacheInner::new(root, max_size, max_files).map(|cache| Cache {
+        CacheInner::new(root, max_size, max_files, options).map(|cache| Cache {
             inner: Arc::new(RwLock::new(cache)),
         })
     }
@@ -66,10 +130,37 @@ impl Cache {
         cache.save_index()
     }
 
+    /// Removes all entries with key matching predicate, returns number of removed entries
+    pub fn remove_matching<F: Fn(&str) -> bool>(&self, predicate: F) -> Result<u64> {
+        remove_matching(&self.inner, predicate)
+    }
+
+    pub fn remove<S: AsRef<str>>(&self, key: S) -> Result<()> {
+        let mut cache = self.inner.write().expect("Cannot lock cache");
+        cache.remove(key)
+    }
+
+    /// Lists cached entries from least recently used
+    pub fn list(&self) -> Vec<EntryInfo> {
+        self.inner.read().unwrap().list()
+    }
+
+    /// Removes expired entries and evicts entries over high watermark,
+    /// returns tuple (expired, evicted)
+    pub fn sweep(&self) -> Result<(u64, u64)> {
+        let mut cache = self.inner.write().expect("Cannot lock cache");
+        cache.sweep()
+    }
+
     pub fn len(&self) -> u64 {
         self.inner.read().unwrap().num_files
     }
 
+    /// total size of cached files
+    pub fn size(&self) -> u64 {
+        self.inner.read().unwrap().size
+    }
+
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
@@ -117,6 +208,27 @@ impl io::Write for FileGuard {
     }
 }
 
+// keys are searched under read lock, so that cache is not blocked, when nothing matches
+fn remove_matching<F: Fn(&str) -> bool>(cache: &CacheInnerType, predicate: F) -> Result<u64> {
+    let keys = cache
+        .read()
+        .expect("Cannot lock cache")
+        .matching_keys(predicate);
+    if keys.is_empty() {
+        return Ok(0);
+    }
+    let mut cache = cache.write().expect("Cannot lock cache");
+    let mut removed = 0;
+    for k in &keys {
+        // entry might be removed meanwhile
+        if cache.files.contains_key(k) {
+            cache.remove(k)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
 fn cleanup<S: AsRef<str>>(cache: &Arc<RwLock<CacheInner>>, key: S) {
     let file_name = {
         let mut cache = cache.write().expect("Cannot lock cache");
@@ -160,6 +272,19 @@ fn entry_path_helper<P: AsRef<Path>>(root: &PathBuf, file_key: P) -> PathBuf {
     root.join(ENTRIES).join(file_key)
 }
 
+// on unix rename is durable only after directory is synced
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        fs::File::open(dir)?.sync_all()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Ok(())
+    }
+}
+
 struct CacheInner {
     files: LinkedHashMap<String, String>,
     opened: HashMap<String, String>,
@@ -168,6 +293,46 @@ struct CacheInner {
     max_files: u64,
     size: u64,
     num_files: u64,
+    options: CacheOptions,
+    // changes since index was saved
+    journal: Option<fs::File>,
+    // exclusive lock of root directory, released when cache is dropped
+    _lock: fs::File,
+}
+
+/// Locks cache directory, so that it cannot be used by other cache instance
+/// (in this or other process) at the same time
+fn lock_dir(root: &Path) -> Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true).write(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // no sharing - file cannot be opened again until it's closed
+        options.share_mode(0);
+    }
+    let f = options.open(root.join(LOCK)).map_err(|e| {
+        // ERROR_SHARING_VIOLATION
+        if cfg!(windows) && e.raw_os_error() == Some(32) {
+            Error::Locked(root.into())
+        } else {
+            e.into()
+        }
+    })?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        // lock is released by OS, when file is closed or process ends
+        if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            return Err(if e.kind() == io::ErrorKind::WouldBlock {
+                Error::Locked(root.into())
+            } else {
+                e.into()
+            });
+        }
+    }
+    Ok(f)
 }
 
 fn recreate_dir<P: AsRef<Path>>(dir: P) -> io::Result<bool> {
@@ -187,11 +352,15 @@ macro_rules! get_cleanup {
 
         {
 
-        // Code to use if we wanted to update timestamp of file too, but generally should not be necessary
-        // let now = filetime::FileTime::from_system_time(SystemTime::now());
-        // if let Err(e) = filetime::set_file_times(&file_name, now, now) {
-        //     error!("Cannot set mtime for file {:?} error {}", file_name, e)
-        // }
+        // mtime of file is used as time of last access
+        if let Some(Ok(_)) = $res {
+            if let Some(ref file_name) = $path {
+                let now = filetime::FileTime::from_system_time(SystemTime::now());
+                if let Err(e) = filetime::set_file_mtime(file_name, now) {
+                    error!("Cannot set mtime for file {:?} error {}", file_name, e)
+                }
+            }
+        }
 
         // cleanup if file was deleted
         if let Some(Err(_)) = $res {
@@ -213,13 +382,13 @@ macro_rules! get_cleanup {
 }
 
 impl CacheInner {
-    fn new(root: PathBuf, max_size: u64, max_files: u64) -> Result<Self> {
-        let created_root = if !root.exists() {
+    fn new(root: PathBuf, max_size: u64, max_files: u64, options: CacheOptions) -> Result<Self> {
+        options.check()?;
+        if !root.exists() {
             fs::create_dir(&root)?;
-            true
-        } else {
-            false
-        };
+        }
+        // must be locked before anything is changed in directory
+        let lock = lock_dir(&root)?;
         let entries_path = root.join(ENTRIES);
         if !entries_path.exists() {
             fs::create_dir(&entries_path)?
@@ -238,18 +407,29 @@ impl CacheInner {
             max_files,
             size: 0,
             num_files: 0,
+            options,
+            journal: None,
+            _lock: lock,
         };
-        match cache.load_index() {
-            Err(e) => {
-                error!("Error loading cache index {}", e);
-                recreate_dir(&entries_path)?;
-            }
-            Ok(false) if !created_root => {
-                warn!("No cache index found,");
-                recreate_dir(&entries_path)?;
-            }
-            _ => (),
+        let mut index = LinkedHashMap::new();
+        match cache.load_index(&mut index) {
+            Err(e) => error!(
+                "Error loading cache index {}, recovering {} entries read before error",
+                e,
+                index.len()
+            ),
+            Ok(false) => debug!("No cache index found"),
+            Ok(true) => (),
+        }
+        match cache.replay_journal(&mut index) {
+            Ok(0) => (),
+            Ok(n) => info!("Replayed {} operations from cache journal", n),
+            Err(e) => error!("Error replaying cache journal {}", e),
         }
+        cache.rebuild(index)?;
+        cache.open_journal()?;
+        // index is now consistent with entries, so journal can start from scratch
+        cache.save_index()?;
         Ok(cache)
     }
 
@@ -305,19 +485,16 @@ impl CacheInner {
 
     // This works only on *nix, as one can delete safely opened files, Windows might require bit different approach
     fn remove_last(&mut self) -> Result<()> {
-        if let Some((_, file_key)) = self.files.pop_front() {
-            let file_path = self.entry_path(file_key);
-            let file_size = fs::metadata(&file_path)?.len();
-            fs::remove_file(file_path)?;
-            self.num_files -= 1;
-            self.size -= file_size;
+        match self.files.front().map(|(key, _)| key.clone()) {
+            Some(key) => self.remove(key),
+            None => Ok(()),
         }
-        Ok(())
     }
 
     fn remove<S: AsRef<str>>(&mut self, key: S) -> Result<()> {
         if let Some(file_key) = self.files.remove(key.as_ref()) {
-            let file_path = self.entry_path(file_key);
+            self.journal(JOURNAL_REMOVE, key.as_ref(), None);
+            let file_path = self.entry_path(&file_key);
             self.num_files -= 1;
             match fs::metadata(&file_path) {
                 Ok(meta) => {
@@ -341,27 +518,145 @@ impl CacheInner {
                 }
             }
 
-            fs::remove_file(file_path)?;
+            self.remove_entry_files(&file_key)?;
+        }
+        Ok(())
+    }
+
+    fn remove_entry_files(&self, file_key: &str) -> io::Result<()> {
+        match fs::remove_file(self.entry_path(file_key)) {
+            // entry file is already gone, which is what we wanted
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            res => res?,
+        }
+        match fs::remove_file(self.key_path(file_key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                error!("Cannot remove key file of entry {}: {}", file_key, e)
+            }
+            _ => (),
         }
         Ok(())
     }
 
+    fn list(&self) -> Vec<EntryInfo> {
+        self.files
+            .iter()
+            .map(|(key, file_key)| {
+                let meta = fs::metadata(self.entry_path(file_key)).ok();
+                EntryInfo {
+                    key: key.clone(),
+                    size: meta.as_ref().map(|m| m.len()).unwrap_or(0),
+                    last_access: meta.and_then(|m| m.modified().ok()),
+                }
+            })
+            .collect()
+    }
+
+    // true if size or number of files (with added ones) is above ratio of limits
+    fn over_limits(&self, ratio: f32, added_size: u64, added_files: u64) -> bool {
+        let ratio = f64::from(ratio);
+        (self.size + added_size) as f64 > self.max_size as f64 * ratio
+            || (self.num_files + added_files) as f64 > self.max_files as f64 * ratio
+    }
+
+    fn remove_expired(&mut self, max_age: Duration) -> Result<u64> {
+        let now = SystemTime::now();
+        let mut expired = 0;
+        // entries are ordered by last access, so we can stop on first not expired
+        while let Some((key, file_key)) = self.files.front() {
+            let file_path = self.entry_path(file_key);
+            match fs::metadata(&file_path).and_then(|m| m.modified()) {
+                Ok(last_access) => {
+                    let age = now.duration_since(last_access).unwrap_or_default();
+                    if age <= max_age {
+                        break;
+                    }
+                    let key = key.clone();
+                    debug!("Removing expired entry {}", key);
+                    self.remove(key)?;
+                }
+                Err(e) => {
+                    let key = key.clone();
+                    warn!("Cannot get last access of entry {}: {}", key, e);
+                    // file is probably missing, remove cleans up size then
+                    self.remove(key).ok();
+                }
+            }
+            expired += 1;
+        }
+        Ok(expired)
+    }
+
+    fn sweep(&mut self) -> Result<(u64, u64)> {
+        let expired = match self.options.max_age {
+            Some(max_age) => self.remove_expired(max_age)?,
+            None => 0,
+        };
+        let mut evicted = 0;
+        if self.over_limits(self.options.high_watermark, 0, 0) {
+            while !self.files.is_empty() && self.over_limits(self.options.low_watermark, 0, 0) {
+                self.remove_last()?;
+                evicted += 1;
+            }
+        }
+        // journal is truncated only when index is saved, so it's compacted here periodically
+        if self.journal_len() > 0 {
+            self.save_index()?;
+        }
+        Ok((expired, evicted))
+    }
+
+    fn matching_keys<F: Fn(&str) -> bool>(&self, predicate: F) -> Vec<String> {
+        self.files
+            .keys()
+            .filter(|k| predicate(k))
+            .cloned()
+            .collect()
+    }
+
     fn finish(&mut self, key: String, file: &mut fs::File) -> Result<()> {
         let file_key = match self.opened.remove(&key) {
             Some(key) => key,
             None => return Err(Error::InvalidCacheState("Missing opened key".into())),
         };
+        let res = self.finish_entry(key, file_key.clone(), file);
+        if res.is_err() {
+            // entry is discarded, so partial file is not needed any more
+            let partial = self.partial_path(&file_key);
+            if partial.exists() {
+                if let Err(e) = fs::remove_file(&partial) {
+                    error!("Cannot delete file {:?}, error {}", partial, e)
+                }
+            }
+        }
+        res
+    }
+
+    fn finish_entry(&mut self, key: String, file_key: String, file: &mut fs::File) -> Result<()> {
         file.flush()?;
+        file.sync_all()?;
         let new_file_size = file.metadata()?.len();
         if new_file_size > self.max_size {
             return Err(Error::FileTooBig);
         }
         let old_path = self.partial_path(file_key.clone());
-        while self.size + new_file_size > self.max_size || self.num_files + 1 > self.max_files {
-            self.remove_last()?
+        if self.over_limits(1.0, new_file_size, 1) {
+            // evict down to low watermark, so that next additions do not have to evict again
+            while !self.files.is_empty()
+                && self.over_limits(self.options.low_watermark, new_file_size, 1)
+            {
+                self.remove_last()?
+            }
         }
+        // key is written before entry is moved, so that every entry has its key, even after crash
+        self.write_key(&file_key, &key)?;
         let new_path = self.entry_path(&file_key);
-        fs::rename(old_path, &new_path)?;
+        if let Err(e) = fs::rename(old_path, &new_path) {
+            fs::remove_file(self.key_path(&file_key)).ok();
+            return Err(e.into());
+        }
+        sync_dir(&self.root.join(ENTRIES))?;
+        self.journal(JOURNAL_ADD, &key, Some(&file_key));
         self.files.insert(key, file_key);
         self.num_files += 1;
         self.size += new_path.metadata().map(|m| m.len()).unwrap_or(0);
@@ -376,6 +671,29 @@ impl CacheInner {
         self.root.join(PARTIAL).join(file_key)
     }
 
+    fn key_path(&self, file_key: &str) -> PathBuf {
+        self.root
+            .join(ENTRIES)
+            .join(String::from(file_key) + KEY_SUFFIX)
+    }
+
+    fn write_key(&self, file_key: &str, key: &str) -> io::Result<()> {
+        let mut data = Vec::with_capacity(2 + key.len());
+        write_string(&mut data, key);
+        let mut f = fs::File::create(self.key_path(file_key))?;
+        f.write_all(&data)?;
+        f.sync_all()
+    }
+
+    fn read_key(&self, file_key: &str) -> Result<String> {
+        let mut f = io::BufReader::new(fs::File::open(self.key_path(file_key))?);
+        let key = read_string(&mut f, MAX_KEY_SIZE)?;
+        if f.read(&mut [0])? != 0 {
+            return Err(Error::InvalidIndex);
+        }
+        Ok(key)
+    }
+
     fn save_index(&self) -> Result<()> {
         let tmp_index = self.root.join(String::from(INDEX) + ".tmp");
         {
@@ -386,88 +704,218 @@ impl CacheInner {
                 f.write_u16::<BigEndian>(value.len() as u16)?;
                 f.write_all(value.as_bytes())?;
             }
+            f.sync_all()?;
         }
         fs::rename(tmp_index, self.root.join(INDEX))?;
+        sync_dir(&self.root)?;
+        self.truncate_journal()
+    }
 
+    fn journal_len(&self) -> u64 {
+        self.journal
+            .as_ref()
+            .and_then(|j| j.metadata().ok())
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    fn truncate_journal(&self) -> Result<()> {
+        if let Some(ref journal) = self.journal {
+            journal.set_len(0)?;
+        }
         Ok(())
     }
 
-    fn load_index(&mut self) -> Result<bool> {
+    fn load_index(&self, index: &mut LinkedHashMap<String, String>) -> Result<bool> {
         let index_path = self.root.join(INDEX);
 
         if index_path.exists() {
-            let mut index = LinkedHashMap::<String, String>::new();
-            let mut f = fs::File::open(index_path)?;
-
+            let mut f = io::BufReader::new(fs::File::open(index_path)?);
             loop {
-                let key_len = match f.read_u16::<BigEndian>() {
-                    Ok(l) => l as usize,
-                    Err(e) => match e.kind() {
-                        io::ErrorKind::UnexpectedEof => break,
-                        _ => return Err(e.into()),
-                    },
+                let key = match read_string(&mut f, MAX_KEY_SIZE) {
+                    Ok(k) => k,
+                    Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
                 };
+                let value = read_string(&mut f, 2 * FILE_KEY_LEN)?;
+                index.insert(key, value);
+            }
+            Ok(true)
+        } else {
+            debug!("No index file");
+            Ok(false)
+        }
+    }
 
-                if key_len > MAX_KEY_SIZE {
-                    return Err(Error::InvalidIndex);
+    // applies operations from journal to index, returns number of replayed operations
+    fn replay_journal(&self, index: &mut LinkedHashMap<String, String>) -> Result<u64> {
+        let journal_path = self.root.join(JOURNAL);
+        if !journal_path.exists() {
+            return Ok(0);
+        }
+        let mut f = io::BufReader::new(fs::File::open(journal_path)?);
+        let mut count = 0;
+        loop {
+            let op = match f.read_u8() {
+                Ok(op) => op,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            let record = read_string(&mut f, MAX_KEY_SIZE).and_then(|key| match op {
+                JOURNAL_ADD => read_string(&mut f, 2 * FILE_KEY_LEN).map(|v| (key, Some(v))),
+                JOURNAL_REMOVE => Ok((key, None)),
+                _ => Err(Error::InvalidIndex),
+            });
+            match record {
+                Ok((key, Some(value))) => {
+                    index.insert(key, value);
                 }
-
-                let mut buf = [0_u8; MAX_KEY_SIZE];
-                f.read_exact(&mut buf[..key_len])?;
-                let key = String::from_utf8(Vec::from(&buf[..key_len]))
-                    .map_err(|_| Error::InvalidIndex)?;
-                let value_len = f.read_u16::<BigEndian>()? as usize;
-                if value_len > 2 * FILE_KEY_LEN {
-                    return Err(Error::InvalidIndex);
+                Ok((key, None)) => {
+                    index.remove(&key);
                 }
-                f.read_exact(&mut buf[..value_len])?;
-                let value = String::from_utf8(Vec::from(&buf[..value_len]))
-                    .map_err(|_| Error::InvalidIndex)?;
-                let file_path = self.entry_path(&value);
-                if file_path.exists() {
-                    let file_size = fs::metadata(&file_path)?.len();
-                    // cleanup files over limit
-                    if self.num_files + 1 > self.max_files || self.size + file_size > self.max_size
-                    {
-                        fs::remove_file(&file_path)?;
-                        warn!("Removing file above limit {:?}", file_path);
-                    } else {
-                        index.insert(key, value);
-                        self.num_files += 1;
-                        self.size += file_size;
-                    }
+                // last record was not completely written
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    warn!("Incomplete last record in cache journal");
+                    break;
                 }
+                Err(e) => return Err(e),
             }
+            count += 1;
+        }
+        Ok(count)
+    }
 
-            //cleanup files not in index
-            {
-                let file_keys_set = index.values().collect::<HashSet<&String>>();
-                let base_dir = self.root.join(ENTRIES);
-                if let Ok(dir_list) = fs::read_dir(&base_dir) {
-                    for f in dir_list {
-                        if let Ok(dir_entry) = f {
-                            if dir_entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
-                                if let Ok(file_name) = dir_entry.file_name().into_string() {
-                                    if !file_keys_set.contains(&file_name) {
-                                        fs::remove_file(dir_entry.path()).ok();
-                                        warn!("Removing file not in index {:?}", dir_entry.path());
-                                    }
-                                }
-                            }
-                        }
+    /// Restores cache from entries/ directory, each entry has its key stored in `.key` file.
+    /// Index and journal give just order of entries (for LRU) and keys of entries created
+    /// before key files were used - only entries with unknown key are removed
+    fn rebuild(&mut self, index: LinkedHashMap<String, String>) -> Result<()> {
+        self.files = LinkedHashMap::new();
+        self.size = 0;
+        self.num_files = 0;
+        let indexed_keys: HashMap<&String, &String> = index.iter().map(|(k, v)| (v, k)).collect();
+        // file key -> (key, size, last access)
+        let mut found = HashMap::new();
+        let mut key_files = vec![];
+        for dir_entry in fs::read_dir(self.root.join(ENTRIES))? {
+            let dir_entry = dir_entry?;
+            if !dir_entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                continue;
+            }
+            let file_key = match dir_entry.file_name().into_string() {
+                Ok(name) if name.ends_with(KEY_SUFFIX) => {
+                    key_files.push(name);
+                    continue;
+                }
+                Ok(name) => name,
+                Err(name) => {
+                    warn!("Removing unexpected file {:?}", name);
+                    fs::remove_file(dir_entry.path()).ok();
+                    continue;
+                }
+            };
+            let key = match (self.read_key(&file_key), indexed_keys.get(&file_key)) {
+                (Ok(key), _) => key,
+                (Err(_), Some(&key)) => {
+                    if let Err(e) = self.write_key(&file_key, key) {
+                        error!("Cannot write key file for entry {}: {}", key, e)
                     }
+                    key.clone()
+                }
+                (Err(e), None) => {
+                    warn!(
+                        "Removing entry file {:?} with unknown key: {}",
+                        dir_entry.path(),
+                        e
+                    );
+                    self.remove_entry_files(&file_key).ok();
+                    continue;
                 }
+            };
+            match dir_entry.metadata() {
+                Ok(meta) => {
+                    found.insert(file_key, (key, meta.len(), meta.modified().ok()));
+                }
+                Err(e) => error!("Cannot get meta for file {:?}: {}", dir_entry.path(), e),
             }
+        }
 
-            self.files = index;
-            Ok(true)
-        } else {
-            debug!("No index file");
-            Ok(false)
+        // key was written, but entry was not moved into entries/
+        for key_file in key_files {
+            if !found.contains_key(&key_file[..key_file.len() - KEY_SUFFIX.len()]) {
+                fs::remove_file(self.root.join(ENTRIES).join(key_file)).ok();
+            }
+        }
+
+        // entries known to index keep their order, others are newer, ordered by last access
+        let mut entries = Vec::with_capacity(found.len());
+        for file_key in index.values() {
+            if let Some(entry) = found.remove(file_key) {
+                entries.push((file_key.clone(), entry));
+            }
+        }
+        let mut others: Vec<_> = found.into_iter().collect();
+        others.sort_by_key(|(_, (_, _, last_access))| *last_access);
+        entries.extend(others);
+
+        for (file_key, (key, file_size, _)) in entries {
+            if self.files.contains_key(&key) {
+                // should not happen, but newer entry is more likely valid
+                self.remove(&key)?;
+            }
+            self.files.insert(key, file_key);
+            self.num_files += 1;
+            self.size += file_size;
+        }
+
+        // cleanup entries over limits, least recently used first
+        while !self.files.is_empty() && self.over_limits(1.0, 0, 0) {
+            warn!("Removing entry above limit");
+            self.remove_last()?;
+        }
+        Ok(())
+    }
+
+    fn open_journal(&mut self) -> Result<()> {
+        let f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join(JOURNAL))?;
+        self.journal = Some(f);
+        Ok(())
+    }
+
+    // record is written at once, so only last record can be incomplete after crash
+    fn journal(&mut self, op: u8, key: &str, file_key: Option<&str>) {
+        if let Some(ref mut journal) = self.journal {
+            let mut record = Vec::with_capacity(5 + key.len() + FILE_KEY_LEN);
+            record.push(op);
+            write_string(&mut record, key);
+            if let Some(file_key) = file_key {
+                write_string(&mut record, file_key);
+            }
+            if let Err(e) = journal.write_all(&record).and_then(|_| journal.sync_data()) {
+                error!("Cannot write to cache journal: {}", e)
+            }
         }
     }
 }
 
+fn read_string<R: Read>(r: &mut R, max_len: usize) -> Result<String> {
+    let len = r.read_u16::<BigEndian>()? as usize;
+    if len > max_len {
+        return Err(Error::InvalidIndex);
+    }
+    let mut buf = vec![0_u8; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| Error::InvalidIndex)
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    // cannot fail for Vec
+    buf.write_u16::<BigEndian>(s.len() as u16).unwrap();
+    buf.extend_from_slice(s.as_bytes());
+}
+
 #[cfg(test)]
 extern crate env_logger;
 #[cfg(test)]
@@ -662,6 +1110,232 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_remove_matching() {
+        env_logger::try_init().ok();
+        let tmp_folder = tempdir().unwrap();
+        let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+        for key in &["a#1", "a#2", "b#1"] {
+            let mut f = c.add(key).unwrap();
+            f.write_all(b"data").unwrap();
+            f.finish().unwrap();
+        }
+        assert_eq!(2, c.remove_matching(|k| k.starts_with("a#")).unwrap());
+        assert_eq!(1, c.len());
+        assert!(c.get("a#1").is_none());
+        assert!(c.get("b#1").is_some());
+        assert_eq!(4, c.size());
+        c.remove("b#1").unwrap();
+        assert!(c.is_empty());
+    }
+
+    fn add_entries(c: &Cache, keys: Range<u32>, size: usize) {
+        for i in keys {
+            let mut f = c.add(format!("key{}", i)).unwrap();
+            f.write_all(&vec![0u8; size]).unwrap();
+            f.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_watermarks() {
+        env_logger::try_init().ok();
+        let tmp_folder = tempdir().unwrap();
+        let opts = CacheOptions {
+            low_watermark: 0.5,
+            high_watermark: 0.8,
+            ..Default::default()
+        };
+        let c = Cache::with_options(tmp_folder.path(), 1000, 10, opts).unwrap();
+        add_entries(&c, 0..10, 10);
+        assert_eq!(10, c.len());
+        // limit reached - evicts down to 5 files, including the new one
+        add_entries(&c, 10..11, 10);
+        assert_eq!(5, c.len());
+        assert!(c.get("key5").is_none());
+        assert!(c.get("key6").is_some());
+
+        add_entries(&c, 11..14, 10);
+        assert_eq!(8, c.len());
+        assert_eq!((0, 0), c.sweep().unwrap());
+        add_entries(&c, 14..15, 10);
+        assert_eq!((0, 4), c.sweep().unwrap());
+        assert_eq!(5, c.len());
+        assert_eq!(50, c.size());
+
+        let invalid = CacheOptions {
+            low_watermark: 0.9,
+            high_watermark: 0.8,
+            ..Default::default()
+        };
+        assert!(Cache::with_options(tmp_folder.path(), 1000, 10, invalid).is_err());
+    }
+
+    #[test]
+    fn test_max_age() {
+        env_logger::try_init().ok();
+        let tmp_folder = tempdir().unwrap();
+        let opts = CacheOptions {
+            max_age: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let c = Cache::with_options(tmp_folder.path(), 1000, 10, opts).unwrap();
+        add_entries(&c, 0..3, 10);
+        let old =
+            filetime::FileTime::from_system_time(SystemTime::now() - Duration::from_secs(7200));
+        for key in &["key0", "key1"] {
+            let path = c.inner.write().unwrap().get_entry_path(key).unwrap();
+            filetime::set_file_mtime(path, old).unwrap();
+        }
+        // key1 was accessed last, so expiry stops on it
+        assert_eq!((0, 0), c.sweep().unwrap());
+        let path = c.inner.write().unwrap().get_entry_path("key2").unwrap();
+        filetime::set_file_mtime(path, old).unwrap();
+        assert_eq!((3, 0), c.sweep().unwrap());
+        assert!(c.is_empty());
+        assert_eq!(0, c.size());
+    }
+
+    // index is not saved on drop, only directory lock is released as by OS
+    fn simulate_crash(c: Cache) {
+        c.inner.write().unwrap()._lock = tempfile::tempfile().unwrap();
+        std::mem::forget(c);
+    }
+
+    #[test]
+    fn test_journal_recovery() {
+        env_logger::try_init().ok();
+        let tmp_folder = tempdir().unwrap();
+        {
+            let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+            add_entries(&c, 0..4, 10);
+            c.remove("key1").unwrap();
+            simulate_crash(c);
+        }
+        let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+        assert_eq!(3, c.len());
+        assert_eq!(30, c.size());
+        assert!(c.get("key0").is_some());
+        assert!(c.get("key1").is_none());
+        assert!(c.get("key3").is_some());
+        // journal is consolidated into index
+        assert_eq!(
+            0,
+            fs::metadata(tmp_folder.path().join(JOURNAL)).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_corrupted_index() {
+        env_logger::try_init().ok();
+        let tmp_folder = tempdir().unwrap();
+        {
+            let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+            add_entries(&c, 0..2, 10);
+            c.save_index().unwrap();
+            add_entries(&c, 2..3, 10);
+            simulate_crash(c);
+        }
+        // last index record is damaged
+        let index_path = tmp_folder.path().join(INDEX);
+        let mut index = fs::read(&index_path).unwrap();
+        let len = index.len();
+        index[len - FILE_KEY_LEN - 2] = 0xff;
+        fs::write(&index_path, index).unwrap();
+        {
+            let mut journal = fs::OpenOptions::new()
+                .append(true)
+                .open(tmp_folder.path().join(JOURNAL))
+                .unwrap();
+            // incomplete record
+            journal.write_all(&[JOURNAL_ADD, 0, 10, b'k']).unwrap();
+        }
+
+        let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+        // entry of damaged index record is recovered from its key file
+        assert_eq!(3, c.len());
+        assert_eq!(30, c.size());
+        assert!(c.get("key0").is_some());
+        assert!(c.get("key1").is_some());
+        assert!(c.get("key2").is_some());
+    }
+
+    #[test]
+    fn test_sweep_compacts_journal() {
+        let tmp_folder = tempdir().unwrap();
+        let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+        add_entries(&c, 0..2, 10);
+        let journal_len = || fs::metadata(tmp_folder.path().join(JOURNAL)).unwrap().len();
+        assert!(journal_len() > 0);
+        c.sweep().unwrap();
+        assert_eq!(0, journal_len());
+        assert_eq!(2, c.len());
+    }
+
+    #[test]
+    fn test_lost_index() {
+        env_logger::try_init().ok();
+        let tmp_folder = tempdir().unwrap();
+        {
+            let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+            add_entries(&c, 0..3, 10);
+        }
+        fs::remove_file(tmp_folder.path().join(INDEX)).unwrap();
+        let entries_dir = tmp_folder.path().join(ENTRIES);
+        // entry without key cannot be recovered, key without entry is leftover
+        fs::write(entries_dir.join("unknown"), b"data").unwrap();
+        fs::write(entries_dir.join(String::from("leftover") + KEY_SUFFIX), b"").unwrap();
+
+        let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+        assert_eq!(3, c.len());
+        assert_eq!(30, c.size());
+        for i in 0..3 {
+            assert!(c.get(format!("key{}", i)).is_some());
+        }
+        // entry and key file for each entry
+        assert_eq!(6, fs::read_dir(&entries_dir).unwrap().count());
+        c.remove("key0").unwrap();
+        assert_eq!(4, fs::read_dir(&entries_dir).unwrap().count());
+    }
+
+    #[test]
+    fn test_evict_missing_file() {
+        env_logger::try_init().ok();
+        let tmp_folder = tempdir().unwrap();
+        let c = Cache::new(tmp_folder.path(), 10_000, 3).unwrap();
+        add_entries(&c, 0..3, 10);
+        let file_key = c.inner.read().unwrap().files["key0"].clone();
+        fs::remove_file(tmp_folder.path().join(ENTRIES).join(file_key)).unwrap();
+        // key0 is evicted first
+        add_entries(&c, 3..4, 10);
+        assert!(c.get("key0").is_none());
+        assert!(c.get("key3").is_some());
+        let inner = c.inner.read().unwrap();
+        assert_eq!(inner.files.len() as u64, inner.num_files);
+        assert_eq!(10 * inner.num_files, inner.size);
+    }
+
+    #[test]
+    fn test_list() {
+        env_logger::try_init().ok();
+        let tmp_folder = tempdir().unwrap();
+        let c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+        for (key, data) in &[("first", "a"), ("second", "bb")] {
+            let mut f = c.add(key).unwrap();
+            f.write_all(data.as_bytes()).unwrap();
+            f.finish().unwrap();
+        }
+        let before_access = SystemTime::now();
+        c.get("first").unwrap().unwrap();
+        let entries = c.list();
+        assert_eq!(2, entries.len());
+        // from least recently used
+        assert_eq!("second", entries[0].key);
+        assert_eq!(2, entries[0].size);
+        assert_eq!("first", entries[1].key);
+        assert!(entries[1].last_access.unwrap() >= before_access);
+    }
+
     #[test]
     fn test_cleanup() {
         env_logger::try_init().ok();
@@ -681,4 +1355,17 @@ mod tests {
 
         assert_eq!(0, list_path())
     }
+
+    #[test]
+    fn test_lock() {
+        let tmp_folder = tempdir().unwrap();
+        {
+            let _c = Cache::new(tmp_folder.path(), 10_000, 50).unwrap();
+            match Cache::new(tmp_folder.path(), 10_000, 50) {
+                Err(Error::Locked(_)) => (),
+                _ => panic!("cache directory should be locked"),
+            }
+        }
+        assert!(Cache::new(tmp_folder.path(), 10_000, 50).is_ok());
+    }
 }
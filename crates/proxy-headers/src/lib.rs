@@ -9,8 +9,11 @@ use std::{
     str::Utf8Error,
 };
 
+mod network;
 mod parser;
 
+pub use network::{InvalidNetwork, IpNetwork};
+
 lazy_static! {
     static ref X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 }
@@ -0,0 +1,135 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// IP network in CIDR notation, like 192.168.1.0/24 or fd00::/8,
+/// plain address is network with just this single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidNetwork;
+
+impl Display for InvalidNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid IP network")
+    }
+}
+
+impl std::error::Error for InvalidNetwork {}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask_v4(addr: &Ipv4Addr, prefix: u8) -> u32 {
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u32::MAX << (32 - prefix)
+    };
+    u32::from(*addr) & mask
+}
+
+fn mask_v6(addr: &Ipv6Addr, prefix: u8) -> u128 {
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u128::MAX << (128 - prefix)
+    };
+    u128::from(*addr) & mask
+}
+
+impl IpNetwork {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, InvalidNetwork> {
+        if prefix > max_prefix(&addr) {
+            return Err(InvalidNetwork);
+        }
+        Ok(IpNetwork { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask_v4(&net, self.prefix) == mask_v4(ip, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask_v6(&net, self.prefix) == mask_v6(ip, self.prefix)
+            }
+            (IpAddr::V6(_), IpAddr::V4(ip)) => self.contains(&IpAddr::V6(ip.to_ipv6_mapped())),
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4() {
+                // only IPv4 mapped addresses
+                Some(ip4) if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                    self.contains(&IpAddr::V4(ip4))
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = InvalidNetwork;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .ok_or(InvalidNetwork)?
+            .parse()
+            .map_err(|_| InvalidNetwork)?;
+        let prefix = match parts.next() {
+            Some(p) => p.parse().map_err(|_| InvalidNetwork)?,
+            None => max_prefix(&addr),
+        };
+        IpNetwork::new(addr, prefix)
+    }
+}
+
+impl Display for IpNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_contains() {
+        let net: IpNetwork = "192.168.1.0/24".parse().unwrap();
+        assert!(net.contains(&"192.168.1.77".parse().unwrap()));
+        assert!(!net.contains(&"192.168.2.1".parse().unwrap()));
+        assert!(net.contains(&"::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!net.contains(&"fd00::1".parse().unwrap()));
+
+        let single: IpNetwork = "127.0.0.1".parse().unwrap();
+        assert_eq!("127.0.0.1/32", single.to_string());
+        assert!(single.contains(&"127.0.0.1".parse().unwrap()));
+        assert!(!single.contains(&"127.0.0.2".parse().unwrap()));
+
+        let net6: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(net6.contains(&"fd12:3456::1".parse().unwrap()));
+        assert!(!net6.contains(&"fe80::1".parse().unwrap()));
+
+        let all: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_network() {
+        assert!("192.168.1.0/33".parse::<IpNetwork>().is_err());
+        assert!("192.168.1/24".parse::<IpNetwork>().is_err());
+        assert!("fd00::/129".parse::<IpNetwork>().is_err());
+        assert!("localhost".parse::<IpNetwork>().is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use super::validators::*;
 use super::*;
-use clap::{crate_authors, crate_name, crate_version, App, Arg};
+use clap::{crate_authors, crate_name, crate_version, App, Arg, SubCommand};
 
 type Parser<'a> = App<'a, 'a>;
 
@@ -91,6 +91,22 @@ fn create_parser<'a>() -> Parser<'a> {
             .env("AUDIOSERVE_SHARED_SECRET_FILE")
             .help("File containing shared secret, it's slightly safer to read it from file, then provide as command argument")
             )
+        .arg(Arg::with_name("users-file")
+            .long("users-file")
+            .takes_value(true)
+            .validator_os(is_existing_file)
+            .env("AUDIOSERVE_USERS_FILE")
+            .help("File (YAML or JSON) with list of users, each user has name and password_hash (see --hash-password), users can authenticate with their name and password")
+            )
+        .arg(Arg::with_name("hash-password")
+            .long("hash-password")
+            .takes_value(true)
+            .help("Will print salted hash of given password to stdout, which can be then used as password_hash in users file")
+            )
+        .arg(Arg::with_name("generate-api-key")
+            .long("generate-api-key")
+            .help("Will print new random API key and its hash to stdout, hash can be then used as key_hash in api_keys in config file")
+            )
         .arg(Arg::with_name("transcoding-max-parallel-processes")
             .short("x")
             .long("transcoding-max-parallel-processes")
@@ -114,6 +130,24 @@ fn create_parser<'a>() -> Parser<'a> {
             .env("AUDIOSERVE_TOKEN_VALIDITY_DAYS")
             .help("Validity of authentication token issued by this server in days[default 365, min 10]")
             )
+        .arg(Arg::with_name("login-max-failures")
+            .long("login-max-failures")
+            .takes_value(true)
+            .validator(is_number)
+            .env("AUDIOSERVE_LOGIN_MAX_FAILURES")
+            .help("Number of failed login attempts from one address, after which address is temporarily banned [default: 10]")
+            )
+        .arg(Arg::with_name("login-ban-minutes")
+            .long("login-ban-minutes")
+            .takes_value(true)
+            .validator(is_number)
+            .env("AUDIOSERVE_LOGIN_BAN_MINUTES")
+            .help("For how long address is banned from login after too many failed attempts [default: 60]")
+            )
+        .arg(Arg::with_name("allow-basic-auth")
+            .long("allow-basic-auth")
+            .help("Accepts also HTTP Basic authentication (user and password or shared secret as password), for media players, which cannot use token authentication")
+            )
         .arg(Arg::with_name("client-dir")
             .short("c")
             .long("client-dir")
@@ -130,6 +164,13 @@ fn create_parser<'a>() -> Parser<'a> {
             .env("AUDIOSERVE_SECRET_FILE")
             .help("Path to file where server secret is kept - it's generated if it does not exists [default: is $HOME/.audioserve.secret]")
             )
+        .arg(Arg::with_name("sessions-file")
+            .long("sessions-file")
+            .takes_value(true)
+            .validator_os(parent_dir_exists)
+            .env("AUDIOSERVE_SESSIONS_FILE")
+            .help("Path to file where issued and revoked tokens are kept [default: is $HOME/.audioserve/audioserve.sessions]")
+            )
         .arg(Arg::with_name("cors")
             .long("cors")
             .help("Enable CORS - enabled any origin of requests")
@@ -170,6 +211,22 @@ fn create_parser<'a>() -> Parser<'a> {
         .long("behind-proxy")
         .help("Informs program that it is behind remote proxy, now used only for logging (to get true remote client ip)")
         )
+        .arg(Arg::with_name("proxy-auth-trusted")
+            .long("proxy-auth-trusted")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .requires("behind-proxy")
+            .env("AUDIOSERVE_PROXY_AUTH_TRUSTED")
+            .help("Enables authentication by reverse proxy - list of trusted proxies networks (in CIDR notation, comma separated), requests from these proxies are authenticated as user from proxy header")
+        )
+        .arg(Arg::with_name("proxy-auth-header")
+            .long("proxy-auth-header")
+            .takes_value(true)
+            .requires("proxy-auth-trusted")
+            .env("AUDIOSERVE_PROXY_AUTH_HEADER")
+            .help("Header with authenticated user name set by reverse proxy [default: X-Remote-User]")
+        )
     }
 
     if cfg!(feature = "folder-download") {
@@ -257,7 +314,8 @@ fn create_parser<'a>() -> Parser<'a> {
         ).arg(
             Arg::with_name("t-cache-disable")
             .long("t-cache-disable")
-            .conflicts_with_all(&["t-cache-save-often", "t-cache-max-files", "t-cache-size", "t-cache-dir"])
+            .conflicts_with_all(&["t-cache-save-often", "t-cache-max-files", "t-cache-size", "t-cache-dir", "t-cache-pretranscode",
+                "t-cache-list", "t-cache-purge-quality", "t-cache-purge-path", "t-cache-purge-all"])
             .help("Transaction cache is disabled. If you want to completely get rid of it, compile without 'transcoding-cache'")
             )
         .arg(
@@ -265,6 +323,81 @@ fn create_parser<'a>() -> Parser<'a> {
             .long("t-cache-save-often")
             .help("Save additions to cache often, after each addition, this is normally not necessary")
         )
+        .arg(
+            Arg::with_name("t-cache-pretranscode")
+            .long("t-cache-pretranscode")
+            .takes_value(true)
+            .env("AUDIOSERVE_T_CACHE_PRETRANSCODE")
+            .validator(is_number)
+            .help("Number of following files in folder, which are transcoded into cache in background, when file is played, 0 disables [default is 0]")
+        )
+        .arg(
+            Arg::with_name("t-cache-list")
+            .long("t-cache-list")
+            .conflicts_with_all(&["t-cache-purge-quality", "t-cache-purge-path", "t-cache-purge-all"])
+            .help("Lists entries of transcoding cache and exits (server is not started)")
+        )
+        .arg(
+            Arg::with_name("t-cache-purge-quality")
+            .long("t-cache-purge-quality")
+            .takes_value(true)
+            .value_name("QUALITY")
+            .conflicts_with("t-cache-purge-all")
+            .help("Removes entries of given transcoding level (l, m, h) or profile from transcoding cache and exits (server is not started), \
+            can be combined with --t-cache-purge-path")
+        )
+        .arg(
+            Arg::with_name("t-cache-purge-path")
+            .long("t-cache-purge-path")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with("t-cache-purge-all")
+            .help("Removes entries of given folder (with all its subfolders) or file from transcoding cache and exits (server is not started), \
+            PATH is relative to collection given by --t-cache-purge-collection")
+        )
+        .arg(
+            Arg::with_name("t-cache-purge-collection")
+            .long("t-cache-purge-collection")
+            .takes_value(true)
+            .validator(is_number)
+            .requires("t-cache-purge-path")
+            .help("Number of collection (order of base directory, starting from 0) for --t-cache-purge-path [default is 0]")
+        )
+        .arg(
+            Arg::with_name("t-cache-purge-all")
+            .long("t-cache-purge-all")
+            .help("Removes all entries from transcoding cache and exits (server is not started)")
+        )
+        .subcommand(
+            SubCommand::with_name("pretranscode")
+            .about("Transcodes files of collection into transcoding cache and exits (server is not started)")
+            .arg(
+                Arg::with_name("collection")
+                .long("collection")
+                .takes_value(true)
+                .validator(is_number)
+                .help("Number of collection (order of base directory, starting from 0) [default is 0]")
+            )
+            .arg(
+                Arg::with_name("quality")
+                .long("quality")
+                .takes_value(true)
+                .help("Transcoding level (l, m, h) or name of transcoding profile [default is m]")
+            )
+            .arg(
+                Arg::with_name("path")
+                .long("path")
+                .takes_value(true)
+                .help("Folder (or chapterized file) within collection, which is transcoded with all its subfolders [default is whole collection]")
+            )
+            .arg(
+                Arg::with_name("parallel")
+                .long("parallel")
+                .takes_value(true)
+                .validator(is_number)
+                .help("Number of parallel transcodings [default is --transcoding-max-parallel-processes]")
+            )
+        )
     }
 
     parser
@@ -296,10 +429,24 @@ where
     let p = create_parser();
     let args = p.get_matches_from(args);
 
+    if let Some(password) = args.value_of("hash-password") {
+        println!("{}", crate::services::auth::hash_password(password));
+        std::process::exit(0);
+    }
+
+    if args.is_present("generate-api-key") {
+        let (key, hash) = crate::services::auth::generate_api_key();
+        println!("key: {}\nkey_hash: {}", key, hash);
+        std::process::exit(0);
+    }
+
     if let Some(dir) = args.value_of_os("data-dir") {
         unsafe {
-            BASE_DATA_DIR.take();
-            BASE_DATA_DIR = Some(dir.into());
+            // on reload data dir can be still in use
+            if BASE_DATA_DIR.as_deref() != Some(Path::new(dir)) {
+                BASE_DATA_DIR.take();
+                BASE_DATA_DIR = Some(dir.into());
+            }
         }
     }
 
@@ -379,12 +526,20 @@ where
 
     if args.is_present("no-authentication") {
         config.shared_secret = None;
+        config.users.clear();
+        config.api_keys.clear();
         no_authentication_confirmed = true
-    } else if let Some(secret) = args.value_of("shared-secret") {
-        config.shared_secret = Some(secret.into())
-    } else if let Some(file) = args.value_of_os("shared-secret-file") {
-        config.set_shared_secret_from_file(file)?
-    };
+    } else {
+        if let Some(secret) = args.value_of("shared-secret") {
+            config.shared_secret = Some(secret.into())
+        } else if let Some(file) = args.value_of_os("shared-secret-file") {
+            config.set_shared_secret_from_file(file)?
+        };
+
+        if let Some(file) = args.value_of_os("users-file") {
+            config.set_users_from_file(file)?
+        }
+    }
 
     if let Some(r) = args.value_of("limit-rate").and_then(|s| s.parse().ok()) {
         config.limit_rate = Some(r)
@@ -406,6 +561,18 @@ where
         config.token_validity_hours = validity.parse::<u32>().unwrap() * 24
     }
 
+    if let Some(n) = args.value_of("login-max-failures") {
+        config.login_lockout.max_failures = n.parse().unwrap()
+    }
+
+    if let Some(n) = args.value_of("login-ban-minutes") {
+        config.login_lockout.ban_minutes = n.parse().unwrap()
+    }
+
+    if is_present_or_env("allow-basic-auth", "AUDIOSERVE_ALLOW_BASIC_AUTH") {
+        config.basic_auth = true;
+    }
+
     if let Some(client_dir) = args.value_of_os("client-dir") {
         config.client_dir = client_dir.into()
     }
@@ -413,6 +580,10 @@ where
         config.secret_file = secret_file.into()
     }
 
+    if let Some(sessions_file) = args.value_of_os("sessions-file") {
+        config.sessions_file = sessions_file.into()
+    }
+
     if is_present_or_env("cors", "AUDIOSERVE_CORS") {
         config.cors = true;
     }
@@ -461,6 +632,30 @@ where
         if is_present_or_env("t-cache-save-often", "AUDIOSERVE_T_CACHE_SAVE_OFTEN") {
             config.transcoding.cache.save_often = true;
         }
+
+        if let Some(n) = args.value_of("t-cache-pretranscode") {
+            config.transcoding.cache.pretranscode_next = n.parse().unwrap()
+        }
+
+        if args.is_present("t-cache-list") {
+            config.cache_command = Some(CacheCommand::List)
+        } else if args.is_present("t-cache-purge-quality") || args.is_present("t-cache-purge-path")
+        {
+            let collection = args
+                .value_of("t-cache-purge-collection")
+                .map(|n| n.parse().unwrap())
+                .unwrap_or(0);
+            config.cache_command = Some(CacheCommand::Purge(PurgeFilter {
+                quality: args
+                    .value_of("t-cache-purge-quality")
+                    .map(ToOwned::to_owned),
+                path: args
+                    .value_of_os("t-cache-purge-path")
+                    .map(|p| (collection, PathBuf::from(p))),
+            }))
+        } else if args.is_present("t-cache-purge-all") {
+            config.cache_command = Some(CacheCommand::PurgeAll)
+        }
     };
     if cfg!(feature = "folder-download") {
         if is_present_or_env(
@@ -477,6 +672,18 @@ where
         config.behind_proxy = true;
     }
 
+    #[cfg(feature = "behind-proxy")]
+    {
+        if let Some(trusted) = args.values_of("proxy-auth-trusted") {
+            let mut proxy_auth = config.proxy_auth.take().unwrap_or_default();
+            proxy_auth.trusted_proxies = trusted.map(ToString::to_string).collect();
+            if let Some(header) = args.value_of("proxy-auth-header") {
+                proxy_auth.header = header.into();
+            }
+            config.proxy_auth = Some(proxy_auth);
+        }
+    }
+
     if let Some(d) = args.value_of("chapters-from-duration") {
         config.chapters.from_duration = d.parse().unwrap()
     }
@@ -501,10 +708,35 @@ where
         config.positions_ws_timeout = Duration::from_secs(positions_ws_timeout.parse().unwrap())
     }
 
-    if !no_authentication_confirmed && config.shared_secret.is_none() {
+    #[cfg(feature = "behind-proxy")]
+    let has_proxy_auth = config.proxy_auth.is_some();
+    #[cfg(not(feature = "behind-proxy"))]
+    let has_proxy_auth = false;
+
+    #[cfg(feature = "transcoding-cache")]
+    {
+        if let Some(sub) = args.subcommand_matches("pretranscode") {
+            config.pretranscode = Some(parse_pretranscode(sub, &config)?);
+        }
+        if let Some(CacheCommand::Purge(ref filter)) = config.cache_command {
+            filter.check(&config)?;
+        }
+    }
+    // server is not started, only cache is processed
+    #[cfg(feature = "transcoding-cache")]
+    let cache_only = config.pretranscode.is_some() || config.cache_command.is_some();
+    #[cfg(not(feature = "transcoding-cache"))]
+    let cache_only = false;
+
+    if !no_authentication_confirmed
+        && !cache_only
+        && config.shared_secret.is_none()
+        && config.users.is_empty()
+        && !has_proxy_auth
+    {
         return arg_error!(
             "shared-secret",
-            "Shared secret is None, but no authentication is not confirmed"
+            "Shared secret is None and no users are defined, but no authentication is not confirmed"
         );
     }
 
@@ -524,6 +756,56 @@ where
     Ok(config)
 }
 
+#[cfg(feature = "transcoding-cache")]
+fn parse_pretranscode(args: &clap::ArgMatches, config: &Config) -> Result<PretranscodeTask> {
+    if config.transcoding.cache.disabled {
+        return arg_error!("pretranscode", "Transcoding cache is disabled");
+    }
+    let collection: usize = args
+        .value_of("collection")
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(0);
+    if !config.has_collection(collection) {
+        return arg_error!("collection", "There is no collection {}", collection);
+    }
+    let quality = args.value_of("quality").unwrap_or("m").to_string();
+    if QualityLevel::from_letter(&quality).is_none()
+        && !config.transcoding.profiles.contains_key(&quality)
+    {
+        return arg_error!(
+            "quality",
+            "Unknown transcoding level or profile {}",
+            quality
+        );
+    }
+    let path = args
+        .value_of_os("path")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    if path
+        .components()
+        .any(|c| !matches!(c, std::path::Component::Normal(_)))
+    {
+        return arg_error!("path", "Path must be relative to collection directory");
+    }
+    if !config.base_dirs[collection].join(&path).exists() {
+        return arg_error!("path", "Path {:?} does not exist in collection", path);
+    }
+    let parallel = args
+        .value_of("parallel")
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(config.transcoding.max_parallel_processes);
+    if parallel < 1 {
+        return arg_error!("parallel", "At least one transcoding must be allowed");
+    }
+    Ok(PretranscodeTask {
+        collection,
+        path,
+        quality,
+        parallel,
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -549,6 +831,11 @@ mod test {
             "99",
             "--token-validity-days",
             "99",
+            "--login-max-failures",
+            "5",
+            "--login-ban-minutes",
+            "99",
+            "--allow-basic-auth",
             "--client-dir",
             "test_data",
             "--secret-file",
@@ -573,6 +860,9 @@ mod test {
         assert_eq!(99, c.transcoding.max_parallel_processes);
         assert_eq!(99, c.transcoding.max_runtime_hours);
         assert_eq!(99 * 24, c.token_validity_hours);
+        assert_eq!(5, c.login_lockout.max_failures);
+        assert_eq!(99, c.login_lockout.ban_minutes);
+        assert!(c.basic_auth);
         assert_eq!(PathBuf::from("test_data"), c.client_dir);
         assert_eq!(PathBuf::from("test_data/some_secret"), c.secret_file);
         assert_eq!(99, c.chapters.from_duration);
@@ -595,6 +885,8 @@ mod test {
             "--t-cache-max-files",
             "999",
             "--t-cache-save-often",
+            "--t-cache-pretranscode",
+            "2",
             "test_data",
         ])
         .unwrap();
@@ -604,6 +896,85 @@ mod test {
         assert_eq!(999, c.transcoding.cache.max_files);
         assert!(!c.transcoding.cache.disabled);
         assert!(c.transcoding.cache.save_often);
+        assert_eq!(2, c.transcoding.cache.pretranscode_next);
+    }
+
+    #[test]
+    #[cfg(feature = "transcoding-cache")]
+    fn test_pretranscode() {
+        init_default_config();
+        let c = parse_args_from(&[
+            "audioserve",
+            "test_data",
+            "pretranscode",
+            "--quality",
+            "h",
+            "--path",
+            "01-file.mp3",
+            "--parallel",
+            "3",
+        ])
+        .unwrap();
+        let task = c.pretranscode.unwrap();
+        assert_eq!(0, task.collection);
+        assert_eq!("h", task.quality);
+        assert_eq!(PathBuf::from("01-file.mp3"), task.path);
+        assert_eq!(3, task.parallel);
+
+        let res = parse_args_from(&["audioserve", "test_data", "pretranscode", "--quality", "x"]);
+        assert!(res.is_err());
+        let res = parse_args_from(&[
+            "audioserve",
+            "test_data",
+            "pretranscode",
+            "--path",
+            "../src",
+        ]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "transcoding-cache")]
+    fn test_cache_command() {
+        init_default_config();
+        let c = parse_args_from(&["audioserve", "--t-cache-list", "test_data"]).unwrap();
+        assert_eq!(Some(CacheCommand::List), c.cache_command);
+        let c = parse_args_from(&[
+            "audioserve",
+            "--t-cache-purge-quality",
+            "m",
+            "--t-cache-purge-path",
+            "music",
+            "test_data",
+        ])
+        .unwrap();
+        assert_eq!(
+            Some(CacheCommand::Purge(PurgeFilter {
+                quality: Some("m".into()),
+                path: Some((0, PathBuf::from("music"))),
+            })),
+            c.cache_command
+        );
+        let res = parse_args_from(&["audioserve", "--t-cache-purge-quality", "x", "test_data"]);
+        assert!(res.is_err());
+        let res = parse_args_from(&[
+            "audioserve",
+            "--t-cache-purge-path",
+            "music",
+            "--t-cache-purge-collection",
+            "1",
+            "test_data",
+        ]);
+        assert!(res.is_err());
+        let res = parse_args_from(&[
+            "audioserve",
+            "--t-cache-purge-all",
+            "--t-cache-list",
+            "test_data",
+        ]);
+        assert!(res.is_err());
+        let c = parse_args_from(&["audioserve", "--no-authentication", "test_data"]).unwrap();
+        assert!(c.cache_command.is_none());
     }
 
     #[test]
@@ -647,5 +1018,53 @@ mod test {
         assert_eq!("neco", c.ssl.as_ref().unwrap().key_password);
         assert_eq!(Some("asecret".into()), c.shared_secret);
         assert_eq!(Some("/user/audioserve".into()), c.url_path_prefix);
+        assert_eq!(1, c.api_keys.len());
+        assert_eq!(ApiKeyScope::List, c.api_keys[0].scope);
+        assert_eq!(6, c.transcoding.hls_segment_duration);
+        assert_eq!(96, c.transcoding.get(QualityLevel::Hls).bitrate());
+        assert_eq!(16, c.transcoding.queue.max_length);
+        assert_eq!(30, c.transcoding.queue.timeout);
+        #[cfg(feature = "transcoding-cache")]
+        {
+            assert_eq!(720, c.transcoding.cache.max_age);
+            assert_eq!(80, c.transcoding.cache.low_watermark);
+            assert_eq!(90, c.transcoding.cache.high_watermark);
+        }
+    }
+
+    #[test]
+    fn test_users_file() {
+        init_default_config();
+        let c = parse_args_from(&[
+            "audioserve",
+            "--users-file",
+            "test_data/users.yaml",
+            "test_data",
+        ])
+        .unwrap();
+
+        assert!(c.shared_secret.is_none());
+        assert_eq!(2, c.users.len());
+        assert_eq!("usak", c.users[0].name);
+    }
+
+    #[test]
+    #[cfg(feature = "behind-proxy")]
+    fn test_proxy_auth() {
+        init_default_config();
+        let c = parse_args_from(&[
+            "audioserve",
+            "--behind-proxy",
+            "--proxy-auth-trusted",
+            "10.0.0.0/8,127.0.0.1",
+            "--proxy-auth-header",
+            "X-Forwarded-User",
+            "test_data",
+        ])
+        .unwrap();
+
+        let proxy_auth = c.proxy_auth.unwrap();
+        assert_eq!("X-Forwarded-User", proxy_auth.header);
+        assert_eq!(2, proxy_auth.trusted_networks().len());
     }
 }
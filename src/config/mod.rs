@@ -1,11 +1,17 @@
 pub use self::error::{Error, Result};
-use super::services::transcode::{QualityLevel, Transcoder, TranscodingFormat};
+use super::services::auth::Identity;
+use super::services::transcode::{
+    negotiate::AUTO_PARAM, QualityLevel, Transcoder, TranscodingFormat, TranscodingProfile,
+};
 use crate::util;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
 use std::time::Duration;
 
 mod cli;
@@ -13,11 +19,22 @@ mod validators;
 #[macro_use]
 mod error;
 
-static mut CONFIG: Option<Config> = None;
+static CONFIG: AtomicPtr<Config> = AtomicPtr::new(ptr::null_mut());
 
-// CONFIG is assured to be inited only once from main thread
+// CONFIG is inited once from main thread and then can be only replaced by reload_config
 pub fn get_config() -> &'static Config {
-    unsafe { CONFIG.as_ref().expect("Config is not initialized") }
+    unsafe {
+        CONFIG
+            .load(Ordering::Acquire)
+            .as_ref()
+            .expect("Config is not initialized")
+    }
+}
+
+// Previous config is intentionally leaked - references to it can be still used (config is 'static),
+// so each reload leaks one config (few kB), reloads are rare and they are skipped if config is not changed
+fn set_config(config: Config) {
+    CONFIG.store(Box::into_raw(Box::new(config)), Ordering::Release);
 }
 
 static mut BASE_DATA_DIR: Option<PathBuf> = None;
@@ -39,6 +56,16 @@ pub struct TranscodingCacheConfig {
     pub max_files: u32,
     pub disabled: bool,
     pub save_often: bool,
+    /// number of following files in folder transcoded into cache in background
+    pub pretranscode_next: u32,
+    /// entries not accessed for this number of hours are removed, 0 means no expiry
+    pub max_age: u32,
+    /// when limit is reached, entries are evicted until cache usage is under this percentage of limits
+    pub low_watermark: u8,
+    /// background sweeper evicts entries, when cache usage is over this percentage of limits
+    pub high_watermark: u8,
+    /// interval of background sweeper in seconds
+    pub sweep_interval: u32,
 }
 
 #[cfg(feature = "transcoding-cache")]
@@ -52,6 +79,11 @@ impl Default for TranscodingCacheConfig {
             max_files: 1024,
             disabled: false,
             save_often: false,
+            pretranscode_next: 0,
+            max_age: 0,
+            low_watermark: 90,
+            high_watermark: 95,
+            sweep_interval: 300,
         }
     }
 }
@@ -81,6 +113,226 @@ impl TranscodingCacheConfig {
             );
         }
 
+        if self.pretranscode_next > 5 {
+            return value_error!(
+                "pretranscode_next",
+                "Pretranscoding more then 5 next files is not supported"
+            );
+        }
+
+        if self.low_watermark == 0 || self.low_watermark > 100 {
+            return value_error!("low_watermark", "Low watermark must be 1 - 100 percent");
+        }
+
+        if self.high_watermark < self.low_watermark || self.high_watermark > 100 {
+            return value_error!(
+                "high_watermark",
+                "High watermark must be between low watermark and 100 percent"
+            );
+        }
+
+        if self.sweep_interval < 1 {
+            return value_error!("sweep_interval", "Sweep interval must be at least 1 sec");
+        }
+
+        Ok(())
+    }
+}
+
+/// Bulk transcoding of collection into transcoding cache, requested by `pretranscode` subcommand
+#[cfg(feature = "transcoding-cache")]
+#[derive(Debug, Clone)]
+pub struct PretranscodeTask {
+    /// index of collection in base_dirs
+    pub collection: usize,
+    /// folder (or chapterized file) within collection
+    pub path: PathBuf,
+    /// letter of transcoding level or profile name
+    pub quality: String,
+    /// number of parallel transcodings
+    pub parallel: usize,
+}
+
+/// Maintenance of transcoding cache requested from command line
+#[cfg(feature = "transcoding-cache")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheCommand {
+    List,
+    /// removes entries selected by filter
+    Purge(PurgeFilter),
+    PurgeAll,
+}
+
+/// Selects entries of transcoding cache to be removed, empty filter selects all entries
+#[cfg(feature = "transcoding-cache")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PurgeFilter {
+    /// letter of transcoding level or profile name
+    pub quality: Option<String>,
+    /// index of collection in base_dirs and folder (or file) within it
+    pub path: Option<(usize, PathBuf)>,
+}
+
+#[cfg(feature = "transcoding-cache")]
+impl PurgeFilter {
+    pub fn is_empty(&self) -> bool {
+        self.quality.is_none() && self.path.is_none()
+    }
+
+    /// Checks that quality and collection exist and path is within collection
+    pub fn check(&self, config: &Config) -> Result<()> {
+        if let Some(ref quality) = self.quality {
+            if QualityLevel::from_letter(quality).is_none()
+                && quality != QualityLevel::Hls.to_letter()
+                && !config.transcoding.profiles.contains_key(quality)
+            {
+                return value_error!(
+                    "quality",
+                    "Unknown transcoding level or profile {}",
+                    quality
+                );
+            }
+        }
+        if let Some((collection, ref path)) = self.path {
+            if !config.has_collection(collection) {
+                return value_error!("collection", "There is no collection {}", collection);
+            }
+            if path
+                .components()
+                .any(|c| !matches!(c, std::path::Component::Normal(_)))
+            {
+                return value_error!("path", "Path must be relative to collection directory");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoudnessConfig {
+    /// target integrated loudness in LUFS
+    pub target: f32,
+    /// maximum true peak in dBTP
+    pub true_peak: f32,
+    /// target loudness range in LU
+    pub range: f32,
+    /// measure loudness of file once and then apply static gain
+    pub two_pass: bool,
+    pub measurements_file: PathBuf,
+}
+
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        LoudnessConfig {
+            target: -16.0,
+            true_peak: -1.5,
+            range: 11.0,
+            two_pass: false,
+            measurements_file: base_data_dir().join("audioserve.loudness"),
+        }
+    }
+}
+
+impl LoudnessConfig {
+    pub fn check(&self) -> Result<()> {
+        if !(-70.0..=-5.0).contains(&self.target) {
+            return value_error!("target", "Target loudness must be between -70 and -5 LUFS");
+        }
+        if !(-9.0..=0.0).contains(&self.true_peak) {
+            return value_error!("true_peak", "True peak must be between -9 and 0 dBTP");
+        }
+        if !(1.0..=20.0).contains(&self.range) {
+            return value_error!("range", "Loudness range must be between 1 and 20 LU");
+        }
+        if self.two_pass && !util::parent_dir_exists(&self.measurements_file) {
+            return value_error!(
+                "measurements_file",
+                "Parent directory does not exists for {:?}",
+                self.measurements_file
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TranscodingQueueConfig {
+    /// max. number of requests waiting for transcoding, 0 means requests are rejected immediately
+    pub max_length: usize,
+    /// max. number of waiting requests from one client
+    pub max_per_client: usize,
+    /// how long (in secs) request can wait in queue
+    pub timeout: u32,
+}
+
+impl Default for TranscodingQueueConfig {
+    fn default() -> Self {
+        TranscodingQueueConfig {
+            max_length: 32,
+            max_per_client: 4,
+            timeout: 60,
+        }
+    }
+}
+
+impl TranscodingQueueConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.max_length > 0 && self.max_per_client < 1 {
+            return value_error!(
+                "max_per_client",
+                "At least one request per client must be allowed in queue"
+            );
+        }
+        if self.timeout < 1 || self.timeout > 3600 {
+            return value_error!("timeout", "Queue timeout must be between 1 and 3600 secs");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SilenceConfig {
+    /// audio bellow this level (in dB) is considered silence
+    pub threshold: f32,
+    /// minimum duration of silence (in secs) to be removed
+    pub min_duration: f32,
+    /// duration of silence (in secs) left in place of removed silence
+    pub keep: f32,
+}
+
+impl Default for SilenceConfig {
+    fn default() -> Self {
+        SilenceConfig {
+            threshold: -50.0,
+            min_duration: 1.0,
+            keep: 0.3,
+        }
+    }
+}
+
+impl SilenceConfig {
+    pub fn check(&self) -> Result<()> {
+        if !(-90.0..=0.0).contains(&self.threshold) {
+            return value_error!(
+                "threshold",
+                "Silence threshold must be between -90 and 0 dB"
+            );
+        }
+        if !(0.1..=60.0).contains(&self.min_duration) {
+            return value_error!(
+                "min_duration",
+                "Minimum silence duration must be between 0.1 and 60 secs"
+            );
+        }
+        if self.keep < 0.0 || self.keep > self.min_duration {
+            return value_error!(
+                "keep",
+                "Kept silence must be between 0 and minimum silence duration"
+            );
+        }
         Ok(())
     }
 }
@@ -88,13 +340,27 @@ impl TranscodingCacheConfig {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default)]
 pub struct TranscodingConfig {
+    /// used on start, change requires restart
     pub max_parallel_processes: usize,
     pub max_runtime_hours: u32,
+    /// length of HLS segment in seconds
+    pub hls_segment_duration: u32,
+    /// queue of requests waiting for transcoding, when max_parallel_processes is reached
+    pub queue: TranscodingQueueConfig,
+    /// parameters of loudness normalization, which is enabled in profile or request
+    pub loudness: LoudnessConfig,
+    /// parameters of silence removal, which is enabled in request
+    pub silence: SilenceConfig,
     #[cfg(feature = "transcoding-cache")]
     pub cache: TranscodingCacheConfig,
-    low: TranscodingFormat,
-    medium: TranscodingFormat,
-    high: TranscodingFormat,
+    low: TranscodingProfile,
+    medium: TranscodingProfile,
+    high: TranscodingProfile,
+    /// used for HLS segments, when client does not select transcoding
+    hls: TranscodingProfile,
+    /// user defined profiles, selected by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, TranscodingProfile>,
 }
 
 impl Default for TranscodingConfig {
@@ -102,22 +368,38 @@ impl Default for TranscodingConfig {
         TranscodingConfig {
             max_parallel_processes: (2 * num_cpus::get()),
             max_runtime_hours: 24,
+            hls_segment_duration: 10,
+            queue: TranscodingQueueConfig::default(),
+            loudness: LoudnessConfig::default(),
+            silence: SilenceConfig::default(),
             #[cfg(feature = "transcoding-cache")]
             cache: TranscodingCacheConfig::default(),
-            low: TranscodingFormat::default_level(QualityLevel::Low),
-            medium: TranscodingFormat::default_level(QualityLevel::Medium),
-            high: TranscodingFormat::default_level(QualityLevel::High),
+            low: TranscodingFormat::default_level(QualityLevel::Low).into(),
+            medium: TranscodingFormat::default_level(QualityLevel::Medium).into(),
+            high: TranscodingFormat::default_level(QualityLevel::High).into(),
+            hls: TranscodingFormat::default_level(QualityLevel::Hls).into(),
+            profiles: BTreeMap::new(),
         }
     }
 }
 
 impl TranscodingConfig {
     pub fn get(&self, quality: QualityLevel) -> TranscodingFormat {
+        self.profile(quality).format
+    }
+
+    pub fn profile(&self, quality: QualityLevel) -> TranscodingProfile {
         match quality {
             QualityLevel::Low => self.low.clone(),
             QualityLevel::Medium => self.medium.clone(),
             QualityLevel::High => self.high.clone(),
-            QualityLevel::Passthrough => TranscodingFormat::Remux,
+            QualityLevel::Passthrough => TranscodingFormat::Remux.into(),
+            QualityLevel::Hls => self.hls.clone(),
+            QualityLevel::Profile(name) => self.profiles.get(name).cloned().unwrap_or_else(|| {
+                // can happen when profile is removed by config reload
+                warn!("Unknown transcoding profile {}, using medium", name);
+                self.medium.clone()
+            }),
         }
     }
 
@@ -137,6 +419,41 @@ impl TranscodingConfig {
         if self.max_runtime_hours < 1 {
             return value_error!("max_runtime_hours", "Minimum time is 1 hour");
         }
+
+        if !(2..=60).contains(&self.hls_segment_duration) {
+            return value_error!(
+                "hls_segment_duration",
+                "HLS segment duration must be between 2 and 60 seconds"
+            );
+        }
+
+        if !self.hls.format.hls_capable() {
+            return value_error!("hls", "HLS transcoding must use aac-in-adts or mp3 codec");
+        }
+
+        for (name, profile) in &self.profiles {
+            if name.is_empty()
+                || QualityLevel::from_letter(name).is_some()
+                || name == "p"
+                || name == QualityLevel::Hls.to_letter()
+                || name == AUTO_PARAM
+            {
+                return value_error!("profiles", "Invalid transcoding profile name {:?}", name);
+            }
+            if name.contains(|c| c == ':' || c == '/') {
+                return value_error!(
+                    "profiles",
+                    "Transcoding profile name {} cannot contain : or /",
+                    name
+                );
+            }
+            if let TranscodingFormat::Remux = profile.format {
+                return value_error!("profiles", "Profile {} cannot be remux", name);
+            }
+        }
+        self.queue.check()?;
+        self.loudness.check()?;
+        self.silence.check()?;
         #[cfg(feature = "transcoding-cache")]
         self.cache.check()?;
         Ok(())
@@ -225,6 +542,91 @@ impl ChaptersSize {
     }
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoginLockoutConfig {
+    pub max_failures: u32,
+    pub ban_minutes: u32,
+}
+
+impl Default for LoginLockoutConfig {
+    fn default() -> Self {
+        LoginLockoutConfig {
+            max_failures: 10,
+            ban_minutes: 60,
+        }
+    }
+}
+
+impl LoginLockoutConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.max_failures < 3 {
+            return value_error!(
+                "max_failures",
+                "At least 3 failed logins should be allowed before ban"
+            );
+        }
+
+        if self.ban_minutes < 1 {
+            return value_error!("ban_minutes", "Minimal ban time is 1 minute");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "behind-proxy")]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProxyAuthConfig {
+    /// header with user name set by authenticating proxy
+    pub header: String,
+    /// networks (in CIDR notation) of proxies, which are trusted to set the header
+    pub trusted_proxies: Vec<String>,
+}
+
+#[cfg(feature = "behind-proxy")]
+impl Default for ProxyAuthConfig {
+    fn default() -> Self {
+        ProxyAuthConfig {
+            header: "X-Remote-User".into(),
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+#[cfg(feature = "behind-proxy")]
+impl ProxyAuthConfig {
+    pub fn header_name(&self) -> Option<hyper::header::HeaderName> {
+        hyper::header::HeaderName::from_bytes(self.header.as_bytes()).ok()
+    }
+
+    pub fn trusted_networks(&self) -> Vec<proxy_headers::IpNetwork> {
+        self.trusted_proxies
+            .iter()
+            .filter_map(|n| n.parse().ok())
+            .collect()
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.header_name().is_none() {
+            return value_error!("header", "{} is not valid header name", self.header);
+        }
+
+        if self.trusted_proxies.is_empty() {
+            return value_error!("trusted_proxies", "At least one trusted proxy is needed");
+        }
+
+        for n in &self.trusted_proxies {
+            if n.parse::<proxy_headers::IpNetwork>().is_err() {
+                return value_error!("trusted_proxies", "{} is not valid IP network", n);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SslConfig {
     pub key_file: PathBuf,
@@ -240,17 +642,112 @@ impl SslConfig {
     }
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserConfig {
+    pub name: String,
+    /// password hash as pbkdf2-sha256$iterations$base64(salt)$base64(hash),
+    /// older format base64(salt)|base64(sha256(password+salt)) is still accepted
+    pub password_hash: String,
+    /// admin can see and revoke sessions of other users
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl UserConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return value_error!("users", "User name cannot be empty");
+        }
+
+        if !crate::services::auth::is_valid_password_hash(&self.password_hash) {
+            return value_error!("users", "Invalid password hash for user {}", self.name);
+        }
+
+        Ok(())
+    }
+}
+
+/// What API key can be used for, each scope includes also all lower scopes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    /// listing collections, folders, search, recent, covers and descriptions
+    List,
+    /// audio files and playback positions
+    Stream,
+    /// folder download
+    Download,
+    /// everything
+    Admin,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiKeyConfig {
+    pub name: String,
+    /// hex encoded SHA-256 hash of the key
+    pub key_hash: String,
+    pub scope: ApiKeyScope,
+    /// key is used on behalf of this user, if not set it's same as shared secret
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+impl ApiKeyConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return value_error!("api_keys", "API key name cannot be empty");
+        }
+
+        if !crate::services::auth::is_valid_api_key_hash(&self.key_hash) {
+            return value_error!("api_keys", "Invalid key hash for API key {}", self.name);
+        }
+
+        Ok(())
+    }
+}
+
+/// Who can access a collection, collections without access rules are accessible to all
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CollectionAccess {
+    /// names of users allowed to access collection
+    pub users: Vec<String>,
+    /// whether clients authenticated with shared secret can access collection
+    pub shared: bool,
+}
+
+impl CollectionAccess {
+    pub fn allows(&self, identity: &Identity) -> bool {
+        match identity {
+            Identity::Shared => self.shared,
+            Identity::User(name) => self.users.iter().any(|u| u == name),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Config {
     pub listen: SocketAddr,
     pub thread_pool: ThreadPoolConfig,
     pub base_dirs: Vec<PathBuf>,
+    /// collections removed on reload - they keep their place in base_dirs,
+    /// so numbers of other collections do not change
+    #[serde(skip)]
+    pub removed_collections: BTreeSet<usize>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub collections_access: HashMap<PathBuf, CollectionAccess>,
     pub url_path_prefix: Option<String>,
     pub shared_secret: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<UserConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub api_keys: Vec<ApiKeyConfig>,
     pub limit_rate: Option<f32>,
     pub transcoding: TranscodingConfig,
     pub token_validity_hours: u32,
+    pub login_lockout: LoginLockoutConfig,
+    pub basic_auth: bool,
     pub secret_file: PathBuf,
     pub client_dir: PathBuf,
     pub cors: bool,
@@ -262,13 +759,90 @@ pub struct Config {
     pub no_dir_collaps: bool,
     pub ignore_chapters_meta: bool,
     pub positions_file: PathBuf,
+    pub sessions_file: PathBuf,
     pub positions_ws_timeout: Duration,
     pub behind_proxy: bool,
+    #[cfg(feature = "behind-proxy")]
+    pub proxy_auth: Option<ProxyAuthConfig>,
+    /// if present, audioserve just transcodes files into cache and exits
+    #[cfg(feature = "transcoding-cache")]
+    #[serde(skip)]
+    pub pretranscode: Option<PretranscodeTask>,
+    /// if present, audioserve just runs this command on transcoding cache and exits
+    #[cfg(feature = "transcoding-cache")]
+    #[serde(skip)]
+    pub cache_command: Option<CacheCommand>,
 }
 
 impl Config {
     pub fn transcoder(&self, transcoding_quality: QualityLevel) -> Transcoder {
-        Transcoder::new(get_config().transcoding.get(transcoding_quality))
+        Transcoder::from_profile(self.transcoding.profile(transcoding_quality))
+    }
+
+    /// Takes options, which cannot be changed without restart, from old config
+    fn keep_startup_options(&mut self, old: &Config) {
+        macro_rules! keep {
+            ($($field:ident).+) => {
+                if format!("{:?}", self.$($field).+) != format!("{:?}", old.$($field).+) {
+                    warn!(
+                        "Change of {} requires restart, keeping old value",
+                        stringify!($($field).+)
+                    );
+                }
+                self.$($field).+ = old.$($field).+.clone();
+            };
+        }
+
+        keep!(listen);
+        keep!(thread_pool);
+        keep!(ssl);
+        keep!(shared_secret);
+        keep!(users);
+        keep!(api_keys);
+        keep!(basic_auth);
+        keep!(token_validity_hours);
+        keep!(login_lockout);
+        keep!(limit_rate);
+        keep!(secret_file);
+        keep!(sessions_file);
+        keep!(positions_file);
+        keep!(positions_ws_timeout);
+        keep!(search_cache);
+        keep!(allow_symlinks);
+        keep!(behind_proxy);
+        keep!(transcoding.max_parallel_processes);
+        keep!(transcoding.loudness.measurements_file);
+        #[cfg(feature = "transcoding-cache")]
+        keep!(transcoding.cache);
+        #[cfg(feature = "behind-proxy")]
+        keep!(proxy_auth);
+    }
+
+    /// Collection number is used by clients, so collections keep their numbers from old config,
+    /// removed collections keep their place (but are not accessible) and new ones are added at the end
+    fn keep_collection_numbers(&mut self, old: &Config) {
+        let mut base_dirs = old.base_dirs.clone();
+        let mut removed = BTreeSet::new();
+        for (i, dir) in old.base_dirs.iter().enumerate() {
+            if !self.base_dirs.contains(dir) {
+                if !old.removed_collections.contains(&i) {
+                    info!("Collection {} {:?} removed", i, dir);
+                }
+                removed.insert(i);
+            }
+        }
+        for dir in &self.base_dirs {
+            if !base_dirs.contains(dir) {
+                base_dirs.push(dir.clone());
+            }
+        }
+        self.base_dirs = base_dirs;
+        self.removed_collections = removed;
+    }
+
+    /// Collection with this number exists and was not removed
+    pub fn has_collection(&self, collection: usize) -> bool {
+        collection < self.base_dirs.len() && !self.removed_collections.contains(&collection)
     }
 
     pub fn add_base_dir<P: Into<PathBuf>>(&mut self, p: P) -> Result<()> {
@@ -280,6 +854,30 @@ impl Config {
         Ok(())
     }
 
+    /// Numbers (indexes in base_dirs) of collections accessible for identity
+    pub fn accessible_collections(&self, identity: Option<&Identity>) -> Vec<usize> {
+        (0..self.base_dirs.len())
+            .filter(|&i| self.can_access_collection(i, identity))
+            .collect()
+    }
+
+    /// If there is no identity (authentication is disabled), all collections are accessible,
+    /// collections removed on reload are not accessible at all
+    pub fn can_access_collection(&self, collection: usize, identity: Option<&Identity>) -> bool {
+        if self.removed_collections.contains(&collection) {
+            return false;
+        }
+        match identity {
+            None => true,
+            Some(identity) => self
+                .base_dirs
+                .get(collection)
+                .and_then(|d| self.collections_access.get(d))
+                .map(|access| access.allows(identity))
+                .unwrap_or(true),
+        }
+    }
+
     pub fn set_shared_secret_from_file<P: AsRef<Path> + std::fmt::Debug>(
         &mut self,
         file: P,
@@ -307,6 +905,21 @@ impl Config {
         }
     }
 
+    pub fn set_users_from_file<P: AsRef<Path> + std::fmt::Debug>(&mut self, file: P) -> Result<()> {
+        let f = File::open(&file).or_else(|e| {
+            value_error!(
+                "users",
+                "Users file {:?} does not exists or is not readable: {}",
+                file,
+                e
+            )
+        })?;
+        let users: Vec<UserConfig> = serde_yaml::from_reader(f)
+            .or_else(|e| value_error!("users", "Invalid users file {:?}, error: {}", file, e))?;
+        self.users.extend(users);
+        Ok(())
+    }
+
     pub fn check(&self) -> Result<()> {
         if self
             .shared_secret
@@ -318,6 +931,36 @@ impl Config {
             return value_error!("shared_secret", "Shared secret must be at least 3 bytes");
         }
 
+        for (i, u) in self.users.iter().enumerate() {
+            u.check()?;
+            if self.users[..i].iter().any(|o| o.name == u.name) {
+                return value_error!("users", "Duplicate user name {}", u.name);
+            }
+        }
+
+        for (i, k) in self.api_keys.iter().enumerate() {
+            k.check()?;
+            if self.api_keys[..i].iter().any(|o| o.name == k.name) {
+                return value_error!("api_keys", "Duplicate API key name {}", k.name);
+            }
+            if let Some(ref user) = k.user {
+                if !self.users.iter().any(|u| &u.name == user) {
+                    return value_error!(
+                        "api_keys",
+                        "Unknown user {} for API key {}",
+                        user,
+                        k.name
+                    );
+                }
+            } else if self.shared_secret.is_none() {
+                return value_error!(
+                    "api_keys",
+                    "API key {} without user requires shared secret",
+                    k.name
+                );
+            }
+        }
+
         if self.token_validity_hours < 240 {
             return value_error!(
                 "token-validity-days",
@@ -353,12 +996,34 @@ impl Config {
             );
         };
 
+        if !util::parent_dir_exists(&self.sessions_file) {
+            return value_error!(
+                "sessions_file",
+                "Parent directory for does not exists for {:?}",
+                self.sessions_file
+            );
+        };
+
         if self.ssl.is_some() {
             self.ssl.as_ref().unwrap().check()?
         }
 
+        #[cfg(feature = "behind-proxy")]
+        {
+            if let Some(ref proxy_auth) = self.proxy_auth {
+                if !self.behind_proxy {
+                    return value_error!(
+                        "proxy_auth",
+                        "Authentication by proxy requires behind_proxy to be enabled"
+                    );
+                }
+                proxy_auth.check()?
+            }
+        }
+
         self.transcoding.check()?;
         self.thread_pool.check()?;
+        self.login_lockout.check()?;
         self.chapters.check()?;
 
         if self.base_dirs.is_empty() {
@@ -374,6 +1039,16 @@ impl Config {
             }
         }
 
+        for d in self.collections_access.keys() {
+            if !self.base_dirs.contains(d) {
+                return value_error!(
+                    "collections_access",
+                    "{:?} is not one of collections directories",
+                    d
+                );
+            }
+        }
+
         if let Some(url) = &self.url_path_prefix {
             if let Err(e) = validators::is_valid_url_path_prefix(url.clone()) {
                 return value_error!("url_path_prefix", e);
@@ -389,13 +1064,19 @@ impl Default for Config {
         let data_base_dir = base_data_dir();
         Config {
             base_dirs: vec![],
+            removed_collections: BTreeSet::new(),
+            collections_access: HashMap::new(),
             url_path_prefix: None,
             listen: ([0, 0, 0, 0], 3000u16).into(),
             thread_pool: ThreadPoolConfig::default(),
             shared_secret: None,
+            users: vec![],
+            api_keys: vec![],
             limit_rate: None,
             transcoding: TranscodingConfig::default(),
             token_validity_hours: 365 * 24,
+            login_lockout: LoginLockoutConfig::default(),
+            basic_auth: false,
             client_dir: "client/dist".into(),
             secret_file: data_base_dir.join("audioserve.secret"),
             cors: false,
@@ -407,27 +1088,46 @@ impl Default for Config {
             no_dir_collaps: false,
             ignore_chapters_meta: false,
             positions_file: data_base_dir.join("audioserve.positions"),
+            sessions_file: data_base_dir.join("audioserve.sessions"),
             positions_ws_timeout: Duration::from_secs(600),
             behind_proxy: false,
+            #[cfg(feature = "behind-proxy")]
+            proxy_auth: None,
+            #[cfg(feature = "transcoding-cache")]
+            pretranscode: None,
+            #[cfg(feature = "transcoding-cache")]
+            cache_command: None,
         }
     }
 }
 
 pub fn init_config() -> Result<()> {
+    if !CONFIG.load(Ordering::Acquire).is_null() {
+        panic!("Config is already initialied")
+    }
     unsafe {
-        if CONFIG.is_some() {
-            panic!("Config is already initialied")
-        }
-
         BASE_DATA_DIR = Some(dirs::home_dir().unwrap_or_default().join(".audioserve"));
     }
 
     let config = cli::parse_args()?;
+    set_config(config);
 
-    unsafe {
-        CONFIG = Some(config);
-    }
+    Ok(())
+}
 
+/// Parses again same arguments and config file, new config is checked and then replaces current config.
+/// Options, which are used only on server start, are kept from current config.
+pub fn reload_config() -> Result<()> {
+    let mut config = cli::parse_args()?;
+    let old = get_config();
+    config.keep_startup_options(old);
+    config.keep_collection_numbers(old);
+    if format!("{:?}", config) == format!("{:?}", old) {
+        info!("Configuration is not changed");
+        return Ok(());
+    }
+    set_config(config);
+    info!("Configuration reloaded");
     Ok(())
 }
 
@@ -435,7 +1135,7 @@ pub fn init_config() -> Result<()> {
 pub mod init {
     /// Static config initialization for tests
     /// as tests are run concurrently it requires also some synchronication
-    use super::{Config, BASE_DATA_DIR, CONFIG};
+    use super::{set_config, Config, BASE_DATA_DIR};
     use std::path::PathBuf;
     use std::sync::Once;
     static INIT: Once = Once::new();
@@ -447,9 +1147,7 @@ pub mod init {
                 BASE_DATA_DIR = Some(base_dir);
             }
             let config = Config::default();
-            unsafe {
-                CONFIG = Some(config);
-            }
+            set_config(config);
         });
     }
 }
@@ -470,6 +1168,115 @@ mod tests {
         assert_eq!(config.transcoding.medium, des.transcoding.medium);
     }
 
+    #[test]
+    fn test_keep_startup_options() {
+        init_default_config();
+        let old = Config::default();
+        let mut config = Config {
+            listen: ([127, 0, 0, 1], 4444u16).into(),
+            base_dirs: vec!["test_data".into()],
+            shared_secret: Some("usak".into()),
+            cors: true,
+            ..Config::default()
+        };
+        config.keep_startup_options(&old);
+        assert_eq!(old.listen, config.listen);
+        assert!(config.shared_secret.is_none());
+        assert_eq!(vec![PathBuf::from("test_data")], config.base_dirs);
+        assert!(config.cors);
+    }
+
+    #[test]
+    fn test_keep_collection_numbers() {
+        init_default_config();
+        let old = Config {
+            base_dirs: vec!["a".into(), "b".into()],
+            ..Config::default()
+        };
+        let mut config = Config {
+            base_dirs: vec!["c".into(), "b".into(), "a".into()],
+            ..Config::default()
+        };
+        config.keep_collection_numbers(&old);
+        let dirs: Vec<PathBuf> = vec!["a".into(), "b".into(), "c".into()];
+        assert_eq!(dirs, config.base_dirs);
+        assert!(config.removed_collections.is_empty());
+
+        let old = config;
+        let mut config = Config {
+            base_dirs: vec!["c".into(), "b".into()],
+            ..Config::default()
+        };
+        config.keep_collection_numbers(&old);
+        assert_eq!(dirs, config.base_dirs);
+        assert!(!config.has_collection(0));
+        assert!(config.has_collection(1));
+        assert!(config.has_collection(2));
+        assert!(!config.can_access_collection(0, None));
+        assert_eq!(vec![1, 2], config.accessible_collections(None));
+
+        // removed collection gets its number back
+        let old = config;
+        let mut config = Config {
+            base_dirs: vec!["a".into(), "c".into()],
+            ..Config::default()
+        };
+        config.keep_collection_numbers(&old);
+        assert_eq!(dirs, config.base_dirs);
+        assert!(config.has_collection(0));
+        assert!(!config.has_collection(1));
+        assert!(!config.has_collection(3));
+    }
+
+    #[test]
+    fn test_collections_access() {
+        init_default_config();
+        let mut config = Config {
+            base_dirs: vec!["test_data".into(), "client".into()],
+            ..Config::default()
+        };
+        config.collections_access.insert(
+            "client".into(),
+            CollectionAccess {
+                users: vec!["usak".into()],
+                shared: false,
+            },
+        );
+        let usak = Identity::User("usak".into());
+        let kulisak = Identity::User("kulisak".into());
+        assert!(config.can_access_collection(0, Some(&kulisak)));
+        assert!(config.can_access_collection(1, Some(&usak)));
+        assert!(!config.can_access_collection(1, Some(&kulisak)));
+        assert!(!config.can_access_collection(1, Some(&Identity::Shared)));
+        assert!(config.can_access_collection(1, None));
+    }
+
+    #[test]
+    fn test_accessible_collections() {
+        init_default_config();
+        let mut config = Config {
+            base_dirs: vec!["client".into(), "test_data".into(), "src".into()],
+            ..Config::default()
+        };
+        config.collections_access.insert(
+            "client".into(),
+            CollectionAccess {
+                users: vec!["usak".into()],
+                shared: false,
+            },
+        );
+        let kulisak = Identity::User("kulisak".into());
+        let numbers = config.accessible_collections(Some(&kulisak));
+        // numbers are real indexes of collections, not positions in filtered list
+        assert_eq!(vec![1, 2], numbers);
+        assert_eq!(PathBuf::from("test_data"), config.base_dirs[numbers[0]]);
+        assert_eq!(
+            vec![0, 1, 2],
+            config.accessible_collections(Some(&Identity::User("usak".into())))
+        );
+        assert_eq!(vec![0, 1, 2], config.accessible_collections(None));
+    }
+
     use crate::services::transcode::QualityLevel;
     #[test]
     fn test_transcoding_profile_deserialize() {
@@ -485,7 +1292,25 @@ mod tests {
             c2.transcoding.get(QualityLevel::Medium).format_name(),
             "mp3"
         );
+        let vorbis = c2.transcoding.get(QualityLevel::Profile("car-vorbis"));
+        assert_eq!("vorbis-in-ogg", vorbis.format_name());
+        assert_eq!(64, vorbis.bitrate());
+        let flac = c2.transcoding.get(QualityLevel::Profile("archive"));
+        assert_eq!("flac", flac.format_name());
+        assert_eq!("audio/flac", flac.mime().as_ref());
         let c3 = load_file("./test_data/transcodings.2.yaml");
         assert_eq!(c3.transcoding.get(QualityLevel::High).bitrate(), 96);
+        assert_eq!(
+            c3.transcoding
+                .get(QualityLevel::Profile("car-mono-24k"))
+                .bitrate(),
+            24
+        );
+        assert_eq!(
+            c3.transcoding
+                .get(QualityLevel::Profile("hifi"))
+                .format_name(),
+            "aac-in-adts"
+        );
     }
 }
@@ -11,14 +11,16 @@ use futures::prelude::*;
 use hyper::{service::make_service_fn, Server as HttpServer};
 use ring::rand::{SecureRandom, SystemRandom};
 use services::{
-    auth::SharedSecretAuthenticator, search::Search, ServiceFactory, TranscodingDetails,
+    auth::{Authenticator, Identity, SharedSecretAuthenticator},
+    search::{Search, SearchTrait},
+    transcode::queue::TranscodingQueue,
+    ServiceFactory, TranscodingDetails,
 };
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::path::Path;
 use std::pin::Pin;
 use std::process;
-use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 mod config;
@@ -76,20 +78,61 @@ macro_rules! get_url_path {
     };
 }
 
-fn start_server(server_secret: Vec<u8>) -> tokio::runtime::Runtime {
+type DynAuthenticator = Box<dyn Authenticator<Credentials = Identity>>;
+
+#[cfg(feature = "behind-proxy")]
+fn with_proxy_authentication(fallback: Option<DynAuthenticator>) -> Option<DynAuthenticator> {
+    use services::auth::ProxyHeaderAuthenticator;
+    match get_config().proxy_auth {
+        Some(ref proxy_auth) => Some(Box::new(ProxyHeaderAuthenticator::new(
+            proxy_auth.header_name().expect("Invalid proxy header"),
+            proxy_auth.trusted_networks(),
+            fallback,
+        ))),
+        None => fallback,
+    }
+}
+
+#[cfg(not(feature = "behind-proxy"))]
+fn with_proxy_authentication(authenticator: Option<DynAuthenticator>) -> Option<DynAuthenticator> {
+    authenticator
+}
+
+fn create_authenticator(server_secret: Vec<u8>) -> Option<DynAuthenticator> {
+    let cfg = get_config();
+    let authenticator: Option<DynAuthenticator> =
+        if cfg.shared_secret.is_some() || !cfg.users.is_empty() {
+            Some(Box::new(SharedSecretAuthenticator::new(
+                cfg.shared_secret.clone(),
+                cfg.users.iter().cloned(),
+                server_secret,
+                cfg.token_validity_hours,
+                Some(cfg.sessions_file.clone()),
+                &cfg.login_lockout,
+                cfg.basic_auth,
+                cfg.api_keys.iter().cloned(),
+            )))
+        } else {
+            None
+        };
+
+    with_proxy_authentication(authenticator)
+}
+
+fn start_server(server_secret: Vec<u8>, search: Search<String>) -> tokio::runtime::Runtime {
     let cfg = get_config();
 
     let addr = cfg.listen;
     let start_server = async move {
-        let authenticator = get_config().shared_secret.as_ref().map(|secret| {
-            SharedSecretAuthenticator::new(secret.clone(), server_secret, cfg.token_validity_hours)
-        });
+        let authenticator = create_authenticator(server_secret);
         let transcoding = TranscodingDetails {
-            transcodings: Arc::new(AtomicUsize::new(0)),
+            transcodings: Arc::new(TranscodingQueue::new(
+                cfg.transcoding.max_parallel_processes,
+            )),
             max_transcodings: cfg.transcoding.max_parallel_processes,
+            client: None,
         };
-        let svc_factory =
-            ServiceFactory::new(authenticator, Search::new(), transcoding, cfg.limit_rate);
+        let svc_factory = ServiceFactory::new(authenticator, search, transcoding, cfg.limit_rate);
 
         let server: Pin<Box<dyn Future<Output = Result<(), Error>> + Send>> =
             match get_config().ssl.as_ref() {
@@ -148,9 +191,32 @@ fn start_server(server_secret: Vec<u8>) -> tokio::runtime::Runtime {
         .unwrap();
 
     rt.spawn(start_server.map_err(|e| error!("Http Server Error: {}", e)));
+    #[cfg(feature = "transcoding-cache")]
+    {
+        if !get_config().transcoding.cache.disabled {
+            rt.spawn(crate::services::transcode::cache::sweep_cache());
+        }
+    }
     rt
 }
 
+#[cfg(unix)]
+async fn reload_config_on_hangup(search: Search<String>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = signal(SignalKind::hangup()).expect("Cannot create SIGHUP handler");
+    while sighup.recv().await.is_some() {
+        info!("Reloading configuration on SIGHUP");
+        match tokio::task::spawn_blocking(config::reload_config).await {
+            Ok(Ok(())) => {
+                debug!("New config {:?}", get_config());
+                search.update_collections()
+            }
+            Ok(Err(e)) => error!("Invalid configuration, keeping current one: {}", e),
+            Err(e) => error!("Configuration reload panicked: {}", e),
+        }
+    }
+}
+
 #[cfg(not(unix))]
 async fn terminate_server() {
     use tokio::signal;
@@ -171,6 +237,80 @@ async fn terminate_server() {
     )
 }
 
+#[cfg(feature = "transcoding-cache")]
+fn pretranscode(task: &config::PretranscodeTask) -> i32 {
+    use crate::services::transcode::{cache::get_cache, pretranscode::pretranscode_collection};
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let res = rt.block_on(pretranscode_collection(task));
+    if let Err(e) = get_cache().save_index_blocking() {
+        error!("Error saving transcoding cache index {}", e);
+    }
+    match res {
+        Ok(stats) => {
+            println!(
+                "Finished - transcoded {}, already in cache {}, failed {}, not fitting into cache {}",
+                stats.transcoded, stats.cached, stats.failed, stats.skipped
+            );
+            if stats.failed > 0 {
+                3
+            } else {
+                0
+            }
+        }
+        Err(e) => {
+            eprintln!("Pretranscoding error: {}", e);
+            3
+        }
+    }
+}
+
+#[cfg(feature = "transcoding-cache")]
+fn cache_command(cmd: &config::CacheCommand) -> i32 {
+    use crate::services::transcode::cache::{get_cache, purge};
+    use config::CacheCommand;
+    use std::time::UNIX_EPOCH;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let res = rt.block_on(async {
+        match cmd {
+            CacheCommand::List => {
+                for e in get_cache().list().await? {
+                    let last_access = e
+                        .last_access
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs().to_string())
+                        .unwrap_or_default();
+                    println!("{}\t{}\t{}", e.key, e.size, last_access);
+                }
+                let (files, size) = get_cache().usage();
+                println!("Total {} files, {} bytes", files, size);
+            }
+            CacheCommand::Purge(filter) => {
+                let removed = purge(filter.clone()).await?;
+                println!("Removed {} entries", removed);
+            }
+            CacheCommand::PurgeAll => {
+                let removed = purge(Default::default()).await?;
+                println!("Removed {} entries", removed);
+            }
+        }
+        get_cache().save_index().await?;
+        Ok::<_, Error>(())
+    });
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Transcoding cache error: {}", e);
+            3
+        }
+    }
+}
+
 fn main() {
     #[cfg(unix)]
     {
@@ -189,10 +329,14 @@ fn main() {
 
     #[cfg(feature = "transcoding-cache")]
     {
-        use crate::services::transcode::cache::get_cache;
+        use crate::services::transcode::cache::{get_cache, init_cache};
         if get_config().transcoding.cache.disabled {
             info!("Transcoding cache is disabled")
         } else {
+            if let Err(e) = init_cache() {
+                eprintln!("Cannot open transcoding cache: {}", e);
+                process::exit(3)
+            }
             let c = get_cache();
             info!(
                 "Using transcoding cache at {:?}, remaining capacity (files,size) : {:?}",
@@ -200,6 +344,12 @@ fn main() {
                 c.free_capacity()
             )
         }
+        if let Some(task) = get_config().pretranscode.as_ref() {
+            process::exit(pretranscode(task))
+        }
+        if let Some(cmd) = get_config().cache_command.as_ref() {
+            process::exit(cache_command(cmd))
+        }
     }
     let server_secret = match generate_server_secret(&get_config().secret_file) {
         Ok(s) => s,
@@ -209,7 +359,10 @@ fn main() {
         }
     };
 
-    let runtime = start_server(server_secret);
+    let search = Search::new();
+    let runtime = start_server(server_secret, search.clone());
+    #[cfg(unix)]
+    runtime.spawn(reload_config_on_hangup(search));
 
     runtime.block_on(terminate_server());
 
@@ -0,0 +1,128 @@
+use super::Identity;
+use crate::config::{get_config, ApiKeyConfig, ApiKeyScope};
+use crate::services::extract_collection_number;
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
+use hyper::Method;
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+fn hash_key(key: &str) -> String {
+    HEXLOWER.encode(digest(&SHA256, key.as_bytes()).as_ref())
+}
+
+/// Generates new random API key, returns key and its hash
+pub fn generate_api_key() -> (String, String) {
+    let mut random = [0u8; 32];
+    let rng = SystemRandom::new();
+    rng.fill(&mut random)
+        .expect("Cannot generate random number");
+    let key = BASE64URL_NOPAD.encode(&random);
+    let hash = hash_key(&key);
+    (key, hash)
+}
+
+/// Checks that string is valid key hash
+pub fn is_valid_api_key_hash(s: &str) -> bool {
+    HEXLOWER
+        .decode(s.as_bytes())
+        .map(|h| h.len() == 32)
+        .unwrap_or(false)
+}
+
+/// Scope needed for request
+fn required_scope(method: &Method, path: &str) -> ApiKeyScope {
+    if method != Method::GET {
+        return ApiKeyScope::Admin;
+    }
+    let path = extract_collection_number(path, get_config())
+        .map(|(p, _)| p)
+        .unwrap_or(path);
+    if path.starts_with("/audio/") || path.starts_with("/position") {
+        ApiKeyScope::Stream
+    } else if path.starts_with("/download") {
+        ApiKeyScope::Download
+    } else if path.starts_with("/collections")
+        || path.starts_with("/transcodings")
+        || path.starts_with("/folder/")
+        || path == "/search"
+        || path.starts_with("/recent")
+        || path.starts_with("/cover/")
+        || path.starts_with("/desc/")
+    {
+        ApiKeyScope::List
+    } else {
+        ApiKeyScope::Admin
+    }
+}
+
+/// Configured API keys indexed by key hash
+pub struct ApiKeys {
+    keys: HashMap<String, ApiKeyConfig>,
+}
+
+impl ApiKeys {
+    pub fn new<I>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = ApiKeyConfig>,
+    {
+        ApiKeys {
+            keys: keys
+                .into_iter()
+                .map(|k| (k.key_hash.to_lowercase(), k))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn find(&self, key: &str) -> Option<&ApiKeyConfig> {
+        self.keys.get(&hash_key(key.trim()))
+    }
+}
+
+impl ApiKeyConfig {
+    pub fn identity(&self) -> Identity {
+        match self.user {
+            Some(ref name) => Identity::User(name.clone()),
+            None => Identity::Shared,
+        }
+    }
+
+    pub fn allows(&self, method: &Method, path: &str) -> bool {
+        self.scope >= required_scope(method, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::init::init_default_config;
+
+    #[test]
+    fn test_api_keys() {
+        init_default_config();
+        let (key, hash) = generate_api_key();
+        assert!(is_valid_api_key_hash(&hash));
+        assert!(!is_valid_api_key_hash(&key));
+        let keys = ApiKeys::new(vec![ApiKeyConfig {
+            name: "script".into(),
+            key_hash: hash,
+            scope: ApiKeyScope::Stream,
+            user: Some("usak".into()),
+        }]);
+        assert!(keys.find("usak").is_none());
+        let k = keys.find(&key).unwrap();
+        assert_eq!(Identity::User("usak".into()), k.identity());
+        assert!(k.allows(&Method::GET, "/recent"));
+        assert!(k.allows(&Method::GET, "/search"));
+        assert!(k.allows(&Method::GET, "/audio/usak/chapter1.mp3"));
+        assert!(!k.allows(&Method::GET, "/download/usak"));
+        assert!(!k.allows(&Method::GET, "/sessions"));
+        assert!(!k.allows(&Method::POST, "/logout"));
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const BASE_DELAY_MS: u64 = 500;
+const MAX_DELAY_MS: u64 = 16_000;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    // next attempt is not allowed before this time
+    blocked_until: Instant,
+    banned_until: Option<Instant>,
+}
+
+fn backoff(count: u32) -> Duration {
+    let exp = count.saturating_sub(1).min(16);
+    Duration::from_millis((BASE_DELAY_MS << exp).min(MAX_DELAY_MS))
+}
+
+/// Tracks failed login attempts per remote address,
+/// after each failure address must wait exponentially longer before next attempt
+/// and after max_failures address is banned for some time
+pub struct LoginFailures {
+    clients: Mutex<HashMap<IpAddr, Failures>>,
+    max_failures: u32,
+    ban_duration: Duration,
+}
+
+impl LoginFailures {
+    pub fn new(max_failures: u32, ban_duration: Duration) -> Self {
+        LoginFailures {
+            clients: Mutex::new(HashMap::new()),
+            max_failures,
+            ban_duration,
+        }
+    }
+
+    /// Checks if client can attempt to log in, returns remaining time if client is banned
+    /// or still waits after previous failure. As client with previous failures can make only
+    /// one attempt in each backoff period, parallel attempts are rejected too.
+    pub fn blocked(&self, addr: &IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().expect("Poisoned lock");
+        let failures = clients.get_mut(addr)?;
+        let until = match failures.banned_until {
+            Some(until) if until > now => until,
+            _ => failures.blocked_until,
+        };
+        if until > now {
+            Some(until - now)
+        } else {
+            if failures.banned_until.is_none() {
+                failures.blocked_until = now + backoff(failures.count);
+            }
+            None
+        }
+    }
+
+    /// Records failure and returns delay, which should be applied before reply
+    pub fn failed(&self, addr: &IpAddr) -> Duration {
+        let now = Instant::now();
+        let ban_duration = self.ban_duration;
+        let mut clients = self.clients.lock().expect("Poisoned lock");
+        // forget old failures, so map does not grow indefinitely
+        clients.retain(|_, f| {
+            now.duration_since(f.last) < ban_duration
+                || f.banned_until.map(|until| until > now).unwrap_or(false)
+        });
+        let failures = clients.entry(*addr).or_insert(Failures {
+            count: 0,
+            last: now,
+            blocked_until: now,
+            banned_until: None,
+        });
+        if failures
+            .banned_until
+            .map(|until| until <= now)
+            .unwrap_or(false)
+        {
+            // ban expired, start again
+            failures.count = 0;
+            failures.banned_until = None;
+        }
+        failures.count += 1;
+        failures.last = now;
+        // this log line can be used by tools like fail2ban, format should be kept stable
+        warn!(
+            "Login failure from {} (failures count {})",
+            addr, failures.count
+        );
+        if failures.count >= self.max_failures {
+            failures.banned_until = Some(now + ban_duration);
+            warn!(
+                "Login banned for {} for {} secs after {} failures",
+                addr,
+                ban_duration.as_secs(),
+                failures.count
+            );
+        }
+
+        let delay = backoff(failures.count);
+        failures.blocked_until = now + delay;
+        delay
+    }
+
+    pub fn succeeded(&self, addr: &IpAddr) {
+        self.clients.lock().expect("Poisoned lock").remove(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_failures() {
+        let f = LoginFailures::new(3, Duration::from_secs(60));
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        assert!(f.blocked(&addr).is_none());
+        assert_eq!(Duration::from_millis(500), f.failed(&addr));
+        assert_eq!(Duration::from_millis(1000), f.failed(&addr));
+        // must wait after failure
+        assert!(f.blocked(&addr).unwrap() <= Duration::from_millis(1000));
+        assert!(f.blocked(&other).is_none());
+        f.failed(&addr);
+        assert!(f.blocked(&addr).unwrap() > Duration::from_secs(59));
+        assert!(f.blocked(&addr).unwrap() <= Duration::from_secs(60));
+
+        f.failed(&other);
+        f.succeeded(&other);
+        assert_eq!(Duration::from_millis(500), f.failed(&other));
+    }
+
+    #[test]
+    fn test_parallel_attempts() {
+        let f = LoginFailures::new(10, Duration::from_secs(60));
+        let addr: IpAddr = "127.0.0.1".parse().unwrap();
+        f.failed(&addr);
+        f.clients
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .blocked_until = Instant::now();
+        // after previous failure only one attempt is allowed in backoff period
+        assert!(f.blocked(&addr).is_none());
+        assert!(f.blocked(&addr).is_some());
+        f.succeeded(&addr);
+        assert!(f.blocked(&addr).is_none());
+        assert!(f.blocked(&addr).is_none());
+    }
+}
@@ -0,0 +1,1488 @@
+use self::api_key::{ApiKeys, API_KEY_HEADER};
+use self::lockout::LoginFailures;
+use self::sessions::{Session, Sessions};
+use self::share::ShareLink;
+use super::subs::json_response;
+use crate::config::{get_config, ApiKeyConfig, LoginLockoutConfig, UserConfig};
+use crate::error::{bail, Result};
+use crate::services::RequestWrapper;
+use crate::util::ResponseBuilderExt;
+use data_encoding::{BASE64, HEXLOWER};
+use futures::{future, prelude::*};
+use headers::authorization::{Basic, Bearer};
+use headers::{Authorization, ContentLength, ContentType, Cookie, HeaderMapExt};
+use hyper::header::{SET_COOKIE, USER_AGENT, WWW_AUTHENTICATE};
+use hyper::{Body, Method, Response};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{
+    constant_time,
+    digest::{digest, SHA256},
+    hmac, pbkdf2,
+};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{borrow, time::Duration};
+use thiserror::Error;
+use tokio::time::sleep;
+use url::form_urlencoded;
+
+use super::resp;
+
+mod api_key;
+mod lockout;
+#[cfg(feature = "behind-proxy")]
+mod proxy;
+mod sessions;
+mod share;
+
+pub use self::api_key::{generate_api_key, is_valid_api_key_hash};
+#[cfg(feature = "behind-proxy")]
+pub use self::proxy::ProxyHeaderAuthenticator;
+
+pub enum AuthResult<T> {
+    Authenticated {
+        credentials: T,
+        request: RequestWrapper,
+    },
+    Rejected(Response<Body>),
+    LoggedIn(Response<Body>),
+    /// Request was fully processed by authenticator (e.g. logout)
+    Handled(Response<Body>),
+}
+type AuthFuture<T> = Pin<Box<dyn Future<Output = Result<AuthResult<T>>> + Send>>;
+
+pub trait Authenticator: Send + Sync {
+    type Credentials;
+    fn authenticate(&self, req: RequestWrapper) -> AuthFuture<Self::Credentials>;
+    /// If authenticated client can administer server (e.g. transcoding cache)
+    fn is_admin(&self, credentials: &Self::Credentials) -> bool;
+}
+
+impl<C> Authenticator for Box<dyn Authenticator<Credentials = C>> {
+    type Credentials = C;
+    fn authenticate(&self, req: RequestWrapper) -> AuthFuture<C> {
+        (**self).authenticate(req)
+    }
+
+    fn is_admin(&self, credentials: &C) -> bool {
+        (**self).is_admin(credentials)
+    }
+}
+
+/// Identity of authenticated client
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// Client proved knowledge of shared secret
+    Shared,
+    /// Client logged in as one of configured users
+    User(String),
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Identity::Shared => write!(f, "shared secret"),
+            Identity::User(name) => write!(f, "user {}", name),
+        }
+    }
+}
+
+struct Secrets {
+    shared_secret: Option<String>,
+    users: HashMap<String, UserConfig>,
+    server_secret: Vec<u8>,
+    token_validity_hours: u32,
+    sessions: Sessions,
+    failures: LoginFailures,
+    basic_auth: bool,
+    api_keys: ApiKeys,
+}
+
+#[derive(Clone)]
+pub struct SharedSecretAuthenticator {
+    secrets: Arc<Secrets>,
+}
+
+impl SharedSecretAuthenticator {
+    /// if sessions_file is None issued tokens and revocations are kept only in memory
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<I, K>(
+        shared_secret: Option<String>,
+        users: I,
+        server_secret: Vec<u8>,
+        token_validity_hours: u32,
+        sessions_file: Option<PathBuf>,
+        login_lockout: &LoginLockoutConfig,
+        basic_auth: bool,
+        api_keys: K,
+    ) -> Self
+    where
+        I: IntoIterator<Item = UserConfig>,
+        K: IntoIterator<Item = ApiKeyConfig>,
+    {
+        SharedSecretAuthenticator {
+            secrets: Arc::new(Secrets {
+                shared_secret,
+                users: users
+                    .into_iter()
+                    .map(|u| {
+                        if is_legacy_password_hash(&u.password_hash) {
+                            warn!(
+                                "Password hash of user {} is in old weak format, create new one with --hash-password",
+                                u.name
+                            );
+                        }
+                        (u.name.clone(), u)
+                    })
+                    .collect(),
+                server_secret,
+                token_validity_hours,
+                sessions: Sessions::new(sessions_file),
+                failures: LoginFailures::new(
+                    login_lockout.max_failures,
+                    Duration::from_secs(u64::from(login_lockout.ban_minutes) * 60),
+                ),
+                basic_auth,
+                api_keys: ApiKeys::new(api_keys),
+            }),
+        }
+    }
+}
+
+const COOKIE_NAME: &str = "audioserve_token";
+const BASIC_REALM: &str = "audioserve";
+const DEFAULT_SHARE_VALIDITY_HOURS: u32 = 7 * 24;
+
+/// Denial with Basic authentication challenge, so media players can ask for credentials
+fn deny_basic() -> Response<Body> {
+    let mut resp = resp::deny();
+    resp.headers_mut().insert(
+        WWW_AUTHENTICATE,
+        format!("Basic realm=\"{}\"", BASIC_REALM).parse().unwrap(),
+    );
+    resp
+}
+
+impl Authenticator for SharedSecretAuthenticator {
+    type Credentials = Identity;
+    fn authenticate(&self, mut req: RequestWrapper) -> AuthFuture<Identity> {
+        fn deny() -> AuthResult<Identity> {
+            AuthResult::Rejected(resp::deny())
+        }
+        // this is part where client can authenticate itself and get token
+        if req.method() == Method::POST && req.path() == "/authenticate" {
+            debug!("Authentication request");
+            let client_ip = req.remote_addr().map(|a| *a.as_ref());
+            if let Some(remaining) = client_ip.and_then(|ip| self.secrets.failures.blocked(&ip)) {
+                error!(
+                    "Invalid authentication: client {:?} is blocked for next {} ms",
+                    req.remote_addr(),
+                    remaining.as_millis()
+                );
+                return Box::pin(future::ok(AuthResult::Rejected(resp::too_many_requests())));
+            }
+            let auth = self.secrets.clone();
+            return Box::pin(async move {
+                match req.body_bytes().await {
+                    Err(e) => bail!(e),
+                    Ok(b) => {
+                        let params = form_urlencoded::parse(b.as_ref())
+                            .into_owned()
+                            .collect::<HashMap<String, String>>();
+                        let identity = if let Some(user) = params.get("user") {
+                            debug!("Authenticating user {}", user);
+                            match params.get("password") {
+                                Some(password) if auth.user_password_ok(user, password) => {
+                                    Some(Identity::User(user.clone()))
+                                }
+                                Some(_) => {
+                                    error!(
+                                        "Invalid authentication: invalid password for user {}, client: {:?}",
+                                        user,
+                                        req.remote_addr()
+                                    );
+                                    None
+                                }
+                                None => {
+                                    error!(
+                                        "Invalid authentication: missing password for user {}, client: {:?}",
+                                        user,
+                                        req.remote_addr()
+                                    );
+                                    None
+                                }
+                            }
+                        } else if let Some(secret) = params.get("secret") {
+                            debug!("Authenticating with shared secret");
+                            if auth.auth_token_ok(secret) {
+                                Some(Identity::Shared)
+                            } else {
+                                error!(
+                                    "Invalid authentication: invalid shared secret, client: {:?}",
+                                    req.remote_addr()
+                                );
+                                None
+                            }
+                        } else {
+                            error!(
+                                "Invalid authentication: missing shared secret, client: {:?}",
+                                req.remote_addr()
+                            );
+                            None
+                        };
+
+                        match identity {
+                            Some(identity) => {
+                                debug!("Authentication success for {}", identity);
+                                if let Some(ip) = client_ip {
+                                    auth.failures.succeeded(&ip);
+                                }
+                                let token = auth.new_auth_token(&identity, &req);
+                                let resp = Response::builder()
+                                    .typed_header(ContentType::text())
+                                    .typed_header(ContentLength(token.len() as u64))
+                                    .header(
+                                        SET_COOKIE,
+                                        format!(
+                                            "{}={}; Max-Age={}; SameSite=Lax",
+                                            COOKIE_NAME,
+                                            token,
+                                            u64::from(auth.token_validity_hours) * 3600
+                                        )
+                                        .as_str(),
+                                    );
+
+                                Ok(AuthResult::LoggedIn(resp.body(token.into()).unwrap()))
+                            }
+                            None => {
+                                // Let's not return failure immediately, because somebody is using wrong secret
+                                // Legitimate user can wait a bit, but for brute force attack it can be advantage not to reply quickly
+                                // and delay grows with each subsequent failure from same address
+                                let delay = client_ip
+                                    .map(|ip| auth.failures.failed(&ip))
+                                    .unwrap_or_else(|| Duration::from_millis(500));
+                                sleep(delay).await;
+                                Ok(deny())
+                            }
+                        }
+                    }
+                }
+            });
+        }
+        if !self.secrets.api_keys.is_empty() {
+            if let Some(key) = req
+                .headers()
+                .get(API_KEY_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(ToOwned::to_owned)
+            {
+                return self.authenticate_api_key(req, &key);
+            }
+        }
+
+        // And in this part we check token
+        let mut token = req
+            .headers()
+            .typed_get::<Authorization<Bearer>>()
+            .map(|a| a.0.token().to_owned());
+        if token.is_none() {
+            token = req
+                .headers()
+                .typed_get::<Cookie>()
+                .and_then(|c| c.get(COOKIE_NAME).map(borrow::ToOwned::to_owned));
+        }
+
+        if token.is_none() {
+            if let Some(share) = req
+                .params()
+                .and_then(|p| p.get("share").map(|s| s.to_string()))
+            {
+                return Box::pin(future::ok(self.secrets.share_access(req, &share)));
+            }
+        }
+
+        if token.is_none() && self.secrets.basic_auth {
+            if let Some(basic) = req.headers().typed_get::<Authorization<Basic>>() {
+                return self.authenticate_basic(req, basic.0);
+            }
+        }
+
+        let token = match token {
+            Some(token) => token,
+            None => {
+                error!(
+                    "Invalid access: missing token on path {}, client: {:?}",
+                    req.path(),
+                    req.remote_addr()
+                );
+                // challenge only for media, otherwise browser would show its login dialog to web client
+                if self.secrets.basic_auth && req.path().starts_with("/audio/") {
+                    return Box::pin(future::ok(AuthResult::Rejected(deny_basic())));
+                }
+                return Box::pin(future::ok(deny()));
+            }
+        };
+
+        let token = match self.secrets.token_ok(&token) {
+            Some(token) => token,
+            None => {
+                error!(
+                    "Invalid access: invalid token on path {}, client: {:?}",
+                    req.path(),
+                    req.remote_addr()
+                );
+                return Box::pin(future::ok(deny()));
+            }
+        };
+
+        if req.method() == Method::POST && req.path() == "/logout" {
+            let auth = self.secrets.clone();
+            return Box::pin(async move {
+                let b = req.body_bytes().await?;
+                let id = form_urlencoded::parse(b.as_ref())
+                    .find(|(k, _)| k == "id")
+                    .map(|(_, v)| v.into_owned());
+                Ok(auth.logout(&token, id))
+            });
+        }
+
+        if req.method() == Method::POST && req.path() == "/share" {
+            let auth = self.secrets.clone();
+            return Box::pin(async move {
+                let b = req.body_bytes().await?;
+                let params = form_urlencoded::parse(b.as_ref())
+                    .into_owned()
+                    .collect::<HashMap<String, String>>();
+                Ok(auth.create_share(&token, params))
+            });
+        }
+
+        if req.method() == Method::GET && req.path() == "/sessions" {
+            return Box::pin(future::ok(self.secrets.list_sessions(&token)));
+        }
+
+        // If everything is ok we return credentials (identity from token) and we return back request
+        Box::pin(future::ok(AuthResult::Authenticated {
+            request: req,
+            credentials: token.identity(),
+        }))
+    }
+
+    fn is_admin(&self, credentials: &Identity) -> bool {
+        self.secrets.is_admin(credentials)
+    }
+}
+
+impl SharedSecretAuthenticator {
+    /// API key from header - for scripts and automation
+    fn authenticate_api_key(&self, req: RequestWrapper, key: &str) -> AuthFuture<Identity> {
+        let client_ip = req.remote_addr().map(|a| *a.as_ref());
+        if let Some(remaining) = client_ip.and_then(|ip| self.secrets.failures.blocked(&ip)) {
+            error!(
+                "Invalid API key authentication: client {:?} is blocked for next {} ms",
+                req.remote_addr(),
+                remaining.as_millis()
+            );
+            return Box::pin(future::ok(AuthResult::Rejected(resp::too_many_requests())));
+        }
+
+        match self.secrets.api_keys.find(key) {
+            Some(api_key) => {
+                if !api_key.allows(req.method(), req.path()) {
+                    error!(
+                        "API key {} with scope {:?} is not allowed to access {} {}",
+                        api_key.name,
+                        api_key.scope,
+                        req.method(),
+                        req.path()
+                    );
+                    return Box::pin(future::ok(AuthResult::Rejected(resp::forbidden())));
+                }
+                let identity = api_key.identity();
+                if !self.secrets.identity_known(&identity) {
+                    return Box::pin(future::ok(AuthResult::Rejected(resp::deny())));
+                }
+                debug!("Authenticated by API key {}", api_key.name);
+                Box::pin(future::ok(AuthResult::Authenticated {
+                    request: req,
+                    credentials: identity,
+                }))
+            }
+            None => {
+                error!(
+                    "Invalid access: invalid API key on path {}, client: {:?}",
+                    req.path(),
+                    req.remote_addr()
+                );
+                let delay = client_ip
+                    .map(|ip| self.secrets.failures.failed(&ip))
+                    .unwrap_or_else(|| Duration::from_millis(500));
+                Box::pin(async move {
+                    sleep(delay).await;
+                    Ok(AuthResult::Rejected(resp::deny()))
+                })
+            }
+        }
+    }
+
+    /// HTTP Basic authentication - for clients, which cannot get token via /authenticate
+    fn authenticate_basic(&self, req: RequestWrapper, basic: Basic) -> AuthFuture<Identity> {
+        let client_ip = req.remote_addr().map(|a| *a.as_ref());
+        if let Some(remaining) = client_ip.and_then(|ip| self.secrets.failures.blocked(&ip)) {
+            error!(
+                "Invalid basic authentication: client {:?} is blocked for next {} ms",
+                req.remote_addr(),
+                remaining.as_millis()
+            );
+            return Box::pin(future::ok(AuthResult::Rejected(resp::too_many_requests())));
+        }
+
+        match self
+            .secrets
+            .basic_identity(basic.username(), basic.password())
+        {
+            Some(identity) => {
+                debug!("Basic authentication success for {}", identity);
+                if let Some(ip) = client_ip {
+                    self.secrets.failures.succeeded(&ip);
+                }
+                Box::pin(future::ok(AuthResult::Authenticated {
+                    request: req,
+                    credentials: identity,
+                }))
+            }
+            None => {
+                error!(
+                    "Invalid basic authentication on path {}, client: {:?}",
+                    req.path(),
+                    req.remote_addr()
+                );
+                // same failure delay as for /authenticate
+                let delay = client_ip
+                    .map(|ip| self.secrets.failures.failed(&ip))
+                    .unwrap_or_else(|| Duration::from_millis(500));
+                Box::pin(async move {
+                    sleep(delay).await;
+                    Ok(AuthResult::Rejected(deny_basic()))
+                })
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ShareInfo {
+    share: String,
+    valid_until: u64,
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    #[serde(flatten)]
+    session: Session,
+    current: bool,
+}
+
+fn salted_hash(secret: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut data = secret.to_vec();
+    data.extend(salt);
+    digest(&SHA256, &data).as_ref().to_vec()
+}
+
+fn decode_salted_hash(s: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let parts = s
+        .split('|')
+        .filter_map(|s| match BASE64.decode(s.as_bytes()) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                error!("Invalid base64 in salted hash {} in string {}", e, s);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    if parts.len() != 2 {
+        error!("Incorrectly formed salted hash - {} parts", parts.len());
+        return None;
+    }
+    let mut parts = parts.into_iter();
+    let salt = parts.next().unwrap();
+    let hash = parts.next().unwrap();
+    if salt.len() != 32 {
+        error!("Random salt must be 32 bytes");
+        return None;
+    }
+    Some((salt, hash))
+}
+
+const PASSWORD_HASH_ALGORITHM: &str = "pbkdf2-sha256";
+const PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+const PASSWORD_HASH_LEN: usize = 32;
+
+enum PasswordHash {
+    Pbkdf2 {
+        iterations: NonZeroU32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+    /// older format - just salted sha256
+    Legacy { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+impl PasswordHash {
+    fn parse(s: &str) -> Option<Self> {
+        let parts: Vec<_> = s.split('$').collect();
+        match parts.as_slice() {
+            [algorithm, iterations, salt, hash] if *algorithm == PASSWORD_HASH_ALGORITHM => {
+                let iterations = iterations.parse().ok().and_then(NonZeroU32::new)?;
+                let salt = BASE64.decode(salt.as_bytes()).ok()?;
+                let hash = BASE64.decode(hash.as_bytes()).ok()?;
+                Some(PasswordHash::Pbkdf2 {
+                    iterations,
+                    salt,
+                    hash,
+                })
+            }
+            [_] => decode_salted_hash(s).map(|(salt, hash)| PasswordHash::Legacy { salt, hash }),
+            _ => {
+                error!("Unknown password hash format");
+                None
+            }
+        }
+    }
+
+    fn hash_len(&self) -> usize {
+        match self {
+            PasswordHash::Pbkdf2 { hash, .. } | PasswordHash::Legacy { hash, .. } => hash.len(),
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Pbkdf2 {
+                iterations,
+                salt,
+                hash,
+            } => pbkdf2::verify(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                *iterations,
+                salt,
+                password.as_bytes(),
+                hash,
+            )
+            .is_ok(),
+            PasswordHash::Legacy { salt, hash } => {
+                let hash2 = salted_hash(password.as_bytes(), salt);
+                constant_time::verify_slices_are_equal(hash, &hash2).is_ok()
+            }
+        }
+    }
+}
+
+/// Creates hash of user password (PBKDF2 with random salt), in form suitable for users configuration
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 32];
+    let rng = SystemRandom::new();
+    rng.fill(&mut salt).expect("Cannot generate random number");
+    let mut hash = [0u8; PASSWORD_HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PASSWORD_HASH_ITERATIONS).unwrap(),
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "{}${}${}${}",
+        PASSWORD_HASH_ALGORITHM,
+        PASSWORD_HASH_ITERATIONS,
+        BASE64.encode(&salt),
+        BASE64.encode(&hash)
+    )
+}
+
+/// Checks that string is valid password hash
+pub fn is_valid_password_hash(s: &str) -> bool {
+    PasswordHash::parse(s)
+        .map(|h| h.hash_len() == PASSWORD_HASH_LEN)
+        .unwrap_or(false)
+}
+
+/// Password hash is in older format (salted sha256), which should be replaced
+fn is_legacy_password_hash(s: &str) -> bool {
+    matches!(PasswordHash::parse(s), Some(PasswordHash::Legacy { .. }))
+}
+
+impl Secrets {
+    fn auth_token_ok(&self, token: &str) -> bool {
+        match (self.shared_secret.as_ref(), decode_salted_hash(token)) {
+            (Some(shared_secret), Some((salt, hash))) => {
+                let hash2 = salted_hash(shared_secret.as_bytes(), &salt);
+                constant_time::verify_slices_are_equal(&hash, &hash2).is_ok()
+            }
+            (None, _) => {
+                error!("Shared secret is not configured");
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn user_password_ok(&self, user: &str, password: &str) -> bool {
+        match self
+            .users
+            .get(user)
+            .and_then(|u| PasswordHash::parse(&u.password_hash))
+        {
+            Some(hash) => hash.verify(password),
+            None => {
+                error!("Unknown user {}", user);
+                false
+            }
+        }
+    }
+
+    /// Configured user name with its password, any other user name with shared secret as password
+    fn basic_identity(&self, user: &str, password: &str) -> Option<Identity> {
+        if self.users.contains_key(user) {
+            if self.user_password_ok(user, password) {
+                return Some(Identity::User(user.to_string()));
+            }
+        } else if let Some(ref shared_secret) = self.shared_secret {
+            if constant_time::verify_slices_are_equal(shared_secret.as_bytes(), password.as_bytes())
+                .is_ok()
+            {
+                return Some(Identity::Shared);
+            }
+        }
+        None
+    }
+
+    /// Admin is configured user with admin flag, clients using shared secret are never admins
+    fn is_admin(&self, identity: &Identity) -> bool {
+        match identity {
+            Identity::User(name) => self.users.get(name).map(|u| u.admin).unwrap_or(false),
+            Identity::Shared => false,
+        }
+    }
+
+    fn new_auth_token(&self, identity: &Identity, req: &RequestWrapper) -> String {
+        let token = Token::new(self.token_validity_hours, &self.server_secret, identity);
+        self.sessions.add(Session {
+            id: token.id(),
+            user: match identity {
+                Identity::User(name) => Some(name.clone()),
+                Identity::Shared => None,
+            },
+            created: now(),
+            valid_until: token.validity(),
+            client: req.remote_addr().map(|a| a.to_string()),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(ToOwned::to_owned),
+        });
+        token.into()
+    }
+
+    fn logout(&self, token: &Token, id: Option<String>) -> AuthResult<Identity> {
+        let identity = token.identity();
+        match id {
+            // revoke some other session
+            Some(id) if id != token.id() => {
+                let allowed = self.is_admin(&identity)
+                    || self
+                        .sessions
+                        .get(&id)
+                        .map(|s| s.identity() == identity)
+                        .unwrap_or(false);
+                if !allowed {
+                    error!("{} is not allowed to revoke session {}", identity, id);
+                    return AuthResult::Rejected(resp::deny());
+                }
+                if !self.sessions.revoke(&id, None) {
+                    return AuthResult::Handled(resp::not_found());
+                }
+                debug!("Session {} revoked by {}", id, identity);
+                AuthResult::Handled(
+                    Response::builder()
+                        .typed_header(ContentLength(0))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }
+            _ => {
+                self.sessions.revoke(&token.id(), Some(token.validity()));
+                debug!("Logged out session {} of {}", token.id(), identity);
+                AuthResult::Handled(
+                    Response::builder()
+                        .typed_header(ContentLength(0))
+                        .header(
+                            SET_COOKIE,
+                            format!("{}=; Max-Age=0; SameSite=Lax", COOKIE_NAME).as_str(),
+                        )
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }
+        }
+    }
+
+    fn list_sessions(&self, token: &Token) -> AuthResult<Identity> {
+        let identity = token.identity();
+        // admin can see all sessions, others only own
+        let filter = if self.is_admin(&identity) {
+            None
+        } else {
+            Some(&identity)
+        };
+        let current_id = token.id();
+        let sessions: Vec<_> = self
+            .sessions
+            .list(filter)
+            .into_iter()
+            .map(|session| SessionInfo {
+                current: session.id == current_id,
+                session,
+            })
+            .collect();
+        AuthResult::Handled(json_response(&sessions))
+    }
+
+    /// identity must be still known to the server
+    fn identity_known(&self, identity: &Identity) -> bool {
+        match identity {
+            Identity::Shared => self.shared_secret.is_some(),
+            Identity::User(ref name) => {
+                let known = self.users.contains_key(name);
+                if !known {
+                    warn!("Credentials of unknown user {}", name);
+                }
+                known
+            }
+        }
+    }
+
+    fn create_share(&self, token: &Token, params: HashMap<String, String>) -> AuthResult<Identity> {
+        let owner = token.identity();
+        let collection: usize = match params.get("collection").map(|c| c.parse()) {
+            None => 0,
+            Some(Ok(c)) => c,
+            Some(Err(_)) => {
+                error!("Invalid collection number for share");
+                return AuthResult::Handled(resp::bad_request());
+            }
+        };
+        let path = params
+            .get("path")
+            .map(|p| p.trim_matches('/'))
+            .unwrap_or_default();
+        if path.is_empty() || path.split('/').any(|s| s.is_empty() || s.starts_with('.')) {
+            error!("Invalid path for share {:?}", path);
+            return AuthResult::Handled(resp::bad_request());
+        }
+        let cfg = get_config();
+        if !cfg.has_collection(collection)
+            || !cfg.can_access_collection(collection, Some(&owner))
+            || !cfg.base_dirs[collection].join(path).exists()
+        {
+            error!(
+                "Cannot share {} in collection {} for {}",
+                path, collection, owner
+            );
+            return AuthResult::Handled(resp::not_found());
+        }
+        // link cannot be valid longer then token
+        let valid_hours = params
+            .get("valid_hours")
+            .and_then(|h| h.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_SHARE_VALIDITY_HOURS)
+            .max(1)
+            .min(self.token_validity_hours);
+        let download = params
+            .get("download")
+            .map(|d| d == "true" || d == "1")
+            .unwrap_or(false);
+        let link = ShareLink {
+            collection: collection as u16,
+            path: path.to_string(),
+            valid_until: now() + u64::from(valid_hours) * 3600,
+            download,
+            owner,
+        };
+        debug!("Created share link {:?}", link);
+        AuthResult::Handled(json_response(&ShareInfo {
+            share: link.encode(&self.server_secret),
+            valid_until: link.valid_until,
+        }))
+    }
+
+    fn share_access(&self, req: RequestWrapper, share: &str) -> AuthResult<Identity> {
+        match ShareLink::decode(share, &self.server_secret) {
+            Ok(link) if req.method() == Method::GET && link.allows(req.path()) => {
+                if !self.identity_known(&link.owner) {
+                    return AuthResult::Rejected(resp::deny());
+                }
+                debug!("Access to {} by share link {:?}", req.path(), link);
+                AuthResult::Authenticated {
+                    request: req,
+                    credentials: link.owner,
+                }
+            }
+            Ok(link) => {
+                error!(
+                    "Invalid access: path {} is not allowed by share link {:?}, client: {:?}",
+                    req.path(),
+                    link,
+                    req.remote_addr()
+                );
+                AuthResult::Rejected(resp::deny())
+            }
+            Err(e) => {
+                error!(
+                    "Invalid access: invalid share link on path {} ({}), client: {:?}",
+                    req.path(),
+                    e,
+                    req.remote_addr()
+                );
+                AuthResult::Rejected(resp::deny())
+            }
+        }
+    }
+
+    fn token_ok(&self, token: &str) -> Option<Token> {
+        match token.parse::<Token>() {
+            Ok(token) => {
+                if !token.is_valid(&self.server_secret) {
+                    return None;
+                }
+                if self
+                    .sessions
+                    .is_revoked(&token.id(), &token.identity(), token.validity())
+                {
+                    warn!("Token {} was revoked", token.id());
+                    return None;
+                }
+                if self.identity_known(&token.identity()) {
+                    Some(token)
+                } else {
+                    None
+                }
+            }
+            Err(e) => {
+                warn!("Invalid token: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Token {
+    random: [u8; 32],
+    validity: [u8; 8],
+    signature: [u8; 32],
+    user: Vec<u8>,
+}
+
+fn prepare_data(r: &[u8; 32], v: [u8; 8], user: &[u8]) -> Vec<u8> {
+    let mut to_sign = Vec::with_capacity(40 + user.len());
+    to_sign.extend_from_slice(&r[..]);
+    to_sign.extend_from_slice(&v[..]);
+    to_sign.extend_from_slice(user);
+    to_sign
+}
+
+fn sign(secret: &[u8], data: &[u8]) -> [u8; 32] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    let sig = hmac::sign(&key, data);
+    let slice = sig.as_ref();
+    assert!(slice.len() == 32);
+    let mut signature = [0u8; 32];
+    signature.copy_from_slice(slice);
+    signature
+}
+
+fn signature_ok(secret: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::verify(&key, data, signature).is_ok()
+}
+
+// shared secret identity is empty user, so tokens are compatible with older versions
+fn identity_to_bytes(identity: &Identity) -> Vec<u8> {
+    match identity {
+        Identity::Shared => vec![],
+        Identity::User(name) => name.as_bytes().to_vec(),
+    }
+}
+
+fn identity_from_bytes(user: &[u8]) -> Identity {
+    if user.is_empty() {
+        Identity::Shared
+    } else {
+        Identity::User(String::from_utf8_lossy(user).into_owned())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Invalid system time")
+        .as_secs()
+}
+
+impl Token {
+    fn new(token_validity_hours: u32, secret: &[u8], identity: &Identity) -> Self {
+        let mut random = [0u8; 32];
+        let rng = SystemRandom::new();
+        rng.fill(&mut random)
+            .expect("Cannot generate random number");
+        let validity: u64 = now() + u64::from(token_validity_hours) * 3600;
+        let validity: [u8; 8] = unsafe { ::std::mem::transmute(validity.to_be()) };
+        let user = identity_to_bytes(identity);
+        let to_sign = prepare_data(&random, validity, &user);
+        let signature = sign(secret, &to_sign);
+
+        Token {
+            random,
+            validity,
+            signature,
+            user,
+        }
+    }
+
+    fn is_valid(&self, secret: &[u8]) -> bool {
+        let data = prepare_data(&self.random, self.validity, &self.user);
+        if !signature_ok(secret, &data, &self.signature) {
+            return false;
+        };
+
+        self.validity() > now()
+    }
+
+    fn validity(&self) -> u64 {
+        let ts: u64 = unsafe { ::std::mem::transmute_copy(&self.validity) };
+        u64::from_be(ts)
+    }
+
+    /// Random part of token is used as its unique id
+    fn id(&self) -> String {
+        HEXLOWER.encode(&self.random[..16])
+    }
+
+    fn identity(&self) -> Identity {
+        identity_from_bytes(&self.user)
+    }
+}
+
+impl Into<String> for Token {
+    fn into(self) -> String {
+        let data = [
+            &self.random[..],
+            &self.validity[..],
+            &self.signature[..],
+            &self.user[..],
+        ]
+        .concat();
+        BASE64.encode(&data)
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+enum TokenError {
+    #[error("Invalid token size")]
+    InvalidSize,
+
+    #[error("Invalid token encoding")]
+    InvalidEncoding(#[from] ::data_encoding::DecodeError),
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+
+    #[error("Expired")]
+    Expired,
+
+    #[error("Invalid path in share link")]
+    InvalidPath(#[from] ::std::string::FromUtf8Error),
+}
+
+impl ::std::str::FromStr for Token {
+    type Err = TokenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = BASE64.decode(s.as_bytes())?;
+        if bytes.len() < 72 {
+            return Err(TokenError::InvalidSize);
+        };
+        let mut random = [0u8; 32];
+        let mut validity = [0u8; 8];
+        let mut signature = [0u8; 32];
+
+        random.copy_from_slice(&bytes[0..32]);
+        validity.copy_from_slice(&bytes[32..40]);
+        signature.copy_from_slice(&bytes[40..72]);
+        let user = bytes[72..].to_vec();
+
+        Ok(Token {
+            random,
+            validity,
+            signature,
+            user,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::init::init_default_config;
+    use borrow::Cow;
+    use hyper::{Request, StatusCode};
+
+    #[test]
+    fn test_token() {
+        let token = Token::new(24, b"my big secret", &Identity::Shared);
+        assert!(token.is_valid(b"my big secret"));
+        let orig_token = token.clone();
+        let serialized_token: String = token.into();
+        assert!(serialized_token.len() >= 72);
+        let new_token: Token = serialized_token.parse().unwrap();
+        assert_eq!(orig_token, new_token);
+        assert!(new_token.is_valid(b"my big secret"));
+        assert!(!new_token.is_valid(b"wrong secret"));
+        assert!(new_token.validity() - now() <= 24 * 3600);
+        assert_eq!(Identity::Shared, new_token.identity());
+    }
+
+    #[test]
+    fn test_user_token() {
+        let identity = Identity::User("usak".into());
+        let token = Token::new(24, b"my big secret", &identity);
+        let serialized_token: String = token.into();
+        let mut new_token: Token = serialized_token.parse().unwrap();
+        assert!(new_token.is_valid(b"my big secret"));
+        assert_eq!(identity, new_token.identity());
+        new_token.user = b"kulisak".to_vec();
+        assert!(!new_token.is_valid(b"my big secret"));
+    }
+
+    #[test]
+    fn test_password_hash() {
+        let hash = hash_password("usak");
+        assert!(is_valid_password_hash(&hash));
+        assert!(!is_valid_password_hash("usak"));
+        assert!(hash.starts_with("pbkdf2-sha256$100000$"));
+        assert!(!is_legacy_password_hash(&hash));
+        assert!(!is_valid_password_hash(&hash.replacen("100000", "0", 1)));
+        let secrets = Secrets {
+            shared_secret: None,
+            users: vec![("usak".to_string(), user("usak", &hash, false))]
+                .into_iter()
+                .collect(),
+            server_secret: b"123456".to_vec(),
+            token_validity_hours: 24,
+            sessions: Sessions::new(None),
+            failures: LoginFailures::new(10, Duration::from_secs(60)),
+            basic_auth: false,
+            api_keys: ApiKeys::new(vec![]),
+        };
+        assert!(secrets.user_password_ok("usak", "usak"));
+        assert!(!secrets.user_password_ok("usak", "kulisak"));
+        assert!(!secrets.user_password_ok("kulisak", "usak"));
+    }
+
+    #[test]
+    fn test_legacy_password_hash() {
+        let mut salt = [0u8; 32];
+        SystemRandom::new().fill(&mut salt).unwrap();
+        let hash = format!(
+            "{}|{}",
+            BASE64.encode(&salt),
+            BASE64.encode(&salted_hash(b"usak", &salt))
+        );
+        assert!(is_valid_password_hash(&hash));
+        assert!(is_legacy_password_hash(&hash));
+        let hash = PasswordHash::parse(&hash).unwrap();
+        assert!(hash.verify("usak"));
+        assert!(!hash.verify("kulisak"));
+    }
+
+    fn build_request(body: impl Into<Body>) -> RequestWrapper {
+        let b = body.into();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/authenticate")
+            .body(b)
+            .unwrap();
+
+        RequestWrapper::new(req, None, None, false).unwrap()
+    }
+
+    fn user(name: &str, password_hash: &str, admin: bool) -> UserConfig {
+        UserConfig {
+            name: name.into(),
+            password_hash: password_hash.into(),
+            admin,
+        }
+    }
+
+    fn build_authenticated_request(token: &str) -> RequestWrapper {
+        build_authenticated_request_to(token, Method::GET, "/neco")
+    }
+
+    fn build_authenticated_request_to(token: &str, method: Method, uri: &str) -> RequestWrapper {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from("Hey"))
+            .unwrap();
+
+        RequestWrapper::new(req, None, None, false).unwrap()
+    }
+
+    fn shared_secret(sec: &str) -> String {
+        let mut salt = [0u8; 32];
+        let rng = SystemRandom::new();
+        rng.fill(&mut salt).expect("cannot generate random number");
+        let mut res = BASE64.encode(&salt);
+        res.push('|');
+        let mut hash: Vec<u8> = sec.into();
+        hash.extend(&salt);
+        let hash = digest(&SHA256, &hash);
+        res.push_str(&BASE64.encode(hash.as_ref()));
+        res
+    }
+
+    fn shared_secret_form(sec: &str) -> String {
+        let ss = shared_secret(sec);
+        let encoded_ss: Cow<str> =
+            percent_encoding::percent_encode(ss.as_bytes(), percent_encoding::NON_ALPHANUMERIC)
+                .into();
+        "secret=".to_string() + encoded_ss.as_ref()
+    }
+
+    #[tokio::test]
+    async fn test_authenticator_login() {
+        env_logger::try_init().ok();
+        let invalid_secret = "secret=aaaaa";
+        let shared = "kulisak";
+        init_default_config();
+
+        let ss = shared_secret_form(shared);
+        let aut = SharedSecretAuthenticator::new(
+            Some(shared.into()),
+            vec![],
+            (&b"123456"[..]).into(),
+            24,
+            None,
+            &LoginLockoutConfig::default(),
+            false,
+            vec![],
+        );
+        let req = build_request(ss);
+        let res = aut
+            .authenticate(req)
+            .await
+            .expect("authentication procedure internal error");
+
+        if let AuthResult::LoggedIn(res) = res {
+            assert_eq!(res.status(), StatusCode::OK);
+            let token = res
+                .into_body()
+                .filter_map(|x| future::ready(x.ok()))
+                .map(|x| x.to_vec())
+                .concat()
+                .await;
+            let token = String::from_utf8(token).expect("token is string");
+            assert!(token.len() > 64);
+            let req = build_authenticated_request(&token);
+
+            let res = aut
+                .authenticate(req)
+                .await
+                .expect("authentication procedure internal error");
+
+            if let AuthResult::Authenticated {
+                request,
+                credentials,
+            } = res
+            {
+                assert_eq!(Identity::Shared, credentials);
+                info!("token {:?} is OK", request.headers().get("Authorization"))
+            } else {
+                panic!("Token authentication failed")
+            }
+        } else {
+            panic!("Authentication should succeed")
+        }
+
+        let wrap = build_request(invalid_secret);
+
+        let res = aut
+            .authenticate(wrap)
+            .await
+            .expect("authentication procedure internal error");
+
+        if let AuthResult::Rejected(res) = res {
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED)
+        } else {
+            panic!("Authentication should fail");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authenticator_user_login() {
+        env_logger::try_init().ok();
+        init_default_config();
+        let aut = SharedSecretAuthenticator::new(
+            None,
+            vec![user("usak", &hash_password("kulisak"), false)],
+            (&b"123456"[..]).into(),
+            24,
+            None,
+            &LoginLockoutConfig::default(),
+            false,
+            vec![],
+        );
+
+        let req = build_request("user=usak&password=kulisak");
+        let res = aut
+            .authenticate(req)
+            .await
+            .expect("authentication procedure internal error");
+        let token = if let AuthResult::LoggedIn(res) = res {
+            assert_eq!(res.status(), StatusCode::OK);
+            let token = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            String::from_utf8(token.to_vec()).expect("token is string")
+        } else {
+            panic!("Authentication should succeed")
+        };
+
+        let res = aut
+            .authenticate(build_authenticated_request(&token))
+            .await
+            .expect("authentication procedure internal error");
+        if let AuthResult::Authenticated { credentials, .. } = res {
+            assert_eq!(Identity::User("usak".into()), credentials)
+        } else {
+            panic!("Token authentication failed")
+        }
+
+        let res = aut
+            .authenticate(build_request("user=usak&password=usak"))
+            .await
+            .expect("authentication procedure internal error");
+        assert!(matches!(res, AuthResult::Rejected(_)));
+
+        // shared secret is not configured
+        let res = aut
+            .authenticate(build_request(shared_secret_form("kulisak")))
+            .await
+            .expect("authentication procedure internal error");
+        assert!(matches!(res, AuthResult::Rejected(_)));
+    }
+
+    fn build_basic_request(user: &str, password: &str) -> RequestWrapper {
+        let req = Request::builder()
+            .uri("/audio/0/usak.mp3")
+            .header(
+                "Authorization",
+                format!(
+                    "Basic {}",
+                    BASE64.encode(format!("{}:{}", user, password).as_bytes())
+                ),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        RequestWrapper::new(req, None, Some("127.0.0.1".parse().unwrap()), false).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        env_logger::try_init().ok();
+        init_default_config();
+        let aut = SharedSecretAuthenticator {
+            secrets: Arc::new(Secrets {
+                shared_secret: Some("kulisak".into()),
+                users: vec![(
+                    "usak".to_string(),
+                    user("usak", &hash_password("usak"), false),
+                )]
+                .into_iter()
+                .collect(),
+                server_secret: b"123456".to_vec(),
+                token_validity_hours: 24,
+                sessions: Sessions::new(None),
+                failures: LoginFailures::new(10, Duration::from_secs(60)),
+                basic_auth: true,
+                api_keys: ApiKeys::new(vec![]),
+            }),
+        };
+
+        let res = aut
+            .authenticate(build_basic_request("usak", "usak"))
+            .await
+            .unwrap();
+        if let AuthResult::Authenticated { credentials, .. } = res {
+            assert_eq!(Identity::User("usak".into()), credentials)
+        } else {
+            panic!("User should be authenticated")
+        }
+
+        let res = aut
+            .authenticate(build_basic_request("", "kulisak"))
+            .await
+            .unwrap();
+        if let AuthResult::Authenticated { credentials, .. } = res {
+            assert_eq!(Identity::Shared, credentials)
+        } else {
+            panic!("Shared secret should be accepted")
+        }
+
+        let res = aut
+            .authenticate(build_basic_request("usak", "kulisak"))
+            .await
+            .unwrap();
+        if let AuthResult::Rejected(res) = res {
+            assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+            assert!(res.headers().contains_key(WWW_AUTHENTICATE));
+        } else {
+            panic!("Authentication should fail")
+        }
+
+        // without credentials media players get challenge
+        let req = Request::builder()
+            .uri("/audio/0/usak.mp3")
+            .body(Body::empty())
+            .unwrap();
+        let res = aut
+            .authenticate(RequestWrapper::new(req, None, None, false).unwrap())
+            .await
+            .unwrap();
+        if let AuthResult::Rejected(res) = res {
+            assert!(res.headers().contains_key(WWW_AUTHENTICATE));
+        } else {
+            panic!("Access should be denied")
+        }
+    }
+
+    async fn login(aut: &SharedSecretAuthenticator, form: &str) -> String {
+        let res = aut
+            .authenticate(build_request(form.to_string()))
+            .await
+            .expect("authentication procedure internal error");
+        if let AuthResult::LoggedIn(res) = res {
+            let token = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            String::from_utf8(token.to_vec()).expect("token is string")
+        } else {
+            panic!("Authentication should succeed")
+        }
+    }
+
+    async fn response_body(res: AuthResult<Identity>) -> String {
+        if let AuthResult::Handled(res) = res {
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        } else {
+            panic!("Request should be handled by authenticator")
+        }
+    }
+
+    #[test]
+    fn test_is_admin() {
+        init_default_config();
+        let aut = SharedSecretAuthenticator::new(
+            Some("secret".into()),
+            vec![
+                user("usak", &hash_password("usak"), false),
+                user("admin", &hash_password("admin"), true),
+            ],
+            (&b"123456"[..]).into(),
+            24,
+            None,
+            &LoginLockoutConfig::default(),
+            false,
+            vec![],
+        );
+        assert!(aut.is_admin(&Identity::User("admin".into())));
+        assert!(!aut.is_admin(&Identity::User("usak".into())));
+        assert!(!aut.is_admin(&Identity::User("nobody".into())));
+        assert!(!aut.is_admin(&Identity::Shared));
+    }
+
+    #[tokio::test]
+    async fn test_logout_and_sessions() {
+        env_logger::try_init().ok();
+        init_default_config();
+        let aut = SharedSecretAuthenticator::new(
+            None,
+            vec![
+                user("usak", &hash_password("usak"), false),
+                user("admin", &hash_password("admin"), true),
+            ],
+            (&b"123456"[..]).into(),
+            24,
+            None,
+            &LoginLockoutConfig::default(),
+            false,
+            vec![],
+        );
+
+        let token1 = login(&aut, "user=usak&password=usak").await;
+        let token2 = login(&aut, "user=usak&password=usak").await;
+        let admin_token = login(&aut, "user=admin&password=admin").await;
+
+        let sessions = aut
+            .authenticate(build_authenticated_request_to(
+                &token1,
+                Method::GET,
+                "/sessions",
+            ))
+            .await
+            .unwrap();
+        let sessions: Vec<serde_json::Value> =
+            serde_json::from_str(&response_body(sessions).await).unwrap();
+        assert_eq!(2, sessions.len());
+
+        let sessions = aut
+            .authenticate(build_authenticated_request_to(
+                &admin_token,
+                Method::GET,
+                "/sessions",
+            ))
+            .await
+            .unwrap();
+        let sessions: Vec<serde_json::Value> =
+            serde_json::from_str(&response_body(sessions).await).unwrap();
+        assert_eq!(3, sessions.len());
+
+        let res = aut
+            .authenticate(build_authenticated_request_to(
+                &token1,
+                Method::POST,
+                "/logout",
+            ))
+            .await
+            .unwrap();
+        response_body(res).await;
+
+        let res = aut
+            .authenticate(build_authenticated_request(&token1))
+            .await
+            .unwrap();
+        assert!(matches!(res, AuthResult::Rejected(_)));
+        let res = aut
+            .authenticate(build_authenticated_request(&token2))
+            .await
+            .unwrap();
+        assert!(matches!(res, AuthResult::Authenticated { .. }));
+    }
+}
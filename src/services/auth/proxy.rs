@@ -0,0 +1,135 @@
+use super::{AuthFuture, AuthResult, Authenticator, Identity};
+use crate::services::{resp, RequestWrapper};
+use futures::future;
+use hyper::header::HeaderName;
+use proxy_headers::IpNetwork;
+
+/// Trusts user name in header set by authenticating reverse proxy (SSO),
+/// but only if request is coming directly from one of trusted proxies.
+/// Other requests are passed to fallback authenticator (if any).
+pub struct ProxyHeaderAuthenticator {
+    header: HeaderName,
+    trusted_proxies: Vec<IpNetwork>,
+    fallback: Option<Box<dyn Authenticator<Credentials = Identity>>>,
+}
+
+impl ProxyHeaderAuthenticator {
+    pub fn new(
+        header: HeaderName,
+        trusted_proxies: Vec<IpNetwork>,
+        fallback: Option<Box<dyn Authenticator<Credentials = Identity>>>,
+    ) -> Self {
+        ProxyHeaderAuthenticator {
+            header,
+            trusted_proxies,
+            fallback,
+        }
+    }
+
+    fn is_trusted(&self, req: &RequestWrapper) -> bool {
+        req.peer_addr()
+            .map(|addr| self.trusted_proxies.iter().any(|net| net.contains(&addr)))
+            .unwrap_or(false)
+    }
+}
+
+impl Authenticator for ProxyHeaderAuthenticator {
+    type Credentials = Identity;
+    fn authenticate(&self, req: RequestWrapper) -> AuthFuture<Identity> {
+        let user = req
+            .headers()
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|u| !u.is_empty())
+            .map(ToOwned::to_owned);
+
+        match user {
+            Some(user) if self.is_trusted(&req) => {
+                debug!("User {} authenticated by proxy {:?}", user, req.peer_addr());
+                return Box::pin(future::ok(AuthResult::Authenticated {
+                    request: req,
+                    credentials: Identity::User(user),
+                }));
+            }
+            Some(_) => {
+                warn!(
+                    "Ignoring header {} from untrusted peer {:?}",
+                    self.header,
+                    req.peer_addr()
+                );
+            }
+            None => (),
+        }
+
+        match self.fallback {
+            Some(ref auth) => auth.authenticate(req),
+            None => {
+                error!(
+                    "Invalid access: no user from trusted proxy on path {}, client: {:?}",
+                    req.path(),
+                    req.remote_addr()
+                );
+                Box::pin(future::ok(AuthResult::Rejected(resp::deny())))
+            }
+        }
+    }
+
+    /// User from proxy is admin, if local user of same name is admin
+    fn is_admin(&self, credentials: &Identity) -> bool {
+        self.fallback
+            .as_ref()
+            .map(|auth| auth.is_admin(credentials))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::init::init_default_config;
+    use hyper::{Body, Request};
+
+    fn request(user: Option<&str>, peer: &str) -> RequestWrapper {
+        let mut req = Request::builder().uri("/collections");
+        if let Some(user) = user {
+            req = req.header("X-Remote-User", user);
+        }
+        RequestWrapper::new(
+            req.body(Body::empty()).unwrap(),
+            None,
+            Some(peer.parse().unwrap()),
+            false,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_proxy_authenticator() {
+        init_default_config();
+        let auth = ProxyHeaderAuthenticator::new(
+            HeaderName::from_static("x-remote-user"),
+            vec!["10.0.0.0/8".parse().unwrap()],
+            None,
+        );
+
+        let res = auth
+            .authenticate(request(Some("usak"), "10.1.2.3"))
+            .await
+            .unwrap();
+        if let AuthResult::Authenticated { credentials, .. } = res {
+            assert_eq!(Identity::User("usak".into()), credentials)
+        } else {
+            panic!("User should be authenticated")
+        }
+
+        let res = auth
+            .authenticate(request(Some("usak"), "192.168.1.1"))
+            .await
+            .unwrap();
+        assert!(matches!(res, AuthResult::Rejected(_)));
+
+        let res = auth.authenticate(request(None, "10.1.2.3")).await.unwrap();
+        assert!(matches!(res, AuthResult::Rejected(_)));
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use super::{now, Identity};
+use crate::util::write_atomically;
+
+/// Max. number of recorded sessions per user, oldest sessions above this limit are revoked
+const MAX_SESSIONS_PER_USER: usize = 50;
+
+/// Record of issued token
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Session {
+    pub id: String,
+    pub user: Option<String>,
+    pub created: u64,
+    pub valid_until: u64,
+    pub client: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Session {
+    pub fn identity(&self) -> Identity {
+        match self.user {
+            Some(ref name) => Identity::User(name.clone()),
+            None => Identity::Shared,
+        }
+    }
+}
+
+// shared secret is empty user, same as in token
+fn identity_key(identity: &Identity) -> &str {
+    match identity {
+        Identity::User(name) => name,
+        Identity::Shared => "",
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SessionsInner {
+    sessions: HashMap<String, Session>,
+    // revoked token ids with their validity, so they can be removed when token expires
+    revoked: HashMap<String, u64>,
+    // tokens of user (not recorded in sessions) valid until this time or earlier are revoked,
+    // used for sessions over per user limit, so they do not need to be recorded one by one
+    #[serde(default)]
+    revoked_until: HashMap<String, u64>,
+    // set when sessions were lost (file was corrupted), then only tokens recorded
+    // in sessions are accepted
+    #[serde(default)]
+    reject_unknown: bool,
+}
+
+impl SessionsInner {
+    fn prune(&mut self, now: u64) {
+        self.sessions.retain(|_, s| s.valid_until > now);
+        self.revoked.retain(|_, valid_until| *valid_until > now);
+        self.revoked_until
+            .retain(|_, valid_until| *valid_until > now);
+    }
+
+    fn limit_sessions(&mut self, identity: &Identity) {
+        let mut own: Vec<_> = self
+            .sessions
+            .values()
+            .filter(|s| s.identity() == *identity)
+            .map(|s| (s.created, s.valid_until, s.id.clone()))
+            .collect();
+        if own.len() <= MAX_SESSIONS_PER_USER {
+            return;
+        }
+        own.sort_unstable();
+        let over = own.len() - MAX_SESSIONS_PER_USER;
+        for (_, valid_until, id) in own.into_iter().take(over) {
+            self.sessions.remove(&id);
+            let revoked_until = self
+                .revoked_until
+                .entry(identity_key(identity).to_string())
+                .or_insert(0);
+            *revoked_until = valid_until.max(*revoked_until);
+        }
+    }
+}
+
+/// Issued and revoked tokens, persisted in file (if provided)
+pub struct Sessions {
+    inner: RwLock<SessionsInner>,
+    file: Option<PathBuf>,
+    // version of data, so older data does not overwrite newer ones in file
+    version: AtomicU64,
+    written_version: Arc<Mutex<u64>>,
+}
+
+impl Sessions {
+    /// If sessions file cannot be read, all previously issued tokens are considered revoked
+    pub fn new(file: Option<PathBuf>) -> Self {
+        let inner = match file.as_ref().map(fs::File::open) {
+            Some(Ok(f)) => match serde_json::from_reader::<_, SessionsInner>(f) {
+                Ok(mut inner) => {
+                    inner.prune(now());
+                    inner
+                }
+                Err(e) => {
+                    error!(
+                        "Cannot read sessions file, all issued tokens are revoked: {}",
+                        e
+                    );
+                    SessionsInner {
+                        reject_unknown: true,
+                        ..Default::default()
+                    }
+                }
+            },
+            Some(Err(e)) => {
+                match e.kind() {
+                    io::ErrorKind::NotFound => {
+                        debug!("Sessions file is not present, new will be created")
+                    }
+                    _ => error!("Cannot open sessions file: {}", e),
+                }
+                SessionsInner::default()
+            }
+            None => SessionsInner::default(),
+        };
+        if inner.reject_unknown {
+            // persist immediately, so revocation survives next restart
+            if let Some(ref fname) = file {
+                let res = serde_json::to_vec(&inner)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                    .and_then(|data| write_atomically(fname, &data));
+                if let Err(e) = res {
+                    error!("Cannot save sessions file {:?}: {}", fname, e)
+                }
+            }
+        }
+        Sessions {
+            inner: RwLock::new(inner),
+            file,
+            version: AtomicU64::new(0),
+            written_version: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    // must be called with write lock held, so versions are in order of changes
+    fn save(&self, inner: &SessionsInner) {
+        if let Some(ref fname) = self.file {
+            let data = match serde_json::to_vec(inner) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Cannot serialize sessions: {}", e);
+                    return;
+                }
+            };
+            let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+            let written_version = self.written_version.clone();
+            let fname = fname.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut written_version = written_version.lock().expect("Poisoned lock");
+                if *written_version > version {
+                    return;
+                }
+                if let Err(e) = write_atomically(&fname, &data) {
+                    error!("Cannot save sessions file {:?}: {}", fname, e)
+                }
+                *written_version = version;
+            });
+        }
+    }
+
+    pub fn add(&self, session: Session) {
+        let mut inner = self.inner.write().expect("Poisoned lock");
+        inner.prune(now());
+        let identity = session.identity();
+        inner.sessions.insert(session.id.clone(), session);
+        inner.limit_sessions(&identity);
+        self.save(&inner);
+    }
+    /// Revokes token with given id, valid_until is needed for tokens, which are not recorded in sessions
+    pub fn revoke(&self, id: &str, valid_until: Option<u64>) -> bool {
+        let mut inner = self.inner.write().expect("Poisoned lock");
+        let valid_until = match inner.sessions.remove(id) {
+            Some(session) => session.valid_until,
+            None => match valid_until {
+                Some(v) => v,
+                None => return false,
+            },
+        };
+        inner.revoked.insert(id.to_string(), valid_until);
+        inner.prune(now());
+        self.save(&inner);
+        true
+    }
+
+    pub fn is_revoked(&self, id: &str, identity: &Identity, valid_until: u64) -> bool {
+        let inner = self.inner.read().expect("Poisoned lock");
+        if inner.revoked.contains_key(id) {
+            return true;
+        }
+        if inner.sessions.contains_key(id) {
+            return false;
+        }
+        inner.reject_unknown
+            || inner
+                .revoked_until
+                .get(identity_key(identity))
+                .map(|until| valid_until <= *until)
+                .unwrap_or(false)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Session> {
+        self.inner
+            .read()
+            .expect("Poisoned lock")
+            .sessions
+            .get(id)
+            .cloned()
+    }
+
+    /// Lists active sessions, all or just for given identity
+    pub fn list(&self, identity: Option<&Identity>) -> Vec<Session> {
+        let now = now();
+        let mut sessions: Vec<_> = self
+            .inner
+            .read()
+            .expect("Poisoned lock")
+            .sessions
+            .values()
+            .filter(|s| s.valid_until > now)
+            .filter(|s| identity.map(|i| *i == s.identity()).unwrap_or(true))
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|s| s.created);
+        sessions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str, user: Option<&str>, valid_until: u64) -> Session {
+        Session {
+            id: id.into(),
+            user: user.map(ToString::to_string),
+            created: now(),
+            valid_until,
+            client: None,
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let s = Sessions::new(None);
+        let valid = now() + 3600;
+        s.add(session("a", None, valid));
+        s.add(session("b", Some("usak"), valid));
+        s.add(session("c", Some("usak"), now() - 1));
+        assert_eq!(2, s.list(None).len());
+        assert_eq!(
+            vec!["b".to_string()],
+            s.list(Some(&Identity::User("usak".into())))
+                .into_iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>()
+        );
+
+        assert!(s.revoke("b", None));
+        let shared = Identity::Shared;
+        let usak = Identity::User("usak".into());
+        assert!(s.is_revoked("b", &usak, valid));
+        assert_eq!(1, s.list(None).len());
+        assert!(!s.revoke("x", None));
+        assert!(s.revoke("x", Some(valid)));
+        assert!(s.is_revoked("x", &shared, valid));
+        assert!(!s.is_revoked("a", &shared, valid));
+        // not recorded token
+        assert!(!s.is_revoked("y", &shared, valid));
+    }
+
+    #[test]
+    fn test_sessions_limit() {
+        let mut inner = SessionsInner::default();
+        let usak = Identity::User("usak".into());
+        let valid = now() + 3600;
+        for i in 0..MAX_SESSIONS_PER_USER + 2 {
+            let mut s = session(&i.to_string(), Some("usak"), valid + i as u64);
+            s.created += i as u64;
+            inner.sessions.insert(s.id.clone(), s);
+        }
+        inner.limit_sessions(&usak);
+        assert_eq!(MAX_SESSIONS_PER_USER, inner.sessions.len());
+        assert!(!inner.sessions.contains_key("1"));
+        assert!(inner.sessions.contains_key("2"));
+        assert_eq!(Some(&(valid + 1)), inner.revoked_until.get("usak"));
+
+        let s = Sessions {
+            inner: RwLock::new(inner),
+            file: None,
+            version: AtomicU64::new(0),
+            written_version: Arc::new(Mutex::new(0)),
+        };
+        assert!(s.is_revoked("0", &usak, valid));
+        assert!(s.is_revoked("1", &usak, valid + 1));
+        assert!(!s.is_revoked("2", &usak, valid + 2));
+        assert!(!s.is_revoked("x", &Identity::Shared, valid));
+    }
+
+    #[test]
+    fn test_corrupted_sessions_file() {
+        let fname = std::env::temp_dir().join("audioserve_sessions_corrupted.json");
+        fs::write(&fname, b"{\"sessions\": {").unwrap();
+        let s = Sessions::new(Some(fname.clone()));
+        // all tokens issued before are unknown, so they must be rejected
+        assert!(s.is_revoked("a", &Identity::Shared, now() + 3600));
+        let s = Sessions::new(Some(fname.clone()));
+        assert!(s.is_revoked("a", &Identity::Shared, now() + 3600));
+        fs::remove_file(&fname).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use super::{
+    identity_from_bytes, identity_to_bytes, now, sign, signature_ok, Identity, TokenError,
+};
+use crate::config::get_config;
+use crate::services::extract_collection_number;
+use data_encoding::BASE64URL_NOPAD;
+
+// distinguishes share link signature from token signature
+const SHARE_DOMAIN: &[u8] = b"audioserve-share";
+const FLAG_DOWNLOAD: u8 = 1;
+// signature, validity, flags, collection, path length
+const HEADER_SIZE: usize = 32 + 8 + 1 + 2 + 2;
+
+/// Signed link, which enables access to one folder or file in one collection until it expires,
+/// link is used in `share` query parameter
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareLink {
+    pub collection: u16,
+    /// path of folder or file relative to collection directory
+    pub path: String,
+    pub valid_until: u64,
+    pub download: bool,
+    /// who created the link, access is checked as for this identity
+    pub owner: Identity,
+}
+
+impl ShareLink {
+    fn payload(&self) -> Vec<u8> {
+        let path = self.path.as_bytes();
+        let user = identity_to_bytes(&self.owner);
+        let mut data = Vec::with_capacity(HEADER_SIZE - 32 + path.len() + user.len());
+        data.extend_from_slice(&self.valid_until.to_be_bytes());
+        data.push(if self.download { FLAG_DOWNLOAD } else { 0 });
+        data.extend_from_slice(&self.collection.to_be_bytes());
+        data.extend_from_slice(&(path.len() as u16).to_be_bytes());
+        data.extend_from_slice(path);
+        data.extend_from_slice(&user);
+        data
+    }
+
+    fn signed_data(payload: &[u8]) -> Vec<u8> {
+        [SHARE_DOMAIN, payload].concat()
+    }
+
+    pub fn encode(&self, secret: &[u8]) -> String {
+        let payload = self.payload();
+        let signature = sign(secret, &ShareLink::signed_data(&payload));
+        BASE64URL_NOPAD.encode(&[&signature[..], &payload[..]].concat())
+    }
+
+    /// Decodes link and checks its signature and expiry
+    pub fn decode(s: &str, secret: &[u8]) -> Result<Self, TokenError> {
+        let bytes = BASE64URL_NOPAD.decode(s.as_bytes())?;
+        if bytes.len() < HEADER_SIZE {
+            return Err(TokenError::InvalidSize);
+        }
+        let (signature, payload) = bytes.split_at(32);
+        if !signature_ok(secret, &ShareLink::signed_data(payload), signature) {
+            return Err(TokenError::InvalidSignature);
+        }
+        let mut num = [0u8; 8];
+        num.copy_from_slice(&payload[0..8]);
+        let valid_until = u64::from_be_bytes(num);
+        if valid_until <= now() {
+            return Err(TokenError::Expired);
+        }
+        let download = payload[8] & FLAG_DOWNLOAD != 0;
+        let collection = u16::from_be_bytes([payload[9], payload[10]]);
+        let path_len = u16::from_be_bytes([payload[11], payload[12]]) as usize;
+        let rest = &payload[13..];
+        if rest.len() < path_len {
+            return Err(TokenError::InvalidSize);
+        }
+        let path = String::from_utf8(rest[..path_len].to_vec())?;
+        let owner = identity_from_bytes(&rest[path_len..]);
+
+        Ok(ShareLink {
+            collection,
+            path,
+            valid_until,
+            download,
+            owner,
+        })
+    }
+
+    /// Checks that request path is within shared folder or file
+    pub fn allows(&self, path: &str) -> bool {
+        let (path, collection) = match extract_collection_number(path, get_config()) {
+            Ok(r) => r,
+            Err(_) => return false,
+        };
+        if collection != self.collection as usize {
+            return false;
+        }
+        let mut prefixes = vec!["/audio/", "/folder/", "/cover/"];
+        if self.download {
+            prefixes.push("/download/");
+        }
+        prefixes
+            .into_iter()
+            .filter_map(|prefix| path.strip_prefix(prefix))
+            .any(|subpath| {
+                let subpath = subpath.trim_end_matches('/');
+                subpath == self.path
+                    || (subpath.starts_with(&self.path)
+                        && subpath[self.path.len()..].starts_with('/'))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::init::init_default_config;
+
+    fn link(download: bool) -> ShareLink {
+        ShareLink {
+            collection: 0,
+            path: "usak/kulisak".into(),
+            valid_until: now() + 3600,
+            download,
+            owner: Identity::User("usak".into()),
+        }
+    }
+
+    #[test]
+    fn test_share_link_encoding() {
+        let l = link(true);
+        let encoded = l.encode(b"secret");
+        assert_eq!(l, ShareLink::decode(&encoded, b"secret").unwrap());
+        assert_eq!(
+            TokenError::InvalidSignature,
+            ShareLink::decode(&encoded, b"other secret").unwrap_err()
+        );
+
+        let mut expired = link(false);
+        expired.valid_until = now() - 1;
+        assert_eq!(
+            TokenError::Expired,
+            ShareLink::decode(&expired.encode(b"secret"), b"secret").unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_share_link_allows() {
+        init_default_config();
+        let l = link(false);
+        assert!(l.allows("/folder/usak/kulisak"));
+        assert!(l.allows("/audio/usak/kulisak/chapter1.mp3"));
+        assert!(l.allows("/cover/usak/kulisak/cover.jpg"));
+        assert!(!l.allows("/1/cover/usak/kulisak/cover.jpg"));
+        assert!(!l.allows("/download/usak/kulisak"));
+        assert!(!l.allows("/folder/usak"));
+        assert!(!l.allows("/audio/usak/kulisak2/chapter1.mp3"));
+        assert!(!l.allows("/desc/usak/kulisak/info.txt"));
+        assert!(!l.allows("/collections"));
+        assert!(link(true).allows("/download/usak/kulisak"));
+    }
+}
@@ -1,12 +1,19 @@
-use self::auth::{AuthResult, Authenticator};
+use self::auth::{AuthResult, Authenticator, Identity};
 use self::search::Search;
 use self::subs::{
-    collections_list, get_folder, recent, search, send_file, send_file_simple, transcodings_list,
-    ResponseFuture,
+    collections_list, get_folder, recent, search, send_file, send_file_negotiated,
+    send_file_simple, send_hls_playlist, send_hls_segment, transcodings_list, ResponseFuture,
+};
+use self::transcode::{
+    filters::AudioFilters,
+    negotiate::{ClientCaps, AUTO_PARAM},
+    queue::TranscodingQueue,
+    QualityLevel,
 };
-use self::transcode::QualityLevel;
 use self::types::FoldersOrdering;
-use crate::config::get_config;
+#[cfg(feature = "transcoding-cache")]
+use crate::config::PurgeFilter;
+use crate::config::{get_config, Config};
 use crate::{error, util::header2header};
 use bytes::{Bytes, BytesMut};
 use futures::prelude::*;
@@ -27,7 +34,7 @@ use std::{
     net::SocketAddr,
     path::{Path, PathBuf},
     pin::Pin,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::Arc,
     task::Poll,
 };
 use url::form_urlencoded;
@@ -46,7 +53,7 @@ mod types;
 const APP_STATIC_FILES_CACHE_AGE: u32 = 30 * 24 * 3600;
 const FOLDER_INFO_FILES_CACHE_AGE: u32 = 24 * 3600;
 
-type Counter = Arc<AtomicUsize>;
+type Counter = Arc<TranscodingQueue>;
 
 #[derive(Debug)]
 pub enum RemoteIpAddr {
@@ -160,6 +167,12 @@ impl RequestWrapper {
         self.remote_addr.map(RemoteIpAddr::Direct)
     }
 
+    /// Address of directly connected peer (proxy if we are behind one)
+    #[cfg(feature = "behind-proxy")]
+    pub fn peer_addr(&self) -> Option<IpAddr> {
+        self.remote_addr
+    }
+
     pub fn headers(&self) -> &hyper::HeaderMap {
         self.request.headers()
     }
@@ -205,6 +218,8 @@ impl RequestWrapper {
 pub struct TranscodingDetails {
     pub transcodings: Counter,
     pub max_transcodings: usize,
+    /// client of current request, used for fair ordering in transcoding queue
+    pub client: Option<IpAddr>,
 }
 
 pub struct ServiceFactory<T> {
@@ -286,7 +301,7 @@ fn add_cors_headers(
 }
 
 #[allow(clippy::type_complexity)]
-impl<C: 'static> Service<Request<Body>> for FileSendService<C> {
+impl<C: Into<Identity> + 'static> Service<Request<Body>> for FileSendService<C> {
     type Response = Response<Body>;
     type Error = error::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
@@ -339,44 +354,71 @@ impl<C: 'static> Service<Request<Body>> for FileSendService<C> {
 
         let resp = match self.authenticator {
             Some(ref auth) => {
+                let authenticator = auth.clone();
                 Box::pin(auth.authenticate(req).and_then(move |result| match result {
-                    AuthResult::Authenticated { request, .. } => {
-                        FileSendService::<C>::process_checked(request, searcher, transcoding)
-                    }
-                    AuthResult::LoggedIn(resp) | AuthResult::Rejected(resp) => {
-                        Box::pin(future::ok(resp))
+                    AuthResult::Authenticated {
+                        request,
+                        credentials,
+                    } => {
+                        let admin = authenticator.is_admin(&credentials);
+                        FileSendService::<C>::process_checked(
+                            request,
+                            Some(credentials.into()),
+                            admin,
+                            searcher,
+                            transcoding,
+                        )
                     }
+                    AuthResult::LoggedIn(resp)
+                    | AuthResult::Rejected(resp)
+                    | AuthResult::Handled(resp) => Box::pin(future::ok(resp)),
                 }))
             }
-            None => FileSendService::<C>::process_checked(req, searcher, transcoding),
+            // without authentication everybody is admin
+            None => FileSendService::<C>::process_checked(req, None, true, searcher, transcoding),
         };
         Box::pin(resp.map_ok(move |r| add_cors_headers(r, origin, cors)))
     }
 }
 
 impl<C> FileSendService<C> {
+    #[cfg_attr(not(feature = "transcoding-cache"), allow(unused_variables))]
     fn process_checked(
         req: RequestWrapper,
+        identity: Option<Identity>,
+        admin: bool,
         searcher: Search<String>,
         transcoding: TranscodingDetails,
     ) -> ResponseFuture {
         let params = req.params();
+        if let Some(ref identity) = identity {
+            debug!("Request {} authenticated as {}", req.path(), identity);
+        }
+        // same config for whole request, as it can be replaced by reload meanwhile
+        let cfg = get_config();
+
+        #[cfg(feature = "transcoding-cache")]
+        {
+            if req.path() == "/cache" || req.path().starts_with("/cache/") {
+                return FileSendService::<C>::cache_admin(&req, identity.as_ref(), admin, params);
+            }
+        }
 
         match *req.method() {
             Method::GET => {
                 let path = req.path();
 
                 if path.starts_with("/collections") {
-                    collections_list()
+                    collections_list(identity)
                 } else if path.starts_with("/transcodings") {
-                    transcodings_list()
+                    transcodings_list(&transcoding)
                 } else if cfg!(feature = "shared-positions") && path.starts_with("/position") {
                     #[cfg(not(feature = "shared-positions"))]
                     unimplemented!();
                     #[cfg(feature = "shared-positions")]
-                    self::position::position_service(req)
+                    self::position::position_service(req, identity)
                 } else {
-                    let (path, colllection_index) = match extract_collection_number(path) {
+                    let (path, colllection_index) = match extract_collection_number(path, cfg) {
                         Ok(r) => r,
                         Err(_) => {
                             error!("Invalid collection number");
@@ -384,7 +426,19 @@ impl<C> FileSendService<C> {
                         }
                     };
 
-                    let base_dir = &get_config().base_dirs[colllection_index];
+                    if !cfg.can_access_collection(colllection_index, identity.as_ref()) {
+                        error!(
+                            "Access to collection {} is forbidden for {}",
+                            colllection_index,
+                            identity
+                                .as_ref()
+                                .map(ToString::to_string)
+                                .unwrap_or_default()
+                        );
+                        return resp::fut(resp::not_found);
+                    }
+
+                    let base_dir = &cfg.base_dirs[colllection_index];
                     let ord = params
                         .as_ref()
                         .and_then(|p| p.get("ord").map(|l| FoldersOrdering::from_letter(l)))
@@ -399,8 +453,7 @@ impl<C> FileSendService<C> {
                         )
                     } else if path.starts_with("/folder/") {
                         get_folder(base_dir, get_subpath(&path, "/folder/"), ord)
-                    } else if !get_config().disable_folder_download && path.starts_with("/download")
-                    {
+                    } else if !cfg.disable_folder_download && path.starts_with("/download") {
                         #[cfg(feature = "folder-download")]
                         {
                             let format = params
@@ -451,13 +504,81 @@ impl<C> FileSendService<C> {
         }
     }
 
+    #[cfg(feature = "transcoding-cache")]
+    fn cache_admin(
+        req: &RequestWrapper,
+        identity: Option<&Identity>,
+        admin: bool,
+        params: Option<HashMap<Cow<str>, Cow<str>>>,
+    ) -> ResponseFuture {
+        if !admin {
+            error!(
+                "Transcoding cache administration is forbidden for {}",
+                identity.map(ToString::to_string).unwrap_or_default()
+            );
+            return resp::fut(resp::forbidden);
+        }
+        if get_config().transcoding.cache.disabled {
+            error!("Transcoding cache is disabled");
+            return resp::fut(resp::not_found);
+        }
+
+        match (req.method(), req.path()) {
+            (&Method::GET, "/cache") => subs::cache_info(),
+            (&Method::POST, "/cache/purge") => {
+                let mut params = params.unwrap_or_default();
+                let all = params
+                    .remove("all")
+                    .map(|v| v == "1" || v == "true")
+                    .unwrap_or(false);
+                if all {
+                    return subs::cache_purge(PurgeFilter::default());
+                }
+                let collection = match params.remove("collection").map(|c| c.parse()) {
+                    None => 0,
+                    Some(Ok(c)) => c,
+                    Some(Err(_)) => {
+                        error!("Invalid collection number");
+                        return resp::fut(resp::bad_request);
+                    }
+                };
+                let filter = PurgeFilter {
+                    quality: params.remove("quality").map(Cow::into_owned),
+                    path: params
+                        .remove("path")
+                        .map(|p| (collection, PathBuf::from(p.into_owned()))),
+                };
+                if filter.is_empty() {
+                    error!("Purge of cache requires quality or path or all parameter");
+                    return resp::fut(resp::bad_request);
+                }
+                match filter.check(get_config()) {
+                    Ok(()) => subs::cache_purge(filter),
+                    Err(e) => {
+                        error!("Invalid cache purge request: {}", e);
+                        resp::fut(resp::bad_request)
+                    }
+                }
+            }
+            (&Method::POST, "/cache/save") => subs::cache_save(),
+            (_, "/cache") | (_, "/cache/purge") | (_, "/cache/save") => {
+                resp::fut(resp::method_not_supported)
+            }
+            _ => {
+                error!("Invalid path requested {}", req.path());
+                resp::fut(resp::not_found)
+            }
+        }
+    }
+
     fn serve_audio(
         req: &RequestWrapper,
         base_dir: &'static Path,
         path: &str,
-        transcoding: TranscodingDetails,
+        mut transcoding: TranscodingDetails,
         mut params: Option<HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>>,
     ) -> ResponseFuture {
+        transcoding.client = req.remote_addr().map(|a| *a.as_ref());
         debug!(
             "Received request with following headers {:?}",
             req.headers()
@@ -484,9 +605,86 @@ impl<C> FileSendService<C> {
             .as_mut()
             .and_then(|p| p.remove("seek"))
             .and_then(|s| s.parse().ok());
-        let transcoding_quality: Option<QualityLevel> = params
-            .and_then(|mut p| p.remove("trans"))
-            .and_then(|t| QualityLevel::from_letter(&t));
+        let trans = params.as_mut().and_then(|p| p.remove("trans"));
+        let mut filters = AudioFilters::from_params(&mut params);
+        let hls = params
+            .as_ref()
+            .map(|p| p.contains_key("hls"))
+            .unwrap_or(false);
+        let segment: Option<usize> = params
+            .as_mut()
+            .and_then(|p| p.remove("seg"))
+            .and_then(|s| s.parse().ok());
+        if hls || segment.is_some() {
+            // HLS is always transcoded, as segments must be in MPEG-TS container
+            if filters.skip_silence {
+                warn!("Silence cannot be skipped in HLS, as segment durations would not match");
+                filters.skip_silence = false;
+            }
+            let quality = trans
+                .as_ref()
+                .and_then(|t| QualityLevel::from_param(t))
+                .unwrap_or(QualityLevel::Hls);
+            if !get_config().transcoding.get(quality).hls_capable() {
+                error!(
+                    "Transcoding {} cannot be used for HLS, only AAC or MP3 can",
+                    quality.to_letter()
+                );
+                return resp::fut(resp::not_acceptable);
+            }
+            let file_path = get_subpath(&path, "/audio/");
+            return match segment {
+                Some(index) => {
+                    send_hls_segment(base_dir, file_path, index, transcoding, quality, filters)
+                }
+                None => {
+                    let mut query = form_urlencoded::Serializer::new(String::new());
+                    if quality != QualityLevel::Hls {
+                        query.append_pair("trans", quality.to_letter());
+                    }
+                    if let Some(normalize) = filters.normalize {
+                        query.append_pair("norm", if normalize { "1" } else { "0" });
+                    }
+                    if let Some(speed) = filters.speed {
+                        query.append_pair("speed", &speed.to_string());
+                    }
+                    if let Some(share) = params.as_ref().and_then(|p| p.get("share")) {
+                        query.append_pair("share", share);
+                    }
+                    send_hls_playlist(base_dir, file_path, query.finish(), filters.speed())
+                }
+            };
+        }
+        let caps = params.as_mut().and_then(|p| p.remove("caps"));
+        let bandwidth: Option<u32> = params
+            .as_mut()
+            .and_then(|p| p.remove("bw"))
+            .and_then(|b| b.parse().ok());
+        let negotiate = match trans {
+            Some(ref t) => t == AUTO_PARAM,
+            None => caps.is_some() || bandwidth.is_some(),
+        };
+
+        if negotiate {
+            // browsers send generic Accept header for media, so it's used only if client asked for negotiation
+            let accept = req
+                .headers()
+                .get(hyper::header::ACCEPT)
+                .and_then(|h| h.to_str().ok())
+                .filter(|_| trans.as_deref() == Some(AUTO_PARAM));
+            return send_file_negotiated(
+                base_dir,
+                get_subpath(&path, "/audio/"),
+                bytes_range,
+                seek,
+                transcoding,
+                ClientCaps::new(caps.as_deref(), accept, bandwidth),
+                filters,
+            );
+        }
+
+        let transcoding_quality: Option<QualityLevel> =
+            trans.and_then(|t| QualityLevel::from_param(&t));
 
         send_file(
             base_dir,
@@ -495,6 +693,7 @@ impl<C> FileSendService<C> {
             seek,
             transcoding,
             transcoding_quality,
+            filters,
         )
     }
 }
@@ -503,7 +702,7 @@ lazy_static! {
     static ref COLLECTION_NUMBER_RE: Regex = Regex::new(r"^/(\d+)/.+").unwrap();
 }
 
-fn extract_collection_number(path: &str) -> Result<(&str, usize), ()> {
+fn extract_collection_number<'a>(path: &'a str, cfg: &Config) -> Result<(&'a str, usize), ()> {
     let matches = COLLECTION_NUMBER_RE.captures(&path);
     if let Some(matches) = matches {
         let cnum = matches.get(1).unwrap();
@@ -511,7 +710,7 @@ fn extract_collection_number(path: &str) -> Result<(&str, usize), ()> {
         let new_path = &path[cnum.end()..];
         // and cnum is guarateed to contain digits only
         let cnum: usize = cnum.as_str().parse().unwrap();
-        if cnum >= get_config().base_dirs.len() {
+        if !cfg.has_collection(cnum) {
             return Err(());
         }
         Ok((new_path, cnum))
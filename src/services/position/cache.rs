@@ -44,7 +44,7 @@ impl Cache {
         let fname = &get_config().positions_file;
         match  fs::File::open(fname) {
         Ok(f)=> {
-            match serde_json::from_reader::<_, CacheInner>(f) {
+            match serde_json::from_reader::<_, StoredCache>(f).map(CacheInner::from) {
             Ok(mut inner) =>  {
                 inner.shrink(sz);
                 inner.max_size = sz;
@@ -86,12 +86,18 @@ impl Cache {
         }
     }
 
-    pub async fn insert<S: Into<String>>(&self, file_path: S, position: f32) -> Result<()> {
-        self.inner.write().await.insert(file_path, position)
+    pub async fn insert<S: Into<String>>(
+        &self,
+        user: &str,
+        file_path: S,
+        position: f32,
+    ) -> Result<()> {
+        self.inner.write().await.insert(user, file_path, position)
     }
 
     pub async fn insert_if_newer<S: Into<String>>(
         &self,
+        user: &str,
         group_path: S,
         position: f32,
         ts: u64,
@@ -99,18 +105,18 @@ impl Cache {
         self.inner
             .write()
             .await
-            .insert_if_newer(group_path, position, ts)
+            .insert_if_newer(user, group_path, position, ts)
     }
 
-    pub async fn get<K>(&self, folder: &K) -> Option<Position>
+    pub async fn get<K>(&self, user: &str, folder: &K) -> Option<Position>
     where
         K: AsRef<str> + ?Sized,
     {
-        self.inner.read().await.get(folder)
+        self.inner.read().await.get(user, folder)
     }
 
-    pub async fn get_last<G: AsRef<str>>(&self, group: G) -> Option<Position> {
-        self.inner.read().await.get_last(group)
+    pub async fn get_last<G: AsRef<str>>(&self, user: &str, group: G) -> Option<Position> {
+        self.inner.read().await.get_last(user, group)
     }
 
     #[allow(dead_code)]
@@ -124,23 +130,66 @@ impl Cache {
     }
 }
 
+type GroupsTable = HashMap<String, LinkedHashMap<String, PositionRecord>>;
+
+/// Positions are partitioned by user - each user has own groups,
+/// clients not authenticated as particular user share partition with empty name
 #[derive(Serialize, Deserialize)]
 struct CacheInner {
-    table: HashMap<String, LinkedHashMap<String, PositionRecord>>,
+    users: HashMap<String, GroupsTable>,
+    max_size: usize,
+    max_groups: usize,
+}
+
+/// Format of positions file before positions were partitioned per user
+#[derive(Deserialize)]
+struct LegacyCacheInner {
+    table: GroupsTable,
     max_size: usize,
     max_groups: usize,
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredCache {
+    Current(CacheInner),
+    Legacy(LegacyCacheInner),
+}
+
+impl From<StoredCache> for CacheInner {
+    fn from(stored: StoredCache) -> Self {
+        match stored {
+            StoredCache::Current(inner) => inner,
+            StoredCache::Legacy(legacy) => {
+                info!("Migrating positions file to per user format");
+                let mut users = HashMap::new();
+                users.insert(String::new(), legacy.table);
+                CacheInner {
+                    users,
+                    max_size: legacy.max_size,
+                    max_groups: legacy.max_groups,
+                }
+            }
+        }
+    }
+}
+
 impl CacheInner {
     fn new(sz: usize, groups: usize) -> Self {
         CacheInner {
-            table: HashMap::new(),
+            users: HashMap::new(),
             max_size: sz,
             max_groups: groups,
         }
     }
 
-    fn _insert<S, F>(&mut self, group_path: S, position: f32, check_rec: F) -> Result<()>
+    fn _insert<S, F>(
+        &mut self,
+        user: &str,
+        group_path: S,
+        position: f32,
+        check_rec: F,
+    ) -> Result<()>
     where
         S: Into<String>,
         F: FnOnce(&CacheInner, &str, PositionRecord) -> Result<PositionRecord>,
@@ -157,7 +206,13 @@ impl CacheInner {
                 None => ("".to_owned(), file_path.to_owned()),
             };
 
-            if !self.table.contains_key(group) && self.table.len() >= self.max_groups {
+            let groups_len = self.users.get(user).map(HashMap::len).unwrap_or(0);
+            let has_group = self
+                .users
+                .get(user)
+                .map(|t| t.contains_key(group))
+                .unwrap_or(false);
+            if !has_group && groups_len >= self.max_groups {
                 return Err(Error::msg("Positions cache is full, all groups taken"));
             }
 
@@ -168,7 +223,9 @@ impl CacheInner {
             };
             rec = check_rec(&*self, group, rec)?;
             let table = self
-                .table
+                .users
+                .entry(user.into())
+                .or_insert_with(HashMap::new)
                 .entry(group.into())
                 .or_insert_with(LinkedHashMap::new);
             table.insert(folder_path, rec);
@@ -183,18 +240,19 @@ impl CacheInner {
 
     fn insert_if_newer<S: Into<String>>(
         &mut self,
+        user: &str,
         group_path: S,
         position: f32,
         ts: u64,
     ) -> Result<()> {
-        self._insert(group_path, position, |t, group, mut rec| {
+        self._insert(user, group_path, position, |t, group, mut rec| {
             let diff = rec
                 .timestamp
                 .duration_since(SystemTime::UNIX_EPOCH)?
                 .as_secs()
                 .saturating_sub(ts);
             rec.timestamp = rec.timestamp - Duration::from_secs(diff);
-            match t.get_last(group) {
+            match t.get_last(user, group) {
                 None => Ok(rec),
                 Some(last) => {
                     if last.timestamp < rec.timestamp {
@@ -207,45 +265,53 @@ impl CacheInner {
         })
     }
 
-    fn insert<S: Into<String>>(&mut self, group_path: S, position: f32) -> Result<()> {
-        self._insert(group_path, position, |_, _, r| Ok(r))
+    fn insert<S: Into<String>>(&mut self, user: &str, group_path: S, position: f32) -> Result<()> {
+        self._insert(user, group_path, position, |_, _, r| Ok(r))
     }
 
-    fn get<K>(&self, group_folder: &K) -> Option<Position>
+    fn get<K>(&self, user: &str, group_folder: &K) -> Option<Position>
     where
         K: AsRef<str> + ?Sized,
     {
         split_group(group_folder).and_then(|(group, folder)| {
-            self.table
-                .get(group)
+            self.users
+                .get(user)
+                .and_then(|groups| groups.get(group))
                 .and_then(|table| table.get(folder).map(|p| to_position(folder, p)))
         })
     }
 
-    fn get_last<G: AsRef<str>>(&self, group: G) -> Option<Position> {
-        self.table.get(group.as_ref()).and_then(|table| {
-            table
-                .back()
-                .map(|(folder, p)| to_position(folder.as_ref(), p))
-        })
+    fn get_last<G: AsRef<str>>(&self, user: &str, group: G) -> Option<Position> {
+        self.users
+            .get(user)
+            .and_then(|groups| groups.get(group.as_ref()))
+            .and_then(|table| {
+                table
+                    .back()
+                    .map(|(folder, p)| to_position(folder.as_ref(), p))
+            })
     }
 
     fn clear(&mut self) {
-        self.table.clear()
+        self.users.clear()
     }
 
     fn len(&self) -> usize {
         let mut l = 0;
-        for table in self.table.values() {
-            l += table.len()
+        for groups in self.users.values() {
+            for table in groups.values() {
+                l += table.len()
+            }
         }
         l
     }
 
     fn shrink(&mut self, sz: usize) {
-        for table in self.table.values_mut() {
-            while table.len() > sz {
-                table.pop_front();
+        for groups in self.users.values_mut() {
+            for table in groups.values_mut() {
+                while table.len() > sz {
+                    table.pop_front();
+                }
             }
         }
     }
@@ -269,26 +335,28 @@ mod test {
 
     use super::*;
 
+    const U: &str = "";
+
     fn make_cache() -> CacheInner {
         let mut c = CacheInner::new(5, 5);
-        let p = c.get_last("group");
+        let p = c.get_last(U, "group");
         assert!(p.is_none());
 
-        c.insert("group/book1/chap1", 1.1).unwrap();
-        c.insert("group/book2/chap2", 2.1).unwrap();
-        c.insert("group/book3/chap3", 3.1).unwrap();
-        c.insert("group/book4/chap4", 4.1).unwrap();
-        c.insert("group/book5/chap5", 5.1).unwrap();
-        c.insert("group/book6/chap6", 6.1).unwrap();
-        c.insert("group/book4/chap7", 7.1).unwrap();
+        c.insert(U, "group/book1/chap1", 1.1).unwrap();
+        c.insert(U, "group/book2/chap2", 2.1).unwrap();
+        c.insert(U, "group/book3/chap3", 3.1).unwrap();
+        c.insert(U, "group/book4/chap4", 4.1).unwrap();
+        c.insert(U, "group/book5/chap5", 5.1).unwrap();
+        c.insert(U, "group/book6/chap6", 6.1).unwrap();
+        c.insert(U, "group/book4/chap7", 7.1).unwrap();
 
         c
     }
 
     fn check_cache(c: &CacheInner) {
         assert_eq!(5, c.len());
-        assert!(c.get_last("other").is_none());
-        let p_last = c.get_last("group").unwrap();
+        assert!(c.get_last(U, "other").is_none());
+        let p_last = c.get_last(U, "group").unwrap();
         assert_eq!(
             ("book4", "chap7", 7.1),
             (
@@ -298,7 +366,7 @@ mod test {
             )
         );
 
-        let p_last = c.get("group/book2").unwrap();
+        let p_last = c.get(U, "group/book2").unwrap();
         assert_eq!(
             ("book2", "chap2", 2.1),
             (
@@ -309,7 +377,7 @@ mod test {
         );
         assert!(p_last.timestamp <= SystemTime::now());
 
-        let p = c.get("group/book1");
+        let p = c.get(U, "group/book1");
         assert!(p.is_none());
     }
 
@@ -323,8 +391,8 @@ mod test {
     #[test]
     fn position_serialization() {
         let mut c = CacheInner::new(10, 10);
-        c.insert("group/book1/chap1", 123.456).unwrap();
-        let p = c.get("group/book1").unwrap();
+        c.insert(U, "group/book1/chap1", 123.456).unwrap();
+        let p = c.get(U, "group/book1").unwrap();
         let s = serde_json::to_string_pretty(&p).unwrap();
         println!("{}", s);
     }
@@ -335,10 +403,10 @@ mod test {
         let serc = serde_json::to_string(&c).unwrap();
         let mut c2: CacheInner = serde_json::from_str(&serc).unwrap();
         check_cache(&c2);
-        c2.insert("other/book10/chap1", 15.1).unwrap();
-        let pos = c2.get("other/book10").unwrap();
+        c2.insert(U, "other/book10/chap1", 15.1).unwrap();
+        let pos = c2.get(U, "other/book10").unwrap();
         assert_eq!(15.1, pos.position);
-        let pos = c2.get_last("other").unwrap();
+        let pos = c2.get_last(U, "other").unwrap();
         assert_eq!(15.1, pos.position);
     }
 
@@ -353,26 +421,51 @@ mod test {
     #[test]
     fn test_max_groups() {
         let mut c = CacheInner::new(5, 3);
-        c.insert("g1/book/f", 1.0).unwrap();
-        c.insert("g2/book/f", 1.0).unwrap();
-        c.insert("g3/book/f", 1.0).unwrap();
-        assert!(c.insert("g4/book/f", 1.0).is_err());
+        c.insert(U, "g1/book/f", 1.0).unwrap();
+        c.insert(U, "g2/book/f", 1.0).unwrap();
+        c.insert(U, "g3/book/f", 1.0).unwrap();
+        assert!(c.insert(U, "g4/book/f", 1.0).is_err());
     }
 
     #[test]
     fn test_insert_newer() {
         let mut c = CacheInner::new(5, 3);
-        c.insert("g1/book/f", 1.0).unwrap();
+        c.insert(U, "g1/book/f", 1.0).unwrap();
         let ts = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs();
         let ts_old = ts - 10;
-        assert!(c.insert_if_newer("g1/book/g", 2.0, ts_old).is_err());
-        c.insert_if_newer("g2/book/f", 3.0, ts_old).unwrap();
-        c.insert_if_newer("g2/book/f", 4.0, ts).unwrap();
-        let rec = c.get("g2/book").unwrap();
+        assert!(c.insert_if_newer(U, "g1/book/g", 2.0, ts_old).is_err());
+        c.insert_if_newer(U, "g2/book/f", 3.0, ts_old).unwrap();
+        c.insert_if_newer(U, "g2/book/f", 4.0, ts).unwrap();
+        let rec = c.get(U, "g2/book").unwrap();
         assert_eq!(rec.file, "f");
         assert_eq!(rec.position, 4.0);
     }
+
+    #[test]
+    fn test_users_partitions() {
+        let mut c = CacheInner::new(5, 1);
+        c.insert("usak", "g1/book/f", 1.0).unwrap();
+        c.insert("kulisak", "g1/book/f", 2.0).unwrap();
+        assert!(c.insert("usak", "g2/book/f", 1.0).is_err());
+        assert_eq!(1.0, c.get("usak", "g1/book").unwrap().position);
+        assert_eq!(2.0, c.get_last("kulisak", "g1").unwrap().position);
+        assert!(c.get(U, "g1/book").is_none());
+        assert_eq!(2, c.len());
+    }
+
+    #[test]
+    fn test_legacy_migration() {
+        let legacy = r#"{"table":{"group":{"book1":{"file":"chap1","timestamp":{"secs_since_epoch":1600000000,"nanos_since_epoch":0},"position":1.5}}},"max_size":10,"max_groups":10}"#;
+        let stored: StoredCache = serde_json::from_str(legacy).unwrap();
+        let c = CacheInner::from(stored);
+        let p = c.get(U, "group/book1").unwrap();
+        assert_eq!(("chap1", 1.5), (p.file.as_ref(), p.position));
+
+        let serc = serde_json::to_string(&c).unwrap();
+        let stored: StoredCache = serde_json::from_str(&serc).unwrap();
+        assert!(matches!(stored, StoredCache::Current(_)));
+    }
 }
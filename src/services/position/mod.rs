@@ -1,4 +1,4 @@
-use super::{RequestWrapper, ResponseFuture};
+use super::{auth::Identity, RequestWrapper, ResponseFuture};
 use crate::config::get_config;
 use crate::error::{bail, Context, Error};
 use cache::{Cache, Position};
@@ -84,17 +84,28 @@ impl FromStr for Msg {
     }
 }
 
-pub fn position_service(req: RequestWrapper) -> ResponseFuture {
+/// Positions are kept separately for each user, clients using shared secret
+/// (or no authentication) share common partition
+fn user_partition(identity: Option<Identity>) -> String {
+    match identity {
+        Some(Identity::User(name)) => name,
+        Some(Identity::Shared) | None => String::new(),
+    }
+}
+
+pub fn position_service(req: RequestWrapper, identity: Option<Identity>) -> ResponseFuture {
     debug!("We got these headers on websocket: {:?}", req.headers());
+    let user = user_partition(identity);
 
     let res = spawn_websocket_with_timeout::<String, _>(
         req.into_request(),
-        |m| {
+        move |m| {
             debug!("Got message {:?}", m);
             let message = m.to_str().map_err(Error::new).and_then(str::parse);
 
+            let user = user.clone();
             match message {
-                Ok(message) => Box::pin(async {
+                Ok(message) => Box::pin(async move {
                     Ok(match message {
                         Msg::Position {
                             position,
@@ -108,9 +119,9 @@ pub fn position_service(req: RequestWrapper) -> ResponseFuture {
                                         *p = file_path.clone();
                                     }
                                     if let Some(ts) = timestamp {
-                                        CACHE.insert_if_newer(file_path, position, ts).await
+                                        CACHE.insert_if_newer(&user, file_path, position, ts).await
                                     } else {
-                                        CACHE.insert(file_path, position).await
+                                        CACHE.insert(&user, file_path, position).await
                                     }
                                     .unwrap_or_else(|e| error!("Cannot insert position: {}", e))
                                 }
@@ -119,9 +130,9 @@ pub fn position_service(req: RequestWrapper) -> ResponseFuture {
                                     let prev = { m.context_ref().read().await.clone() };
 
                                     if !prev.is_empty() {
-                                        CACHE.insert(prev, position).await.unwrap_or_else(|e| {
-                                            error!("Cannot insert position: {}", e)
-                                        })
+                                        CACHE.insert(&user, prev, position).await.unwrap_or_else(
+                                            |e| error!("Cannot insert position: {}", e),
+                                        )
                                     } else {
                                         error!(
                                             "Client sent short position, but there is no context"
@@ -133,7 +144,7 @@ pub fn position_service(req: RequestWrapper) -> ResponseFuture {
                             None
                         }
                         Msg::GenericQuery { group } => {
-                            let last = CACHE.get_last(group).await;
+                            let last = CACHE.get_last(&user, group).await;
                             let res = Reply { folder: None, last };
 
                             Some(ws::Message::text(
@@ -144,8 +155,8 @@ pub fn position_service(req: RequestWrapper) -> ResponseFuture {
 
                         Msg::FolderQuery { folder_path } => {
                             let group = Some(folder_path.splitn(2, '/')).and_then(|mut p| p.next());
-                            let last = CACHE.get_last(group.unwrap()).await;
-                            let folder = CACHE.get(&folder_path).await;
+                            let last = CACHE.get_last(&user, group.unwrap()).await;
+                            let folder = CACHE.get(&user, &folder_path).await;
                             let res = Reply {
                                 last: if last != folder { last } else { None },
                                 folder,
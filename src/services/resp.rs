@@ -8,10 +8,12 @@ use crate::util::ResponseBuilderExt;
 const NOT_FOUND_MESSAGE: &str = "Not Found";
 const TOO_MANY_REQUESTS_MSG: &str = "Too many requests";
 const ACCESS_DENIED_MSG: &str = "Access denied";
+const FORBIDDEN_MSG: &str = "Forbidden";
 const METHOD_NOT_ALLOWED_MSG: &str = "Method not supported";
 const INTERNAL_ERROR_MSG: &str = "Internal Server Error";
 const BAD_REQUEST_MSG: &str = "Bad request";
 const NOT_IMPLEMENTED_MSG: &str = "Not Implemented";
+const NOT_ACCEPTABLE_MSG: &str = "Not Acceptable";
 
 fn short_response(status: StatusCode, msg: &'static str) -> Response<Body> {
     Response::builder()
@@ -42,10 +44,12 @@ macro_rules! def_resp {
 
 def_resp!(
     deny(StatusCode::UNAUTHORIZED, ACCESS_DENIED_MSG);
+    forbidden(StatusCode::FORBIDDEN, FORBIDDEN_MSG);
     too_many_requests(StatusCode::TOO_MANY_REQUESTS, TOO_MANY_REQUESTS_MSG);
     not_found(StatusCode::NOT_FOUND, NOT_FOUND_MESSAGE);
     method_not_supported(StatusCode::METHOD_NOT_ALLOWED, METHOD_NOT_ALLOWED_MSG);
     internal_error(StatusCode::INTERNAL_SERVER_ERROR,INTERNAL_ERROR_MSG);
     bad_request(StatusCode::BAD_REQUEST, BAD_REQUEST_MSG);
-    not_implemented(StatusCode::NOT_IMPLEMENTED, NOT_IMPLEMENTED_MSG)
+    not_implemented(StatusCode::NOT_IMPLEMENTED, NOT_IMPLEMENTED_MSG);
+    not_acceptable(StatusCode::NOT_ACCEPTABLE, NOT_ACCEPTABLE_MSG)
 );
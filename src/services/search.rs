@@ -12,6 +12,8 @@ const RECENT_LIST_SIZE: usize = 64;
 pub trait SearchTrait<S> {
     fn search(&self, collection: usize, query: S, ordering: FoldersOrdering) -> SearchResult;
     fn recent(&self, collection: usize) -> SearchResult;
+    /// Called after config reload, when collections might have changed
+    fn update_collections(&self) {}
 }
 
 struct FoldersSearch;
@@ -28,6 +30,9 @@ impl<S: AsRef<str>> SearchTrait<S> for Search<S> {
     fn recent(&self, collection: usize) -> SearchResult {
         self.inner.recent(collection)
     }
+    fn update_collections(&self) {
+        self.inner.update_collections()
+    }
 }
 
 impl<S: AsRef<str>> Search<S> {
@@ -213,10 +218,13 @@ impl FoldersSearch {
 #[cfg(feature = "search-cache")]
 mod cache {
     use super::*;
-    use cachedirtree::{DirCache, OptionsBuilder};
+    use cachedirtree::{DirCache, Options, OptionsBuilder};
+    use std::sync::RwLock;
 
     pub struct CachedSearch {
-        caches: Vec<DirCache>,
+        /// cache for each collection number, removed collections have no cache
+        caches: RwLock<Vec<Option<(PathBuf, DirCache)>>>,
+        opts: Options,
     }
 
     impl CachedSearch {
@@ -231,16 +239,32 @@ mod cache {
             let caches = get_config()
                 .base_dirs
                 .iter()
-                .map(|p| DirCache::new_with_options(p, opts))
+                .map(|p| Some((p.clone(), DirCache::new_with_options(p, opts))))
                 .collect();
 
-            CachedSearch { caches }
+            CachedSearch {
+                caches: RwLock::new(caches),
+                opts,
+            }
+        }
+
+        fn cache(&self, collection: usize) -> Option<DirCache> {
+            self.caches
+                .read()
+                .expect("Poisoned lock")
+                .get(collection)
+                .and_then(|c| c.as_ref())
+                .map(|(_, c)| c.clone())
         }
     }
 
     impl<S: AsRef<str>> SearchTrait<S> for CachedSearch {
         fn search(&self, collection: usize, query: S, ordering: FoldersOrdering) -> SearchResult {
-            let mut res = self.caches[collection]
+            let cache = match self.cache(collection) {
+                Some(c) => c,
+                None => return SearchResult::new(),
+            };
+            let mut res = cache
                 .search_collected(query, |iter| {
                     let mut res = SearchResult::new();
                     iter.for_each(|e| {
@@ -277,8 +301,12 @@ mod cache {
 
         fn recent(&self, collection: usize) -> SearchResult {
             let mut res = SearchResult::new();
+            let cache = match self.cache(collection) {
+                Some(c) => c,
+                None => return res,
+            };
 
-            self.caches[collection]
+            cache
                 .recent()
                 .map(|v| {
                     let subfolders = v
@@ -292,6 +320,32 @@ mod cache {
 
             res
         }
+
+        fn update_collections(&self) {
+            let cfg = get_config();
+            let mut caches = self.caches.write().expect("Poisoned lock");
+            let mut old: Vec<_> = caches.drain(..).flatten().collect();
+            for (i, dir) in cfg.base_dirs.iter().enumerate() {
+                if !cfg.has_collection(i) {
+                    caches.push(None);
+                    continue;
+                }
+                match old.iter().position(|(p, _)| p == dir) {
+                    Some(idx) => caches.push(Some(old.swap_remove(idx))),
+                    None => {
+                        info!("Creating search cache for new collection {:?}", dir);
+                        caches.push(Some((
+                            dir.clone(),
+                            DirCache::new_with_options(dir, self.opts),
+                        )))
+                    }
+                }
+            }
+            for (dir, cache) in old {
+                info!("Dropping search cache for removed collection {:?}", dir);
+                cache.stop()
+            }
+        }
     }
 }
 
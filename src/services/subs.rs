@@ -1,10 +1,20 @@
 #[cfg(feature = "folder-download")]
 use super::audio_folder::list_dir_files_only;
+#[cfg(feature = "transcoding-cache")]
+use super::transcode::pretranscode::pretranscode_next;
 use super::{
     audio_folder::{list_dir, parse_chapter_path},
+    audio_meta::{get_audio_properties, MediaInfo},
+    auth::Identity,
     resp,
     search::{Search, SearchTrait},
-    transcode::{guess_format, AudioFilePath, QualityLevel, TimeSpan},
+    transcode::{
+        filters::AudioFilters,
+        guess_format,
+        hls::{segment_span, HlsPlaylist, PLAYLIST_MIME},
+        negotiate::ClientCaps,
+        AudioFilePath, QualityLevel, TimeSpan, Transcoder,
+    },
     types::*,
     Counter,
 };
@@ -18,14 +28,16 @@ use crate::{
 use futures::prelude::*;
 use futures::{future, ready, Stream};
 use headers::{AcceptRanges, CacheControl, ContentLength, ContentRange, ContentType, LastModified};
-use hyper::{Body, Response as HyperResponse, StatusCode};
+use hyper::{
+    header::{ACCEPT_RANGES, CONTENT_TYPE},
+    Body, Response as HyperResponse, StatusCode,
+};
 use std::{
     collections::Bound,
     ffi::OsStr,
     io::{self, SeekFrom},
     path::{Path, PathBuf},
     pin::Pin,
-    sync::atomic::Ordering,
     task::{Context, Poll},
 };
 use tokio::{
@@ -45,6 +57,7 @@ fn serve_file_cached_or_transcoded(
     _range: Option<ByteRange>,
     transcoding: super::TranscodingDetails,
     transcoding_quality: QualityLevel,
+    filters: AudioFilters,
 ) -> ResponseFuture {
     serve_file_transcoded_checked(
         AudioFilePath::Original(full_path),
@@ -52,6 +65,7 @@ fn serve_file_cached_or_transcoded(
         span,
         transcoding,
         transcoding_quality,
+        filters,
     )
 }
 
@@ -63,6 +77,7 @@ fn serve_file_cached_or_transcoded(
     range: Option<ByteRange>,
     transcoding: super::TranscodingDetails,
     transcoding_quality: QualityLevel,
+    filters: AudioFilters,
 ) -> ResponseFuture {
     if get_config().transcoding.cache.disabled {
         return serve_file_transcoded_checked(
@@ -71,97 +86,264 @@ fn serve_file_cached_or_transcoded(
             span,
             transcoding,
             transcoding_quality,
+            filters,
+        );
+    }
+
+    if seek.is_none() && span.is_none() {
+        pretranscode_next(
+            full_path.clone(),
+            transcoding_quality,
+            filters,
+            transcoding.transcodings.clone(),
         );
     }
 
-    use super::transcode::cache::{cache_key, get_cache};
-    let cache = get_cache();
-    let cache_key = cache_key(&full_path, transcoding_quality, span);
-    let fut = cache
-        .get2(cache_key)
-        .then(|res| match res {
+    use super::transcode::cache;
+    let fut = async move {
+        let cache_key = get_config()
+            .transcoder(transcoding_quality)
+            .with_filters(filters)
+            .cache_key(&full_path, transcoding_quality, span)
+            .await;
+        let maybe_file = match cache::lookup(cache_key.clone()).await {
             Err(e) => {
                 error!("Cache lookup error: {}", e);
-                future::ok(None)
+                None
+            }
+            Ok(f) => f,
+        };
+        match maybe_file {
+            None if seek.is_none() => {
+                serve_file_tail_or_transcoded(
+                    full_path,
+                    span,
+                    transcoding,
+                    transcoding_quality,
+                    filters,
+                    cache_key,
+                )
+                .await
+            }
+            None => {
+                serve_file_transcoded_checked(
+                    AudioFilePath::Original(full_path),
+                    seek,
+                    span,
+                    transcoding,
+                    transcoding_quality,
+                    filters,
+                )
+                .await
+            }
+            Some((_, _)) if seek.is_some() && filters.skip_silence => {
+                debug!("Seek with skipped silence is in original file time, will transcode again");
+                serve_file_transcoded_checked(
+                    AudioFilePath::Original(full_path),
+                    seek,
+                    span,
+                    transcoding,
+                    transcoding_quality,
+                    filters,
+                )
+                .await
+            }
+            Some((_, path)) if seek.is_some() => {
+                debug!(
+                    "File is in cache and seek is needed -  will send remuxed from {:?} {:?}",
+                    path, span
+                );
+                serve_file_transcoded_checked(
+                    AudioFilePath::Transcoded(path),
+                    seek,
+                    None,
+                    transcoding,
+                    transcoding_quality,
+                    filters,
+                )
+                .await
             }
-            Ok(f) => future::ok(f),
-        })
-        .and_then(move |maybe_file| match maybe_file {
-            None => serve_file_transcoded_checked(
-                AudioFilePath::Original(full_path),
-                seek,
-                span,
-                transcoding,
-                transcoding_quality,
-            ),
             Some((f, path)) => {
-                if seek.is_some() {
-                    debug!(
-                        "File is in cache and seek is needed -  will send remuxed from {:?} {:?}",
-                        path, span
-                    );
-                    serve_file_transcoded_checked(
-                        AudioFilePath::Transcoded(path),
-                        seek,
-                        None,
-                        transcoding,
-                        transcoding_quality,
-                    )
-                } else {
-                    debug!("Sending file {:?} from transcoded cache", &full_path);
-                    let mime = get_config()
-                        .transcoder(transcoding_quality)
-                        .transcoded_mime();
-                    Box::pin(serve_opened_file(f, range, None, mime).map_err(|e| {
-                        error!("Error sending cached file: {}", e);
-                        Error::new(e).context("sending cached file")
-                    }))
+                debug!("Sending file {:?} from transcoded cache", &full_path);
+                let mime = get_config()
+                    .transcoder(transcoding_quality)
+                    .transcoded_mime();
+                let mut resp = serve_opened_file(f, range, None, mime).await.map_err(|e| {
+                    error!("Error sending cached file: {}", e);
+                    Error::new(e).context("sending cached file")
+                })?;
+                if filters.skip_silence {
+                    // duration of output without silence is known only when it's complete
+                    let duration = transcoded_duration(path)
+                        .await
+                        .map(|d| d.to_string())
+                        .unwrap_or_else(|| UNKNOWN_DURATION.into());
+                    resp.headers_mut()
+                        .insert(DURATION_HEADER, duration.parse().unwrap());
                 }
+                Ok(resp)
             }
-        });
+        }
+    };
 
     Box::pin(fut)
 }
 
-fn serve_file_transcoded_checked(
-    full_path: AudioFilePath<PathBuf>,
-    seek: Option<f32>,
+/// If file is just being transcoded into cache, its output is shared, otherwise it's transcoded
+#[cfg(feature = "transcoding-cache")]
+fn serve_file_tail_or_transcoded(
+    full_path: PathBuf,
     span: Option<TimeSpan>,
     transcoding: super::TranscodingDetails,
     transcoding_quality: QualityLevel,
+    filters: AudioFilters,
+    cache_key: String,
 ) -> ResponseFuture {
-    let counter = transcoding.transcodings;
-    let mut running_transcodings = counter.load(Ordering::SeqCst);
-    loop {
-        if running_transcodings >= transcoding.max_transcodings {
-            warn!(
-                "Max transcodings reached {}/{}",
-                running_transcodings, transcoding.max_transcodings
-            );
-            return resp::fut(resp::too_many_requests);
+    use super::transcode::cache::get_cache;
+    let fut = async move {
+        match get_cache().tail(cache_key).await {
+            Ok(Some(reader)) => {
+                debug!(
+                    "File {:?} is being transcoded into cache, will follow it",
+                    full_path
+                );
+                let transcoder = get_config()
+                    .transcoder(transcoding_quality)
+                    .with_filters(filters);
+                let stream = stream::try_unfold(reader, |mut reader| async move {
+                    let mut buf = vec![0u8; 8 * 1024];
+                    let n = reader.read(&mut buf).await?;
+                    if n == 0 {
+                        Ok::<_, io::Error>(None)
+                    } else {
+                        buf.truncate(n);
+                        Ok(Some((buf, reader)))
+                    }
+                });
+                Ok(HyperResponse::builder()
+                    .typed_header(ContentType::from(transcoder.transcoded_mime()))
+                    .header(
+                        "X-Transcode",
+                        transcoder
+                            .transcoding_params(transcoding_quality)
+                            .as_bytes(),
+                    )
+                    .with_unknown_duration(transcoder.skips_silence())
+                    .body(Body::wrap_stream(stream.map_err(Error::new)))
+                    .unwrap())
+            }
+            res => {
+                if let Err(e) = res {
+                    error!("Cache lookup error: {}", e);
+                }
+                serve_file_transcoded_checked(
+                    AudioFilePath::Original(full_path),
+                    None,
+                    span,
+                    transcoding,
+                    transcoding_quality,
+                    filters,
+                )
+                .await
+            }
+        }
+    };
+    Box::pin(fut)
+}
+
+/// duration (in secs) of transcoded content with skipped silence or `unknown`,
+/// if it's still being transcoded
+pub const DURATION_HEADER: &str = "X-Duration";
+const UNKNOWN_DURATION: &str = "unknown";
+
+trait UnknownDurationExt {
+    fn with_unknown_duration(self, unknown: bool) -> Self;
+}
+
+impl UnknownDurationExt for hyper::http::response::Builder {
+    /// Marks live stream with skipped silence, which is shorter then original file,
+    /// so client cannot rely on duration (and byte ranges) of original file
+    fn with_unknown_duration(self, unknown: bool) -> Self {
+        if unknown {
+            self.header(DURATION_HEADER, UNKNOWN_DURATION)
+                .header(ACCEPT_RANGES, "none")
+        } else {
+            self
         }
+    }
+}
 
-        match counter.compare_exchange(
-            running_transcodings,
-            running_transcodings + 1,
-            Ordering::SeqCst,
-            Ordering::SeqCst,
-        ) {
-            Ok(_) => {
-                running_transcodings = running_transcodings + 1;
-                break;
-            }
-            Err(curr) => running_transcodings = curr,
+#[cfg(feature = "transcoding-cache")]
+async fn transcoded_duration(path: PathBuf) -> Option<u32> {
+    blocking(move || {
+        get_audio_properties(&path)
+            .ok()
+            .and_then(|info| info.get_audio_info())
+            .map(|meta| meta.duration)
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+// waits in queue for transcoding process slot, returns number of running transcodings
+// or None if queue is full or waiting timed out
+async fn reserve_transcoding(
+    transcoding: &super::TranscodingDetails,
+    file: &Path,
+    continuation: bool,
+) -> Option<usize> {
+    match transcoding
+        .transcodings
+        .acquire(transcoding.client, file, continuation)
+        .await
+    {
+        Ok(running) => Some(running),
+        Err(e) => {
+            warn!(
+                "Cannot start transcoding ({:?}), running {}/{}, waiting {}",
+                e,
+                transcoding.transcodings.running(),
+                transcoding.max_transcodings,
+                transcoding.transcodings.waiting()
+            );
+            None
         }
     }
+}
 
-    debug!(
-        "Sendig file {:?} transcoded - remaining slots {}/{}",
-        &full_path,
-        transcoding.max_transcodings - running_transcodings,
-        transcoding.max_transcodings
-    );
-    serve_file_transcoded(full_path, seek, span, transcoding_quality, counter)
+fn serve_file_transcoded_checked(
+    full_path: AudioFilePath<PathBuf>,
+    seek: Option<f32>,
+    span: Option<TimeSpan>,
+    transcoding: super::TranscodingDetails,
+    transcoding_quality: QualityLevel,
+    filters: AudioFilters,
+) -> ResponseFuture {
+    let fut = async move {
+        let running_transcodings =
+            match reserve_transcoding(&transcoding, full_path.as_ref(), seek.is_some()).await {
+                Some(n) => n,
+                None => return Ok(resp::too_many_requests()),
+            };
+
+        debug!(
+            "Sendig file {:?} transcoded - remaining slots {}/{}",
+            &full_path,
+            transcoding.max_transcodings - running_transcodings,
+            transcoding.max_transcodings
+        );
+        serve_file_transcoded(
+            full_path,
+            seek,
+            span,
+            transcoding_quality,
+            transcoding.transcodings,
+            filters,
+        )
+        .await
+    };
+    Box::pin(fut)
 }
 
 fn serve_file_transcoded(
@@ -170,22 +352,46 @@ fn serve_file_transcoded(
     span: Option<TimeSpan>,
     transcoding_quality: QualityLevel,
     counter: Counter,
+    filters: AudioFilters,
 ) -> ResponseFuture {
-    let transcoder = get_config().transcoder(transcoding_quality);
-    let params = transcoder.transcoding_params();
+    let transcoder = get_config()
+        .transcoder(transcoding_quality)
+        .with_filters(filters);
     let mime = if let QualityLevel::Passthrough = transcoding_quality {
         guess_format(full_path.as_ref()).mime
     } else {
         transcoder.transcoded_mime()
     };
+    serve_transcoded_stream(
+        transcoder,
+        mime,
+        full_path,
+        seek,
+        span,
+        transcoding_quality,
+        counter,
+    )
+}
 
+fn serve_transcoded_stream(
+    transcoder: Transcoder,
+    mime: mime::Mime,
+    full_path: AudioFilePath<PathBuf>,
+    seek: Option<f32>,
+    span: Option<TimeSpan>,
+    transcoding_quality: QualityLevel,
+    counter: Counter,
+) -> ResponseFuture {
+    let params = transcoder.transcoding_params(transcoding_quality);
+    let unknown_duration = transcoder.skips_silence();
     let fut = transcoder
-        .transcode(full_path, seek, span, counter.clone(), transcoding_quality)
+        .transcode(full_path, seek, span, counter, transcoding_quality)
         .then(move |res| match res {
             Ok(stream) => future::ok(
                 HyperResponse::builder()
                     .typed_header(ContentType::from(mime))
                     .header("X-Transcode", params.as_bytes())
+                    .with_unknown_duration(unknown_duration)
                     .body(Body::wrap_stream(stream.map_err(Error::new)))
                     .unwrap(),
             ),
@@ -346,6 +552,7 @@ pub fn send_file<P: AsRef<Path>>(
     seek: Option<f32>,
     transcoding: super::TranscodingDetails,
     transcoding_quality: Option<QualityLevel>,
+    filters: AudioFilters,
 ) -> ResponseFuture {
     let (real_path, span) = parse_chapter_path(file_path.as_ref());
     let full_path = base_path.join(real_path);
@@ -361,6 +568,7 @@ pub fn send_file<P: AsRef<Path>>(
             range,
             transcoding,
             transcoding_quality,
+            filters,
         )
     } else if span.is_some() {
         debug!("Sending part of file remuxed");
@@ -370,6 +578,7 @@ pub fn send_file<P: AsRef<Path>>(
             span,
             transcoding,
             QualityLevel::Passthrough,
+            AudioFilters::default(),
         )
     } else {
         debug!("Sending file directly from fs");
@@ -377,6 +586,215 @@ pub fn send_file<P: AsRef<Path>>(
     }
 }
 
+/// Sends file with transcoding chosen by server according to client capabilities
+pub fn send_file_negotiated(
+    base_path: &'static Path,
+    file_path: PathBuf,
+    range: Option<ByteRange>,
+    seek: Option<f32>,
+    transcoding: super::TranscodingDetails,
+    caps: ClientCaps,
+    filters: AudioFilters,
+) -> ResponseFuture {
+    let fut = async move {
+        let (real_path, _span) = parse_chapter_path(&file_path);
+        let full_path = base_path.join(real_path);
+        let bitrate = blocking(move || {
+            get_audio_properties(&full_path)
+                .ok()
+                .and_then(|info| info.get_audio_info())
+                .map(|meta| meta.bitrate)
+        })
+        .await
+        .unwrap_or_else(|e| {
+            error!("Error when getting audio properties: {}", e);
+            None
+        });
+        let transcoding_quality = match caps.choose(&file_path, bitrate) {
+            Ok(q) => q,
+            Err(_) => {
+                error!(
+                    "Client cannot play {:?} nor any available transcoding",
+                    file_path
+                );
+                return Ok(resp::not_acceptable());
+            }
+        };
+        debug!(
+            "Negotiated transcoding {:?} for {:?} with bitrate {:?}",
+            transcoding_quality, file_path, bitrate
+        );
+        let mut resp = send_file(
+            base_path,
+            file_path,
+            range,
+            seek,
+            transcoding,
+            transcoding_quality,
+            filters,
+        )
+        .await?;
+        if !resp.headers().contains_key("X-Transcode") {
+            let profile = transcoding_quality.unwrap_or(QualityLevel::Passthrough);
+            resp.headers_mut().insert(
+                "X-Transcode",
+                format!("profile={}", profile.to_letter()).parse().unwrap(),
+            );
+        }
+        Ok(resp)
+    };
+    Box::pin(fut)
+}
+
+// duration of file or chapter in ms
+async fn media_duration(full_path: PathBuf, span: Option<TimeSpan>) -> Result<Option<u64>> {
+    if let Some(TimeSpan {
+        duration: Some(d), ..
+    }) = span
+    {
+        return Ok(Some(d));
+    }
+    let file_duration = blocking(move || {
+        get_audio_properties(&full_path)
+            .ok()
+            .and_then(|info| info.get_audio_info())
+            .map(|meta| u64::from(meta.duration) * 1000)
+    })
+    .await
+    .map_err(Error::new)?;
+    Ok(file_duration.map(|d| match span {
+        Some(span) => d.saturating_sub(span.start),
+        None => d,
+    }))
+}
+
+/// Sends HLS playlist for audio file or chapter, `segment_query` is query string for segment URIs
+pub fn send_hls_playlist(
+    base_path: &'static Path,
+    file_path: PathBuf,
+    segment_query: String,
+    speed: f32,
+) -> ResponseFuture {
+    let fut = async move {
+        let name = match file_path.file_name().and_then(OsStr::to_str) {
+            Some(n) => n.to_owned(),
+            None => return Ok(resp::not_found()),
+        };
+        let (real_path, span) = parse_chapter_path(&file_path);
+        let full_path = base_path.join(real_path);
+        let duration = match media_duration(full_path, span).await? {
+            Some(d) => d,
+            None => {
+                error!("Cannot get duration of {:?} for HLS playlist", file_path);
+                return Ok(resp::not_found());
+            }
+        };
+
+        let name = percent_encoding::utf8_percent_encode(&name, percent_encoding::NON_ALPHANUMERIC)
+            .to_string();
+        // playlist duration is in output time
+        let duration = (duration as f32 / speed).round() as u64;
+        let playlist = HlsPlaylist::new(duration, get_config().transcoding.hls_segment_duration)
+            .render(|i| format!("{}?seg={}&{}", name, i, segment_query));
+        Ok(HyperResponse::builder()
+            .header(CONTENT_TYPE, PLAYLIST_MIME)
+            .typed_header(ContentLength(playlist.len() as u64))
+            .body(playlist.into())
+            .unwrap())
+    };
+    Box::pin(fut)
+}
+
+/// Sends one HLS segment, transcoded on demand or from cache
+pub fn send_hls_segment(
+    base_path: &'static Path,
+    file_path: PathBuf,
+    index: usize,
+    transcoding: super::TranscodingDetails,
+    transcoding_quality: QualityLevel,
+    filters: AudioFilters,
+) -> ResponseFuture {
+    let (real_path, span) = parse_chapter_path(&file_path);
+    let full_path = base_path.join(real_path);
+    let segment_secs = get_config().transcoding.hls_segment_duration;
+    let segment = match segment_span(span, index, segment_secs, filters.speed()) {
+        Some(s) => s,
+        None => return resp::fut(resp::not_found),
+    };
+    let transcoder = get_config()
+        .transcoder(transcoding_quality)
+        .with_filters(filters)
+        .hls_segment(index as f32 * segment_secs as f32);
+    #[cfg(feature = "transcoding-cache")]
+    let cache_path = full_path.clone();
+    let speed = filters.speed();
+    let transcode = move |transcoder: Transcoder| -> ResponseFuture {
+        Box::pin(async move {
+            // index must be within file, so arbitrary segments cannot start transcodings
+            let duration = match media_duration(full_path.clone(), span).await? {
+                Some(d) => (d as f32 / speed).round() as u64,
+                None => {
+                    error!("Cannot get duration of {:?} for HLS segment", full_path);
+                    return Ok(resp::not_found());
+                }
+            };
+            if index >= HlsPlaylist::new(duration, segment_secs).segments_count() {
+                error!("HLS segment {} is after end of {:?}", index, full_path);
+                return Ok(resp::not_found());
+            }
+            // segments after first one continue playback
+            if reserve_transcoding(&transcoding, &full_path, index > 0)
+                .await
+                .is_none()
+            {
+                return Ok(resp::too_many_requests());
+            }
+            debug!("Transcoding HLS segment {} of {:?}", index, full_path);
+            let mime = transcoder.transcoded_mime();
+            serve_transcoded_stream(
+                transcoder,
+                mime,
+                AudioFilePath::Original(full_path),
+                None,
+                Some(segment),
+                transcoding_quality,
+                transcoding.transcodings,
+            )
+            .await
+        })
+    };
+
+    #[cfg(feature = "transcoding-cache")]
+    {
+        if !get_config().transcoding.cache.disabled {
+            use super::transcode::cache;
+            let fut = async move {
+                let cache_key = transcoder
+                    .cache_key(&cache_path, transcoding_quality, Some(segment))
+                    .await;
+                match cache::lookup(cache_key).await {
+                    Ok(Some((f, _path))) => {
+                        debug!("Sending HLS segment {} from cache", index);
+                        let mime = transcoder.transcoded_mime();
+                        serve_opened_file(f, None, None, mime).await.map_err(|e| {
+                            error!("Error sending cached file: {}", e);
+                            Error::new(e).context("sending cached file")
+                        })
+                    }
+                    Ok(None) => transcode(transcoder).await,
+                    Err(e) => {
+                        error!("Cache lookup error: {}", e);
+                        transcode(transcoder).await
+                    }
+                }
+            };
+            return Box::pin(fut);
+        }
+    }
+
+    transcode(transcoder)
+}
+
 pub fn get_folder(
     base_path: &'static Path,
     folder_path: PathBuf,
@@ -512,7 +930,7 @@ pub fn download_folder(
     Box::pin(f)
 }
 
-fn json_response<T: serde::Serialize>(data: &T) -> Response {
+pub fn json_response<T: serde::Serialize>(data: &T) -> Response {
     let json = serde_json::to_string(data).expect("Serialization error");
 
     HyperResponse::builder()
@@ -524,7 +942,21 @@ fn json_response<T: serde::Serialize>(data: &T) -> Response {
 
 const UKNOWN_NAME: &str = "unknown";
 
-pub fn collections_list() -> ResponseFuture {
+pub fn collections_list(identity: Option<Identity>) -> ResponseFuture {
+    let cfg = get_config();
+    let (numbers, names): (Vec<_>, Vec<_>) = cfg
+        .accessible_collections(identity.as_ref())
+        .into_iter()
+        .map(|i| {
+            (
+                i as u32,
+                cfg.base_dirs[i]
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .unwrap_or(UKNOWN_NAME),
+            )
+        })
+        .unzip();
     let collections = Collections {
         folder_download: !get_config().disable_folder_download,
         shared_positions: if cfg!(feature = "shared-positions") {
@@ -532,21 +964,59 @@ pub fn collections_list() -> ResponseFuture {
         } else {
             false
         },
-        count: get_config().base_dirs.len() as u32,
-        names: get_config()
-            .base_dirs
-            .iter()
-            .map(|p| p.file_name().and_then(OsStr::to_str).unwrap_or(UKNOWN_NAME))
-            .collect(),
+        count: names.len() as u32,
+        names,
+        numbers,
     };
     Box::pin(future::ok(json_response(&collections)))
 }
 
-pub fn transcodings_list() -> ResponseFuture {
-    let transcodings = Transcodings::new();
+pub fn transcodings_list(transcoding: &super::TranscodingDetails) -> ResponseFuture {
+    let transcodings = Transcodings::new(&transcoding.transcodings);
     Box::pin(future::ok(json_response(&transcodings)))
 }
 
+#[cfg(feature = "transcoding-cache")]
+pub fn cache_info() -> ResponseFuture {
+    use super::transcode::cache::get_cache;
+    Box::pin(async {
+        let cache = get_cache();
+        let entries = cache.list().await?;
+        let (files, size) = cache.usage();
+        let (max_files, max_size) = cache.limits();
+        let info = CacheInfo {
+            files,
+            max_files,
+            size,
+            max_size,
+            entries: entries.into_iter().map(CacheEntry::from).collect(),
+        };
+        Ok(json_response(&info))
+    })
+}
+
+#[cfg(feature = "transcoding-cache")]
+pub fn cache_purge(filter: crate::config::PurgeFilter) -> ResponseFuture {
+    use super::transcode::cache::purge;
+    Box::pin(async move {
+        let removed = purge(filter).await?;
+        debug!("Removed {} entries from transcoding cache", removed);
+        Ok(json_response(&CachePurged { removed }))
+    })
+}
+
+#[cfg(feature = "transcoding-cache")]
+pub fn cache_save() -> ResponseFuture {
+    use super::transcode::cache::get_cache;
+    Box::pin(async {
+        get_cache().save_index().await?;
+        Ok(HyperResponse::builder()
+            .typed_header(ContentLength(0))
+            .body(Body::empty())
+            .unwrap())
+    })
+}
+
 pub fn search(
     collection: usize,
     searcher: Search<String>,
@@ -1,35 +1,80 @@
-use crate::config::get_config;
+use crate::config::{get_config, PurgeFilter};
+use crate::error::{Context, Error, Result};
 use crate::services::transcode::{QualityLevel, TimeSpan};
-use simple_file_cache::AsyncCache as Cache;
+use crate::util::file_version;
+use data_encoding::HEXLOWER;
+use ring::digest::{digest, SHA256};
+use simple_file_cache::{AsyncCache as Cache, CacheOptions};
+use std::borrow::Cow;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Separates version of entry from rest of the key
+const VERSION_SEPARATOR: char = '#';
 
 lazy_static! {
-    pub static ref CACHE: Option<Cache> = {
-        let cfg = get_config();
-        if cfg.transcoding.cache.disabled {
-            None
-        } else {
-            let cache_dir = &cfg.transcoding.cache.root_dir;
-            if !cache_dir.exists() {
-                fs::create_dir(&cache_dir).expect("Cannot create directory for cache")
+    static ref CACHE: std::result::Result<Option<Cache>, String> =
+        open_cache().map_err(|e| format!("{:#}", e));
+}
+
+fn open_cache() -> Result<Option<Cache>> {
+    let cfg = get_config();
+    if cfg.transcoding.cache.disabled {
+        return Ok(None);
+    }
+    let cache_dir = &cfg.transcoding.cache.root_dir;
+    if !cache_dir.exists() {
+        fs::create_dir(&cache_dir).context("Cannot create directory for cache")?
+    }
+    let options = CacheOptions {
+        max_age: match cfg.transcoding.cache.max_age {
+            0 => None,
+            hours => Some(Duration::from_secs(u64::from(hours) * 3600)),
+        },
+        low_watermark: f32::from(cfg.transcoding.cache.low_watermark) / 100.0,
+        high_watermark: f32::from(cfg.transcoding.cache.high_watermark) / 100.0,
+    };
+    let cache = Cache::with_options(
+        cache_dir,
+        u64::from(cfg.transcoding.cache.max_size) * 1024 * 1024,
+        cfg.transcoding.cache.max_files.into(),
+        options,
+    )?;
+    Ok(Some(cache))
+}
+
+// % is escaped, so path can be decoded back by percent decoding, in non-unicode names
+// also all non ASCII bytes are escaped
+fn path_key(p: &Path) -> Cow<str> {
+    match p.to_str() {
+        Some(s) if s.contains('%') => Cow::Owned(s.replace('%', "%25")),
+        Some(s) => Cow::Borrowed(s),
+        None => {
+            #[cfg(unix)]
+            {
+                use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+                use std::os::unix::ffi::OsStrExt;
+                const ESCAPED: &AsciiSet = &CONTROLS.add(b'%');
+                Cow::Owned(percent_encode(p.as_os_str().as_bytes(), ESCAPED).to_string())
+            }
+            #[cfg(not(unix))]
+            {
+                Cow::Owned(p.to_string_lossy().replace('%', "%25"))
             }
-            Some(
-                Cache::new(
-                    cache_dir,
-                    u64::from(cfg.transcoding.cache.max_size) * 1024 * 1024,
-                    cfg.transcoding.cache.max_files.into(),
-                )
-                .expect("Cannot create cache"),
-            )
         }
-    };
+    }
 }
 
-//TODO: not ideal as potential collisions for non-unicode names
+/// Identifies transcoded part of file in cache (without version)
 pub fn cache_key<P: AsRef<Path>>(file: P, quality: QualityLevel, span: Option<TimeSpan>) -> String {
-    let mut key: String = quality.to_letter().into();
-    key.push_str(&file.as_ref().to_string_lossy());
+    let mut key: String = match quality {
+        // profile names cannot contain : or /, so it cannot collide with letters or path,
+        // hls is not single letter, so it's separated same way
+        QualityLevel::Profile(_) | QualityLevel::Hls => format!("{}:", quality.to_letter()),
+        _ => quality.to_letter().into(),
+    };
+    key.push_str(&path_key(file.as_ref()));
 
     if let Some(span) = span {
         key.push('/');
@@ -38,8 +83,124 @@ pub fn cache_key<P: AsRef<Path>>(file: P, quality: QualityLevel, span: Option<Ti
     key
 }
 
+/// Adds version to key - it's hash of source file size and modification time (from its `meta`)
+/// and `fingerprint` of transcoding parameters, so entry is not used, when any of them changes
+pub fn versioned_key(mut key: String, meta: Option<&fs::Metadata>, fingerprint: &str) -> String {
+    let mut data = fingerprint.to_owned();
+    if let Some(meta) = meta {
+        data.push('|');
+        data.push_str(&file_version(meta));
+    }
+    let hash = digest(&SHA256, data.as_bytes());
+    key.push(VERSION_SEPARATOR);
+    key.push_str(&HEXLOWER.encode(&hash.as_ref()[..8]));
+    key
+}
+
+// key without version
+fn unversioned_key(key: &str) -> &str {
+    match key.rfind(VERSION_SEPARATOR) {
+        Some(idx) => &key[..idx],
+        None => key,
+    }
+}
+
+/// Looks up entry in cache, on miss removes other versions of this entry,
+/// as it's going to be transcoded again
+pub async fn lookup(key: String) -> Result<Option<(tokio::fs::File, PathBuf)>> {
+    let entry = get_cache().get2(&key).await?;
+    if entry.is_none() {
+        remove_stale(key).await;
+    }
+    Ok(entry)
+}
+
+/// Removes other versions of this entry from cache
+async fn remove_stale(key: String) {
+    let base = unversioned_key(&key).to_string();
+    match get_cache()
+        .remove_matching(move |k| k != key && unversioned_key(k) == base)
+        .await
+    {
+        Ok(0) => (),
+        Ok(n) => debug!("Removed {} stale entries from cache", n),
+        Err(e) => error!("Cannot remove stale entries from cache: {}", e),
+    }
+}
+
+// splits key into quality (letter of transcoding level, hls or profile name) and rest of key (path ...)
+fn split_key<'a>(key: &'a str, profiles: &[String]) -> (&'a str, &'a str) {
+    std::iter::once(QualityLevel::Hls.to_letter())
+        .chain(profiles.iter().map(String::as_str))
+        .find(|name| key.starts_with(name) && key[name.len()..].starts_with(':'))
+        .map(|name| (&key[..name.len()], &key[name.len() + 1..]))
+        .unwrap_or_else(|| match key.char_indices().nth(1) {
+            Some((idx, _)) => key.split_at(idx),
+            None => (key, ""),
+        })
+}
+
+// creates predicate selecting keys of entries by quality and path of file or folder,
+// path must be full path (as in key)
+fn key_matcher(
+    quality: Option<String>,
+    path: Option<PathBuf>,
+    profiles: Vec<String>,
+) -> impl Fn(&str) -> bool {
+    let path = path.map(|p| path_key(&p).trim_end_matches('/').to_string());
+    move |key| {
+        let (key_quality, rest) = split_key(key, &profiles);
+        if let Some(ref quality) = quality {
+            if key_quality != quality {
+                return false;
+            }
+        }
+        match path {
+            // path must be whole file or folder, not just prefix of its name
+            Some(ref path) => rest
+                .strip_prefix(path.as_str())
+                .map(|r| r.is_empty() || r.starts_with('/') || r.starts_with(VERSION_SEPARATOR))
+                .unwrap_or(false),
+            None => true,
+        }
+    }
+}
+
+/// Removes entries selected by `filter` (all entries for empty filter),
+/// returns number of removed entries
+pub async fn purge(filter: PurgeFilter) -> Result<u64> {
+    let cfg = get_config();
+    let path = filter
+        .path
+        .map(|(collection, path)| cfg.base_dirs[collection].join(path));
+    let profiles = cfg.transcoding.profiles.keys().cloned().collect();
+    let removed = get_cache()
+        .remove_matching(key_matcher(filter.quality, path, profiles))
+        .await?;
+    Ok(removed)
+}
+
+/// Periodically removes expired entries and evicts entries over high watermark
+pub async fn sweep_cache() {
+    let period = Duration::from_secs(u64::from(get_config().transcoding.cache.sweep_interval));
+    get_cache().clone().sweep_periodically(period).await
+}
+
+/// Opens cache (if enabled) - cache directory is locked, so it fails,
+/// if cache is already used by other audioserve process (server or cache CLI command)
+pub fn init_cache() -> Result<()> {
+    match *CACHE {
+        Ok(_) => Ok(()),
+        Err(ref e) => Err(Error::msg(e.clone())),
+    }
+}
+
 pub fn get_cache() -> &'static Cache {
-    CACHE.as_ref().unwrap()
+    CACHE
+        .as_ref()
+        .ok()
+        .and_then(Option::as_ref)
+        .expect("Transcoding cache is not available")
 }
 
 #[cfg(test)]
@@ -58,5 +219,87 @@ mod tests {
             }),
         );
         assert_eq!("m/home/ivan/neco/0-5", key);
+        let key = cache_key(
+            "/home/ivan/neco",
+            QualityLevel::Profile("car-mono-24k"),
+            None,
+        );
+        assert_eq!("car-mono-24k:/home/ivan/neco", key);
+        let key = cache_key("/home/ivan/neco", QualityLevel::Hls, None);
+        assert_eq!("hls:/home/ivan/neco", key);
+        let key = cache_key("/home/ivan/100%", QualityLevel::Low, None);
+        assert_eq!("l/home/ivan/100%25", key);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_unicode_key() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let p = Path::new(OsStr::from_bytes(b"/home/ivan/\xe9t\xe9%"));
+        let key = cache_key(p, QualityLevel::Low, None);
+        assert_eq!("l/home/ivan/%E9t%E9%25", key);
+        // lossy conversion would collide with this one
+        let key2 = cache_key("/home/ivan/\u{FFFD}t\u{FFFD}%", QualityLevel::Low, None);
+        assert_ne!(key, key2);
+    }
+
+    #[test]
+    fn test_versioned_key() {
+        let key = cache_key("test_data/01-file.mp3", QualityLevel::Low, None);
+        let meta1 = fs::metadata("test_data/01-file.mp3").ok();
+        let meta2 = fs::metadata("test_data/02-file.opus").ok();
+        let v1 = versioned_key(key.clone(), meta1.as_ref(), "opus 32");
+        let v2 = versioned_key(key.clone(), meta1.as_ref(), "opus 48");
+        let v3 = versioned_key(key.clone(), meta2.as_ref(), "opus 32");
+        assert!(v1.starts_with("ltest_data/01-file.mp3#"));
+        assert_eq!(key.len() + 17, v1.len());
+        assert_ne!(v1, v2);
+        assert_ne!(v1, v3);
+        assert_eq!(v1, versioned_key(key.clone(), meta1.as_ref(), "opus 32"));
+        assert_eq!(key, unversioned_key(&v1));
+        assert_eq!(unversioned_key(&v1), unversioned_key(&v2));
+        let hashed_name = cache_key("a#b", QualityLevel::Low, None);
+        assert_eq!(
+            hashed_name,
+            unversioned_key(&versioned_key(hashed_name.clone(), None, ""))
+        );
+    }
+
+    #[test]
+    fn test_key_matcher() {
+        let profiles = vec!["mono".to_string()];
+        let keys = [
+            "m/home/ivan/author/book/01.mp3#1234",
+            "m/home/ivan/author/book/02.mp3/0-5#1234",
+            "l/home/ivan/author/book/01.mp3/norm#1234",
+            "mono:/home/ivan/author/book/01.mp3#1234",
+            "m/home/ivan/author2/book/01.mp3#1234",
+            "hls:/home/ivan/author/book/01.mp3/10-10/hls#1234",
+            "h/home/ivan/author/book/01.mp3#1234",
+        ];
+        let count = |quality: Option<&str>, path: Option<&str>| {
+            let matcher = key_matcher(
+                quality.map(ToOwned::to_owned),
+                path.map(PathBuf::from),
+                profiles.clone(),
+            );
+            keys.iter().filter(|k| matcher(k)).count()
+        };
+        assert_eq!(7, count(None, None));
+        assert_eq!(3, count(Some("m"), None));
+        assert_eq!(1, count(Some("mono"), None));
+        assert_eq!(1, count(Some("h"), None));
+        assert_eq!(1, count(Some("hls"), None));
+        assert_eq!(6, count(None, Some("/home/ivan/author")));
+        assert_eq!(6, count(None, Some("/home/ivan/author/")));
+        assert_eq!(2, count(Some("m"), Some("/home/ivan/author/book")));
+        assert_eq!(1, count(Some("hls"), Some("/home/ivan/author/book")));
+        assert_eq!(5, count(None, Some("/home/ivan/author/book/01.mp3")));
+        assert_eq!(
+            1,
+            count(Some("mono"), Some("/home/ivan/author/book/01.mp3"))
+        );
+        assert_eq!(0, count(None, Some("/home/ivan/auth")));
     }
 }
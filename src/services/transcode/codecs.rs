@@ -67,6 +67,10 @@ impl AudioCodec for Opus {
         &["-acodec", "libopus", "-vbr", "on"]
     }
 
+    fn sample_rate(&self) -> u32 {
+        48_000
+    }
+
     fn bitrate(&self) -> u32 {
         u32::from(self.bitrate)
     }
@@ -145,6 +149,14 @@ impl SampleRate {
             SampleRate::Unlimited => 0,
         }
     }
+
+    // for unlimited it's CD quality, as we cannot know rate of source
+    fn or_default_rate(&self) -> u32 {
+        match self {
+            SampleRate::Unlimited => super::DEFAULT_SAMPLE_RATE,
+            sr => sr.to_sr(),
+        }
+    }
 }
 
 impl Default for SampleRate {
@@ -164,6 +176,17 @@ pub struct Aac {
     mono: bool,
 }
 
+impl Aac {
+    pub fn new(bitrate: u32, sr: SampleRate, ltp: bool, mono: bool) -> Self {
+        Aac {
+            bitrate,
+            sr,
+            ltp,
+            mono,
+        }
+    }
+}
+
 impl AudioCodec for Aac {
     fn quality_args(&self) -> Vec<Cow<'static, str>> {
         let mut v = vec![];
@@ -190,7 +213,135 @@ impl AudioCodec for Aac {
         &["-strict", "-2", "-acodec", "aac"]
     }
 
+    fn sample_rate(&self) -> u32 {
+        self.sr.or_default_rate()
+    }
+
     fn bitrate(&self) -> u32 {
         self.bitrate as u32
     }
 }
+
+// FLAC codec - lossless
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum BitDepth {
+    #[serde(rename = "16bit")]
+    _16bit,
+    #[serde(rename = "24bit")]
+    _24bit,
+    #[serde(rename = "original")]
+    Original,
+}
+
+impl BitDepth {
+    fn to_bits(&self) -> Option<u32> {
+        match self {
+            BitDepth::_16bit => Some(16),
+            BitDepth::_24bit => Some(24),
+            BitDepth::Original => None,
+        }
+    }
+}
+
+impl Default for BitDepth {
+    fn default() -> Self {
+        BitDepth::Original
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Flac {
+    /// 0 fastest - 12 best compression, does not affect quality
+    compression_level: u8,
+    #[serde(default)]
+    sr: SampleRate,
+    #[serde(default)]
+    bit_depth: BitDepth,
+    #[serde(default)]
+    mono: bool,
+}
+
+impl AudioCodec for Flac {
+    fn quality_args(&self) -> Vec<Cow<'static, str>> {
+        let mut v = vec![];
+        if self.mono {
+            v.push("-ac".into());
+            v.push("1".into());
+        }
+        if self.sr != SampleRate::Unlimited {
+            v.push("-ar".into());
+            v.push(self.sr.to_sr().to_string().into())
+        }
+        match self.bit_depth {
+            BitDepth::_16bit => {
+                v.push("-sample_fmt".into());
+                v.push("s16".into());
+            }
+            BitDepth::_24bit => {
+                v.push("-sample_fmt".into());
+                v.push("s32".into());
+                v.push("-bits_per_raw_sample".into());
+                v.push("24".into());
+            }
+            BitDepth::Original => (),
+        }
+        v.push("-compression_level".into());
+        v.push(format!("{}", self.compression_level.min(12)).into());
+        v
+    }
+
+    fn codec_args(&self) -> &'static [&'static str] {
+        &["-acodec", "flac"]
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sr.or_default_rate()
+    }
+
+    /// just estimate, FLAC typically compresses to about 60% of PCM
+    fn bitrate(&self) -> u32 {
+        let sr = self.sr.or_default_rate();
+        let bits = self.bit_depth.to_bits().unwrap_or(16);
+        let channels = if self.mono { 1 } else { 2 };
+        sr * bits * channels / 1000 * 6 / 10
+    }
+}
+
+// Vorbis codec
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Vorbis {
+    /// VBR quality 0 worst - 10 best
+    quality: u8,
+    #[serde(default)]
+    mono: bool,
+}
+
+impl AudioCodec for Vorbis {
+    fn quality_args(&self) -> Vec<Cow<'static, str>> {
+        let mut v = vec![];
+        if self.mono {
+            v.push("-ac".into());
+            v.push("1".into());
+        }
+        v.push("-q:a".into());
+        v.push(format!("{}", self.quality.min(10)).into());
+        v
+    }
+
+    fn codec_args(&self) -> &'static [&'static str] {
+        &["-acodec", "libvorbis"]
+    }
+
+    /// nominal bitrate of quality level for stereo
+    fn bitrate(&self) -> u32 {
+        const BITRATES: [u32; 11] = [64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 500];
+        let b = BITRATES[self.quality.min(10) as usize];
+        if self.mono {
+            b / 2
+        } else {
+            b
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::config::get_config;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 4.0;
+
+fn parse_flag(v: &str) -> Option<bool> {
+    match v {
+        "1" | "true" | "on" => Some(true),
+        "0" | "false" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Request specific adjustments of transcoded audio, applied as ffmpeg audio filters
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AudioFilters {
+    /// overrides loudness normalization of transcoding profile
+    pub normalize: Option<bool>,
+    /// playback speed, pitch is preserved
+    pub speed: Option<f32>,
+    /// removes silent parts, so output is shorter and seek is not accurate
+    pub skip_silence: bool,
+}
+
+impl AudioFilters {
+    /// Takes filter parameters from query string parameters
+    pub fn from_params(params: &mut Option<HashMap<Cow<str>, Cow<str>>>) -> Self {
+        let normalize = params
+            .as_mut()
+            .and_then(|p| p.remove("norm"))
+            .and_then(|v| parse_flag(&v));
+        let speed = params
+            .as_mut()
+            .and_then(|p| p.remove("speed"))
+            .and_then(|v| match v.parse::<f32>() {
+                Ok(s) if (MIN_SPEED..=MAX_SPEED).contains(&s) => Some(s),
+                _ => {
+                    warn!("Invalid speed {}, ignoring", v);
+                    None
+                }
+            })
+            .filter(|s| (s - 1.0).abs() > f32::EPSILON);
+        let skip_silence = params
+            .as_mut()
+            .and_then(|p| p.remove("skip_silence"))
+            .and_then(|v| parse_flag(&v))
+            .unwrap_or(false);
+        AudioFilters {
+            normalize,
+            speed,
+            skip_silence,
+        }
+    }
+
+    pub fn silence_filter(&self) -> Option<String> {
+        if !self.skip_silence {
+            return None;
+        }
+        let cfg = &get_config().transcoding.silence;
+        Some(format!(
+            "silenceremove=start_periods=1:start_threshold={t}dB:\
+            stop_periods=-1:stop_duration={d}:stop_threshold={t}dB:stop_silence={k}",
+            t = cfg.threshold,
+            d = cfg.min_duration,
+            k = cfg.keep
+        ))
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed.unwrap_or(1.0)
+    }
+
+    /// atempo filter for speed change, single atempo is limited to 0.5 - 2.0, so they are chained
+    pub fn tempo_filter(&self) -> Option<String> {
+        let mut speed = self.speed?;
+        let mut chain = vec![];
+        while speed > 2.0 {
+            chain.push("atempo=2".to_string());
+            speed /= 2.0;
+        }
+        while speed < 0.5 {
+            chain.push("atempo=0.5".to_string());
+            speed /= 0.5;
+        }
+        chain.push(format!("atempo={}", speed));
+        Some(chain.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_from_params() {
+        let mut params: Option<HashMap<Cow<str>, Cow<str>>> =
+            Some(vec![("norm".into(), "off".into())].into_iter().collect());
+        let f = AudioFilters::from_params(&mut params);
+        assert_eq!(Some(false), f.normalize);
+        assert!(params.unwrap().is_empty());
+        assert_eq!(
+            AudioFilters::default(),
+            AudioFilters::from_params(&mut None)
+        );
+
+        let mut params: Option<HashMap<Cow<str>, Cow<str>>> =
+            Some(vec![("speed".into(), "1.5".into())].into_iter().collect());
+        let f = AudioFilters::from_params(&mut params);
+        assert_eq!(Some(1.5), f.speed);
+        let mut params: Option<HashMap<Cow<str>, Cow<str>>> =
+            Some(vec![("speed".into(), "10".into())].into_iter().collect());
+        assert_eq!(None, AudioFilters::from_params(&mut params).speed);
+    }
+
+    #[test]
+    fn test_tempo_filter() {
+        let f = |speed| AudioFilters {
+            speed,
+            ..Default::default()
+        };
+        assert_eq!(None, f(None).tempo_filter());
+        assert_eq!(Some("atempo=1.25".into()), f(Some(1.25)).tempo_filter());
+        assert_eq!(
+            Some("atempo=2,atempo=1.5".into()),
+            f(Some(3.0)).tempo_filter()
+        );
+        assert_eq!(3.0, f(Some(3.0)).speed());
+    }
+
+    #[test]
+    fn test_silence_filter() {
+        crate::config::init::init_default_config();
+        let mut params: Option<HashMap<Cow<str>, Cow<str>>> = Some(
+            vec![("skip_silence".into(), "1".into())]
+                .into_iter()
+                .collect(),
+        );
+        let f = AudioFilters::from_params(&mut params);
+        assert!(f.skip_silence);
+        assert_eq!(
+            Some(
+                "silenceremove=start_periods=1:start_threshold=-50dB:\
+                stop_periods=-1:stop_duration=1:stop_threshold=-50dB:stop_silence=0.3"
+                    .into()
+            ),
+            f.silence_filter()
+        );
+        assert_eq!(None, AudioFilters::default().silence_filter());
+    }
+}
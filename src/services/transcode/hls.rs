@@ -0,0 +1,111 @@
+use super::TimeSpan;
+use std::fmt::Write;
+
+pub const PLAYLIST_MIME: &str = "application/vnd.apple.mpegurl";
+
+/// HLS playlist of one audio file or chapter, split into segments of same length (except last one)
+pub struct HlsPlaylist {
+    // in ms
+    duration: u64,
+    segment: u64,
+}
+
+impl HlsPlaylist {
+    pub fn new(duration_ms: u64, segment_secs: u32) -> Self {
+        HlsPlaylist {
+            duration: duration_ms,
+            segment: u64::from(segment_secs) * 1000,
+        }
+    }
+
+    pub fn segments_count(&self) -> usize {
+        ((self.duration + self.segment - 1) / self.segment) as usize
+    }
+
+    fn segment_duration(&self, index: usize) -> u64 {
+        let start = index as u64 * self.segment;
+        self.segment.min(self.duration.saturating_sub(start))
+    }
+
+    /// Creates m3u8 playlist, `uri` creates segment URI from its index
+    pub fn render<F: Fn(usize) -> String>(&self, uri: F) -> String {
+        let mut p = String::new();
+        writeln!(p, "#EXTM3U").unwrap();
+        writeln!(p, "#EXT-X-VERSION:3").unwrap();
+        writeln!(p, "#EXT-X-TARGETDURATION:{}", self.segment / 1000).unwrap();
+        writeln!(p, "#EXT-X-MEDIA-SEQUENCE:0").unwrap();
+        writeln!(p, "#EXT-X-PLAYLIST-TYPE:VOD").unwrap();
+        for i in 0..self.segments_count() {
+            writeln!(
+                p,
+                "#EXTINF:{:.3},",
+                self.segment_duration(i) as f32 / 1000.0
+            )
+            .unwrap();
+            writeln!(p, "{}", uri(i)).unwrap();
+        }
+        writeln!(p, "#EXT-X-ENDLIST").unwrap();
+        p
+    }
+}
+
+/// Part of file for segment `index`, `span` is chapter or None for whole file,
+/// `speed` is playback speed (segment length is in output time, span in input time),
+/// returns None if segment is after end of chapter
+pub fn segment_span(
+    span: Option<TimeSpan>,
+    index: usize,
+    segment_secs: u32,
+    speed: f32,
+) -> Option<TimeSpan> {
+    let segment = (segment_secs as f32 * 1000.0 * speed).round() as u64;
+    let offset = index as u64 * segment;
+    let chapter_start = span.map(|s| s.start).unwrap_or(0);
+    let duration = match span.and_then(|s| s.duration) {
+        Some(d) if offset >= d => return None,
+        Some(d) => segment.min(d - offset),
+        // file end is not known here, but ffmpeg will just stop at the end of file
+        None => segment,
+    };
+    Some(TimeSpan {
+        start: chapter_start + offset,
+        duration: Some(duration),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playlist() {
+        let p = HlsPlaylist::new(25_500, 10);
+        assert_eq!(3, p.segments_count());
+        let m3u = p.render(|i| format!("file.mp3?seg={}", i));
+        let lines: Vec<_> = m3u.lines().collect();
+        assert_eq!("#EXTM3U", lines[0]);
+        assert_eq!("#EXT-X-TARGETDURATION:10", lines[2]);
+        assert_eq!("#EXTINF:10.000,", lines[5]);
+        assert_eq!("file.mp3?seg=0", lines[6]);
+        assert_eq!("#EXTINF:5.500,", lines[9]);
+        assert_eq!("file.mp3?seg=2", lines[10]);
+        assert_eq!("#EXT-X-ENDLIST", lines[11]);
+        assert_eq!(2, HlsPlaylist::new(20_000, 10).segments_count());
+    }
+
+    #[test]
+    fn test_segment_span() {
+        let s = segment_span(None, 2, 10, 1.0).unwrap();
+        assert_eq!((20_000, Some(10_000)), (s.start, s.duration));
+        let chapter = Some(TimeSpan {
+            start: 60_000,
+            duration: Some(25_000),
+        });
+        let s = segment_span(chapter, 2, 10, 1.0).unwrap();
+        assert_eq!((80_000, Some(5_000)), (s.start, s.duration));
+        assert!(segment_span(chapter, 3, 10, 1.0).is_none());
+        let s = segment_span(chapter, 1, 10, 1.5).unwrap();
+        assert_eq!((75_000, Some(10_000)), (s.start, s.duration));
+        assert!(segment_span(chapter, 2, 10, 1.5).is_none());
+    }
+}
@@ -0,0 +1,286 @@
+use super::super::Counter;
+use crate::config::get_config;
+use crate::error::{bail, Error, Result};
+use crate::util::{file_version, write_atomically};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::process::Command;
+
+lazy_static! {
+    static ref MEASUREMENTS: Measurements =
+        Measurements::load(&get_config().transcoding.loudness.measurements_file);
+}
+
+/// Loudness of file as measured by first pass of ffmpeg loudnorm filter
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Measurement {
+    /// integrated loudness in LUFS
+    pub integrated: f32,
+    /// true peak in dBTP
+    pub true_peak: f32,
+}
+
+impl Measurement {
+    /// Static gain (in dB) to reach target loudness without exceeding max. true peak
+    fn gain(&self, target: f32, max_true_peak: f32) -> f32 {
+        (target - self.integrated).min(max_true_peak - self.true_peak)
+    }
+}
+
+/// Measurement is valid only for same version of file (size and modification time)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct VersionedMeasurement {
+    #[serde(default)]
+    version: String,
+    #[serde(flatten)]
+    measurement: Measurement,
+}
+
+struct Measurements {
+    values: RwLock<HashMap<PathBuf, VersionedMeasurement>>,
+    pending: Mutex<HashSet<PathBuf>>,
+    // serializes writes of file, so older values cannot overwrite newer
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl Measurements {
+    fn load(fname: &Path) -> Self {
+        let values = match fs::File::open(fname) {
+            Ok(f) => serde_json::from_reader(f).unwrap_or_else(|e| {
+                error!("Cannot read loudness measurements file: {}", e);
+                HashMap::new()
+            }),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("Cannot open loudness measurements file: {}", e)
+                }
+                HashMap::new()
+            }
+        };
+        Measurements {
+            values: RwLock::new(values),
+            pending: Mutex::new(HashSet::new()),
+            saving: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn get(&self, file: &Path, version: &str) -> Option<Measurement> {
+        self.values
+            .read()
+            .expect("Poisoned lock")
+            .get(file)
+            .filter(|m| m.version == version)
+            .map(|m| m.measurement)
+    }
+
+    async fn insert(&self, file: PathBuf, version: String, measurement: Measurement) -> Result<()> {
+        let _saving = self.saving.lock().await;
+        let data = {
+            let mut values = self.values.write().expect("Poisoned lock");
+            values.insert(
+                file,
+                VersionedMeasurement {
+                    version,
+                    measurement,
+                },
+            );
+            serde_json::to_vec(&*values)?
+        };
+        let fname = &get_config().transcoding.loudness.measurements_file;
+        tokio::task::spawn_blocking(move || write_atomically(fname, &data)).await??;
+        Ok(())
+    }
+
+    // measurement decodes whole file, so it runs only in free transcoding slot
+    fn measure_later(&'static self, file: &Path, counter: &Counter) {
+        if !self
+            .pending
+            .lock()
+            .expect("Poisoned lock")
+            .insert(file.to_owned())
+        {
+            return;
+        }
+        let file = file.to_owned();
+        let counter = counter.clone();
+        tokio::spawn(async move {
+            self.measure_if_needed(&file, &counter).await;
+            self.pending.lock().expect("Poisoned lock").remove(&file);
+        });
+    }
+
+    async fn measure_if_needed(&self, file: &Path, counter: &Counter) {
+        let version = match tokio::fs::metadata(file).await {
+            Ok(meta) => file_version(&meta),
+            Err(e) => {
+                error!("Cannot get metadata of {:?}: {}", file, e);
+                return;
+            }
+        };
+        if self.get(file, &version).is_some() {
+            return;
+        }
+        if counter.try_acquire_background().is_none() {
+            debug!(
+                "No free transcoding slot for loudness measurement of {:?}",
+                file
+            );
+            return;
+        }
+        let max_runtime =
+            Duration::from_secs(u64::from(get_config().transcoding.max_runtime_hours * 3600));
+        let res = tokio::time::timeout(max_runtime, measure(file)).await;
+        counter.release();
+        match res {
+            Ok(Ok(m)) => {
+                debug!("Measured loudness of {:?}: {:?}", file, m);
+                if let Err(e) = self.insert(file.to_owned(), version, m).await {
+                    error!("Cannot save loudness measurements: {}", e)
+                }
+            }
+            Ok(Err(e)) => error!("Loudness measurement of {:?} failed: {}", file, e),
+            Err(_) => error!("Loudness measurement of {:?} timed out", file),
+        }
+    }
+}
+
+fn loudnorm_filter() -> String {
+    let cfg = &get_config().transcoding.loudness;
+    format!(
+        "loudnorm=I={}:TP={}:LRA={}",
+        cfg.target, cfg.true_peak, cfg.range
+    )
+}
+
+/// ffmpeg filter normalizing loudness of the file - static gain if this version of file
+/// (given by its `meta`) was already measured in two pass mode, otherwise dynamic loudnorm filter,
+/// which is followed by resampling to `sample_rate` (as loudnorm upsamples to 192kHz)
+pub fn normalization_filter(file: &Path, meta: Option<&fs::Metadata>, sample_rate: u32) -> String {
+    let cfg = &get_config().transcoding.loudness;
+    if cfg.two_pass {
+        if let Some(m) = meta.and_then(|meta| MEASUREMENTS.get(file, &file_version(meta))) {
+            return format!("volume={:.2}dB", m.gain(cfg.target, cfg.true_peak));
+        }
+    }
+    format!("{},aresample={}", loudnorm_filter(), sample_rate)
+}
+
+/// Measures loudness of file in background, if two pass normalization is enabled
+/// and file is not measured yet
+pub fn measure_later(file: &Path, counter: &Counter) {
+    if get_config().transcoding.loudness.two_pass {
+        MEASUREMENTS.measure_later(file, counter)
+    }
+}
+
+async fn measure(file: &Path) -> Result<Measurement> {
+    let output = Command::new("ffmpeg")
+        .args(&["-nostdin", "-hide_banner", "-i"])
+        .arg(file)
+        .args(&["-map", "a", "-af"])
+        .arg(format!("{}:print_format=json", loudnorm_filter()))
+        .args(&["-f", "null", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        // process is killed, when measurement times out
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        bail!("ffmpeg failed with code {:?}", output.status.code());
+    }
+    parse_measurement(&String::from_utf8_lossy(&output.stderr))
+}
+
+// loudnorm prints json with measured values at the end of output
+fn parse_measurement(output: &str) -> Result<Measurement> {
+    #[derive(Deserialize)]
+    struct LoudnormOutput {
+        input_i: String,
+        input_tp: String,
+    }
+
+    let json = match (output.rfind('{'), output.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &output[start..=end],
+        _ => bail!("Missing loudnorm output"),
+    };
+    let out: LoudnormOutput = serde_json::from_str(json).map_err(Error::new)?;
+    let integrated: f32 = out.input_i.parse().map_err(Error::new)?;
+    let true_peak: f32 = out.input_tp.parse().map_err(Error::new)?;
+    if !integrated.is_finite() || !true_peak.is_finite() {
+        bail!("Cannot measure loudness of silence");
+    }
+    Ok(Measurement {
+        integrated,
+        true_peak,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measurement_version() {
+        // measurements from older version of file have no version
+        let values = serde_json::from_str(
+            r#"{"test_data/01-file.mp3": {"integrated": -20.5, "true_peak": -1.5}}"#,
+        )
+        .unwrap();
+        let measurements = Measurements {
+            values: RwLock::new(values),
+            pending: Mutex::new(HashSet::new()),
+            saving: tokio::sync::Mutex::new(()),
+        };
+        let file = Path::new("test_data/01-file.mp3");
+        let version = file_version(&fs::metadata(file).unwrap());
+        assert!(measurements.get(file, &version).is_none());
+        let m = Measurement {
+            integrated: -18.0,
+            true_peak: -2.0,
+        };
+        measurements.values.write().unwrap().insert(
+            file.to_owned(),
+            VersionedMeasurement {
+                version: version.clone(),
+                measurement: m,
+            },
+        );
+        assert_eq!(Some(m), measurements.get(file, &version));
+        assert!(measurements.get(file, "1|1").is_none());
+    }
+
+    #[test]
+    fn test_parse_measurement() {
+        let output = r#"[Parsed_loudnorm_0 @ 0x5581e2a1a0c0]
+{
+	"input_i" : "-23.54",
+	"input_tp" : "-4.20",
+	"input_lra" : "5.60",
+	"input_thresh" : "-34.01",
+	"output_i" : "-16.20",
+	"output_tp" : "-1.50",
+	"output_lra" : "4.30",
+	"output_thresh" : "-26.63",
+	"normalization_type" : "dynamic",
+	"target_offset" : "0.20"
+}
+"#;
+        let m = parse_measurement(output).unwrap();
+        assert_eq!(-23.54, m.integrated);
+        assert_eq!(-4.2, m.true_peak);
+        // limited by true peak
+        assert!((m.gain(-16.0, -1.5) - 2.7).abs() < 0.001);
+        assert!((m.gain(-20.0, -1.5) - 2.54).abs() < 0.001);
+
+        let silence = output.replace("-23.54", "-inf");
+        assert!(parse_measurement(&silence).is_err());
+        assert!(parse_measurement("no json").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use self::codecs::*;
+use self::filters::AudioFilters;
 use super::subs::ChunkStream;
 use super::types::AudioFormat;
 use crate::config::get_config;
@@ -11,7 +12,6 @@ use std::fmt::Debug;
 #[cfg(feature = "transcoding-cache")]
 use std::pin::Pin;
 use std::process::Stdio;
-use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 use tokio::process::{ChildStdout, Command};
 use tokio::time::timeout;
@@ -19,12 +19,26 @@ use tokio::time::timeout;
 #[cfg(feature = "transcoding-cache")]
 pub mod cache;
 pub mod codecs;
+pub mod filters;
+pub mod hls;
+mod loudness;
+pub mod negotiate;
+#[cfg(feature = "transcoding-cache")]
+pub mod pretranscode;
+pub mod queue;
+
+pub const HLS_SEGMENT_MIME: &str = "video/mp2t";
+const DEFAULT_SAMPLE_RATE: u32 = 44_100;
 
 pub trait AudioCodec {
     fn quality_args(&self) -> Vec<Cow<'static, str>>;
     fn codec_args(&self) -> &'static [&'static str];
     /// in kbps
     fn bitrate(&self) -> u32;
+    /// sample rate of output in Hz, used when filter changes rate of input
+    fn sample_rate(&self) -> u32 {
+        DEFAULT_SAMPLE_RATE
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -34,6 +48,8 @@ pub enum TranscodingFormat {
     OpusInWebm(Opus),
     Mp3(Mp3),
     AacInAdts(Aac),
+    VorbisInOgg(Vorbis),
+    Flac(Flac),
     Remux,
 }
 
@@ -60,6 +76,8 @@ impl TranscodingFormat {
             TranscodingFormat::OpusInWebm(args) => targs!(args, "webm"),
             TranscodingFormat::Mp3(args) => targs!(args, "mp3"),
             TranscodingFormat::AacInAdts(args) => targs!(args, "adts"),
+            TranscodingFormat::VorbisInOgg(args) => targs!(args, "ogg"),
+            TranscodingFormat::Flac(args) => targs!(args, "flac"),
             TranscodingFormat::Remux => TranscodingArgs {
                 format: "",
                 codec_args: &[],
@@ -74,29 +92,58 @@ impl TranscodingFormat {
             TranscodingFormat::OpusInWebm(args) => args.bitrate(),
             TranscodingFormat::Mp3(args) => args.bitrate(),
             TranscodingFormat::AacInAdts(args) => args.bitrate(),
+            TranscodingFormat::VorbisInOgg(args) => args.bitrate(),
+            TranscodingFormat::Flac(args) => args.bitrate(),
             TranscodingFormat::Remux => 0,
         }
     }
 
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            TranscodingFormat::OpusInOgg(args) => args.sample_rate(),
+            TranscodingFormat::OpusInWebm(args) => args.sample_rate(),
+            TranscodingFormat::Mp3(args) => args.sample_rate(),
+            TranscodingFormat::AacInAdts(args) => args.sample_rate(),
+            TranscodingFormat::VorbisInOgg(args) => args.sample_rate(),
+            TranscodingFormat::Flac(args) => args.sample_rate(),
+            TranscodingFormat::Remux => DEFAULT_SAMPLE_RATE,
+        }
+    }
+
     pub fn format_name(&self) -> &'static str {
         match self {
             TranscodingFormat::OpusInOgg(_) => "opus-in-ogg",
             TranscodingFormat::OpusInWebm(_) => "opus-in-webm",
             TranscodingFormat::Mp3(_) => "mp3",
             TranscodingFormat::AacInAdts(_) => "aac-in-adts",
+            TranscodingFormat::VorbisInOgg(_) => "vorbis-in-ogg",
+            TranscodingFormat::Flac(_) => "flac",
             TranscodingFormat::Remux => "remux",
         }
     }
 
-    pub fn mime(&self) -> Mime {
-        let m = match self {
+    fn mime_str(&self) -> &'static str {
+        match self {
             TranscodingFormat::OpusInOgg(_) => "audio/ogg",
             TranscodingFormat::OpusInWebm(_) => "audio/webm",
             TranscodingFormat::Mp3(_) => "audio/mpeg",
             TranscodingFormat::AacInAdts(_) => "audio/aac",
+            TranscodingFormat::VorbisInOgg(_) => "audio/ogg",
+            TranscodingFormat::Flac(_) => "audio/flac",
             TranscodingFormat::Remux => unreachable!("mime for Remux should never be used!"),
-        };
-        m.parse().unwrap()
+        }
+    }
+
+    pub fn mime(&self) -> Mime {
+        self.mime_str().parse().unwrap()
+    }
+
+    /// Codec can be played from HLS segments (in MPEG-TS) natively on Apple devices
+    pub fn hls_capable(&self) -> bool {
+        matches!(
+            self,
+            TranscodingFormat::AacInAdts(_) | TranscodingFormat::Mp3(_)
+        )
     }
 }
 
@@ -113,6 +160,30 @@ impl TranscodingFormat {
                 TranscodingFormat::OpusInOgg(Opus::new(64, 10, Bandwidth::FullBand, false))
             }
             QualityLevel::Passthrough => TranscodingFormat::Remux,
+            QualityLevel::Hls => {
+                TranscodingFormat::AacInAdts(Aac::new(64, SampleRate::Unlimited, false, false))
+            }
+            QualityLevel::Profile(_) => TranscodingFormat::default_level(QualityLevel::Medium),
+        }
+    }
+}
+
+/// Transcoding format with options applied before encoding, as configured for quality level
+/// or user defined profile
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TranscodingProfile {
+    #[serde(flatten)]
+    pub format: TranscodingFormat,
+    /// EBU R128 loudness normalization
+    #[serde(default)]
+    pub loudnorm: bool,
+}
+
+impl From<TranscodingFormat> for TranscodingProfile {
+    fn from(format: TranscodingFormat) -> Self {
+        TranscodingProfile {
+            format,
+            loudnorm: false,
         }
     }
 }
@@ -123,6 +194,10 @@ pub enum QualityLevel {
     Medium,
     High,
     Passthrough,
+    /// Default transcoding of HLS segments, when client does not select any
+    Hls,
+    /// User defined transcoding profile from config, name is borrowed from (static) config
+    Profile(&'static str),
 }
 
 impl QualityLevel {
@@ -137,7 +212,17 @@ impl QualityLevel {
         }
     }
 
-    #[allow(dead_code)]
+    /// Parses `trans` parameter - either letter of predefined level or name of profile
+    pub fn from_param<T: AsRef<str>>(p: &T) -> Option<Self> {
+        QualityLevel::from_letter(p).or_else(|| {
+            get_config()
+                .transcoding
+                .profiles
+                .get_key_value(p.as_ref())
+                .map(|(name, _)| QualityLevel::Profile(name.as_str()))
+        })
+    }
+
     pub fn to_letter(self) -> &'static str {
         use self::QualityLevel::*;
         match self {
@@ -145,6 +230,8 @@ impl QualityLevel {
             Medium => "m",
             High => "h",
             Passthrough => "p",
+            Hls => "hls",
+            Profile(name) => name,
         }
     }
 }
@@ -185,6 +272,10 @@ impl std::fmt::Display for TimeSpan {
 #[derive(Clone, Debug)]
 pub struct Transcoder {
     quality: TranscodingFormat,
+    loudnorm: bool,
+    // if set, output is HLS segment (in MPEG-TS) starting at this time (in secs) of playlist
+    hls_offset: Option<f32>,
+    filters: AudioFilters,
 }
 
 #[cfg(feature = "transcoding-cache")]
@@ -195,7 +286,107 @@ type TranscodedFuture = Pin<Box<dyn Future<Output = Result<TranscodedStream>> +
 
 impl Transcoder {
     pub fn new(quality: TranscodingFormat) -> Self {
-        Transcoder { quality }
+        Transcoder {
+            quality,
+            loudnorm: false,
+            hls_offset: None,
+            filters: AudioFilters::default(),
+        }
+    }
+
+    pub fn from_profile(profile: TranscodingProfile) -> Self {
+        Transcoder {
+            loudnorm: profile.loudnorm,
+            ..Transcoder::new(profile.format)
+        }
+    }
+
+    pub fn with_filters(mut self, filters: AudioFilters) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// output is shorter then original file, so its duration is not known in advance
+    pub fn skips_silence(&self) -> bool {
+        self.filters.skip_silence
+    }
+
+    fn normalize(&self) -> bool {
+        self.filters.normalize.unwrap_or(self.loudnorm)
+    }
+
+    fn filter_chain(&self, file: &OsStr) -> Vec<String> {
+        let mut filters = vec![];
+        if let Some(silence) = self.filters.silence_filter() {
+            filters.push(silence);
+        }
+        if self.normalize() {
+            let file = std::path::Path::new(file);
+            let meta = std::fs::metadata(file).ok();
+            filters.push(loudness::normalization_filter(
+                file,
+                meta.as_ref(),
+                self.quality.sample_rate(),
+            ));
+        }
+        if let Some(tempo) = self.filters.tempo_filter() {
+            filters.push(tempo);
+        }
+        filters
+    }
+
+    /// Transcodes into HLS segment, which starts at `offset` seconds of playlist
+    pub fn hls_segment(mut self, offset: f32) -> Self {
+        self.hls_offset = Some(offset);
+        self
+    }
+
+    #[cfg(feature = "transcoding-cache")]
+    pub async fn cache_key<P: AsRef<std::path::Path>>(
+        &self,
+        file: P,
+        quality: QualityLevel,
+        span: Option<TimeSpan>,
+    ) -> String {
+        let meta = tokio::fs::metadata(file.as_ref())
+            .await
+            .map_err(|e| warn!("Cannot get metadata of {:?}: {}", file.as_ref(), e))
+            .ok();
+        let mut key = cache::cache_key(&file, quality, span);
+        if self.normalize() {
+            key.push_str("/norm");
+        }
+        if let Some(speed) = self.filters.speed {
+            key.push_str(&format!("/speed={}", speed));
+        }
+        if self.filters.skip_silence {
+            key.push_str("/skip_silence");
+        }
+        if self.hls_offset.is_some() {
+            key.push_str("/hls");
+        }
+        let fingerprint = self.fingerprint(file.as_ref(), meta.as_ref());
+        cache::versioned_key(key, meta.as_ref(), &fingerprint)
+    }
+
+    // all parameters, which influence output of transcoding
+    #[cfg(feature = "transcoding-cache")]
+    fn fingerprint(&self, file: &std::path::Path, meta: Option<&std::fs::Metadata>) -> String {
+        let cfg = &get_config().transcoding;
+        let mut fp = format!("{:?}", self.quality);
+        if self.normalize() {
+            // dynamic and measured (static gain) normalization are different versions of entry
+            fp.push('|');
+            fp.push_str(&loudness::normalization_filter(
+                file,
+                meta,
+                self.quality.sample_rate(),
+            ));
+        }
+        if self.filters.skip_silence {
+            fp.push_str(&format!("|{:?}", cfg.silence));
+        }
+        fp
     }
 
     fn base_ffmpeg(&self, seek: Option<f32>, span: Option<TimeSpan>) -> Command {
@@ -207,7 +398,12 @@ impl Transcoder {
         let start = offset as f32 / 1000.0 + seek;
 
         if start > 0.0 {
-            cmd.args(&["-accurate_seek", "-ss"]);
+            if self.filters.skip_silence {
+                // position in output cannot be mapped to input, so seek is just approximate
+                cmd.args(&["-noaccurate_seek", "-ss"]);
+            } else {
+                cmd.args(&["-accurate_seek", "-ss"]);
+            }
             let time_spec = format!("{:3}", start);
             cmd.arg(time_spec);
         }
@@ -242,14 +438,30 @@ impl Transcoder {
         seek: Option<f32>,
         span: Option<TimeSpan>,
     ) -> Command {
+        // seek is position in output, which can be sped up, but ffmpeg seeks in input,
+        // with skipped silence seek is already position in input
+        let seek = if self.filters.skip_silence {
+            seek
+        } else {
+            seek.map(|s| s * self.filters.speed())
+        };
         let mut cmd = self.base_ffmpeg(seek, span);
         let targs = self.quality.args();
+        let filters = self.filter_chain(file.as_ref());
         self.input_file_args(&mut cmd, file);
+        if !filters.is_empty() {
+            cmd.arg("-af").arg(filters.join(","));
+        }
         cmd.args(targs.codec_args)
-            .args(targs.quality_args.iter().map(|i| i.as_ref()))
-            .arg("-f")
-            .arg(targs.format)
-            .arg("pipe:1")
+            .args(targs.quality_args.iter().map(|i| i.as_ref()));
+        match self.hls_offset {
+            Some(offset) => cmd
+                .arg("-output_ts_offset")
+                .arg(format!("{:3}", offset))
+                .args(&["-f", "mpegts"]),
+            None => cmd.arg("-f").arg(targs.format),
+        };
+        cmd.arg("pipe:1")
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::inherit());
@@ -282,15 +494,19 @@ impl Transcoder {
         cmd
     }
 
-    pub fn transcoding_params(&self) -> String {
+    pub fn transcoding_params(&self, level: QualityLevel) -> String {
         format!(
-            "codec={}; bitrate={}",
+            "codec={}; bitrate={}; profile={}",
             self.quality.format_name(),
             self.quality.bitrate(),
+            level.to_letter(),
         )
     }
 
     pub fn transcoded_mime(&self) -> Mime {
+        if self.hls_offset.is_some() {
+            return HLS_SEGMENT_MIME.parse().unwrap();
+        }
         self.quality.mime()
     }
 
@@ -321,9 +537,8 @@ impl Transcoder {
         counter: super::Counter,
         quality: QualityLevel,
     ) -> TranscodedFuture {
-        use self::cache::{cache_key, get_cache};
+        use self::cache::get_cache;
         use futures::channel::mpsc;
-        use std::io;
 
         let is_transcoded = matches!(file, AudioFilePath::Transcoded(_));
         if is_transcoded
@@ -341,65 +556,82 @@ impl Transcoder {
             ));
         }
 
-        //TODO: this is ugly -  unify either we will use Path or OsStr!
-        let key = cache_key(file.as_ref().as_ref(), quality, span);
-        let fut = get_cache().add(key).then(move |res| match res {
-            Err(e) => {
-                warn!("Cannot create cache entry: {}", e);
-                future::ready(
+        let fut = async move {
+            //TODO: this is ugly -  unify either we will use Path or OsStr!
+            let path = std::path::PathBuf::from(file.as_ref());
+            let key = self.cache_key(&path, quality, span).await;
+            match get_cache().add(key).await {
+                Err(e) => {
+                    warn!("Cannot create cache entry: {}", e);
                     self.transcode_inner(file, seek, span, counter)
                         .map(|(stream, f)| {
                             tokio::spawn(f);
                             Box::pin(stream) as TranscodedStream
-                        }),
-                )
-            }
-            Ok((cache_file, cache_finish)) => future::ready(
-                self.transcode_inner(file, seek, span, counter)
-                    .map(|(mut stream, f)| {
-                        tokio::spawn(f.then(|res| {
-                            match res {
-                                Ok(()) => cache_finish
-                                    .commit()
-                                    .map_err(|e| error!("Error in cache: {}", e))
-                                    .and_then(|_| {
-                                        debug!("Added to cache");
-                                        if get_config().transcoding.cache.save_often {
-                                            tokio::spawn(get_cache().save_index().map_err(|e| {
-                                                error!("Error when saving cache index: {}", e)
-                                            }));
-                                        }
-                                        future::ok(())
-                                    })
-                                    .boxed(),
-
-                                Err(()) => cache_finish
-                                    .roll_back()
-                                    .map_err(|e| error!("Error in cache: {}", e))
-                                    .boxed(),
-                            }
-                        }));
-                        let cache_sink = tokio_util::codec::FramedWrite::new(
-                            cache_file,
-                            self::vec_codec::VecEncoder,
-                        );
+                        })
+                }
+                Ok((cache_file, cache_finish)) => self
+                    .transcode_inner(file, seek, span, counter)
+                    .map(|(stream, process)| {
                         let (tx, rx) = mpsc::channel(64);
-                        let mut tx = cache_sink
-                            .fanout(tx.sink_map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
-                        let f = async move {
-                            let done = tx.send_all(&mut stream).await;
-                            if let Err(e) = done {
-                                warn!("Error in channel: {}", e)
-                            }
-                        };
-                        tokio::spawn(f);
+                        tokio::spawn(tee_to_cache(stream, process, cache_file, cache_finish, tx));
                         Box::pin(rx.map(Ok)) as TranscodedStream
                     }),
-            ),
-        });
+            }
+        };
         Box::pin(fut)
     }
 
+    /// Transcodes whole file (or its part) directly into transcoding cache,
+    /// transcoding slot must be already reserved in `counter`
+    #[cfg(feature = "transcoding-cache")]
+    pub async fn transcode_to_cache(
+        self,
+        file: std::path::PathBuf,
+        span: Option<TimeSpan>,
+        counter: super::Counter,
+        quality: QualityLevel,
+    ) -> Result<()> {
+        use self::cache::get_cache;
+        use tokio::io::AsyncWriteExt;
+
+        let key = self.cache_key(&file, quality, span).await;
+        let (mut cache_file, cache_finish) = match get_cache().add(key).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                counter.release();
+                return Err(e.into());
+            }
+        };
+        let (mut stream, process) =
+            match self.transcode_inner(AudioFilePath::Original(file), None, span, counter) {
+                Ok(r) => r,
+                Err(e) => {
+                    cache_finish.roll_back().await?;
+                    return Err(e);
+                }
+            };
+        let copied: std::io::Result<()> = async {
+            while let Some(chunk) = stream.next().await {
+                cache_file.write_all(&chunk?).await?;
+            }
+            cache_file.flush().await
+        }
+        .await;
+        // closes pipe, so process is not blocked, if copying failed
+        drop(stream);
+        let finished = process.await;
+        match (copied, finished) {
+            (Ok(()), Ok(())) => cache_finish.commit().await.map_err(From::from),
+            (copied, _) => {
+                cache_finish.roll_back().await?;
+                match copied {
+                    Err(e) => Err(e.into()),
+                    Ok(()) => bail!("Transcoding process failed"),
+                }
+            }
+        }
+    }
+
     fn transcode_inner<S: AsRef<OsStr> + Debug + Send + 'static>(
         &self,
         file: AudioFilePath<S>,
@@ -417,7 +649,12 @@ impl Transcoder {
             (AudioFilePath::Transcoded(_), _) => {
                 self.build_remux_command(file.as_ref(), seek, span, true)
             }
-            _ => self.build_command(file.as_ref(), seek, span),
+            _ => {
+                if self.normalize() {
+                    loudness::measure_later(std::path::Path::new(file.as_ref()), &counter);
+                }
+                self.build_command(file.as_ref(), seek, span)
+            }
         };
         match cmd.spawn() {
             Ok(mut child) => {
@@ -435,7 +672,7 @@ impl Transcoder {
                         )
                         .await;
 
-                        counter.fetch_sub(1, Ordering::SeqCst);
+                        counter.release();
                         match res {
                             Ok(res) => match res {
                                 Ok(res) => {
@@ -476,13 +713,13 @@ impl Transcoder {
                     };
                     Ok((stream, fut))
                 } else {
-                    counter.fetch_sub(1, Ordering::SeqCst);
+                    counter.release();
                     error!("Cannot get child process stdout");
                     bail!("Cannot get child process stdout");
                 }
             }
             Err(e) => {
-                counter.fetch_sub(1, Ordering::SeqCst);
+                counter.release();
                 error!("Cannot spawn child process: {:?}", e);
                 bail!("Cannot spawn child");
             }
@@ -490,6 +727,63 @@ impl Transcoder {
     }
 }
 
+/// Writes transcoded stream into cache entry and also sends it to client - if client disconnects,
+/// transcoding continues only if other clients read the entry as it's written (see `Cache::tail`)
+#[cfg(feature = "transcoding-cache")]
+async fn tee_to_cache(
+    mut stream: ChunkStream<ChildStdout>,
+    process: impl Future<Output = Result<(), ()>>,
+    mut cache_file: tokio::fs::File,
+    cache_finish: simple_file_cache::Finisher,
+    mut client: futures::channel::mpsc::Sender<Vec<u8>>,
+) {
+    use self::cache::get_cache;
+    use std::io;
+    use tokio::io::AsyncWriteExt;
+
+    let copied: io::Result<()> = async {
+        let mut client_connected = true;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            cache_file.write_all(&chunk).await?;
+            if client_connected && client.send(chunk).await.is_err() {
+                debug!("Client disconnected from transcoded stream");
+                client_connected = false;
+            }
+            if !client_connected && !cache_finish.has_tail_readers() {
+                return Err(io::Error::new(io::ErrorKind::Other, "client disconnected"));
+            }
+        }
+        cache_file.flush().await
+    }
+    .await;
+    // closes pipe, so process is not blocked, if copying failed
+    drop(stream);
+    drop(client);
+    let finished = process.await;
+    match (copied, finished) {
+        (Ok(()), Ok(())) => match cache_finish.commit().await {
+            Ok(()) => {
+                debug!("Added to cache");
+                if get_config().transcoding.cache.save_often {
+                    if let Err(e) = get_cache().save_index().await {
+                        error!("Error when saving cache index: {}", e)
+                    }
+                }
+            }
+            Err(e) => error!("Error in cache: {}", e),
+        },
+        (copied, _) => {
+            if let Err(e) = copied {
+                debug!("Transcoded stream is not cached: {}", e)
+            }
+            if let Err(e) = cache_finish.roll_back().await {
+                error!("Error in cache: {}", e)
+            }
+        }
+    }
+}
+
 pub fn guess_format<P: AsRef<std::path::Path>>(p: P) -> AudioFormat {
     const DEFAULT_FORMAT: (&str, &str) = ("matroska", "audio/x-matroska"); // matroska is fairly universal, so it's good chance that audio stream will fit in
     let t = match p.as_ref().extension() {
@@ -500,6 +794,8 @@ pub fn guess_format<P: AsRef<std::path::Path>>(p: P) -> AudioFormat {
                 "mp3" => ("mp3", "audio/mpeg"),
                 "m4b" => ("adts", "audio/aac"), // we cannot create mp4 container in pipe
                 "m4a" => ("adts", "audion/aac"),
+                "ogg" | "oga" => ("ogg", "audio/ogg"),
+                "flac" => ("flac", "audio/flac"),
                 _ => DEFAULT_FORMAT,
             }
         }
@@ -511,25 +807,6 @@ pub fn guess_format<P: AsRef<std::path::Path>>(p: P) -> AudioFormat {
     }
 }
 
-#[cfg(feature = "transcoding-cache")]
-mod vec_codec {
-    use bytes::BufMut;
-    use std::io;
-    use tokio_util::codec::Encoder;
-
-    pub struct VecEncoder;
-
-    impl Encoder<Vec<u8>> for VecEncoder {
-        type Error = io::Error;
-
-        fn encode(&mut self, data: Vec<u8>, buf: &mut bytes::BytesMut) -> Result<(), Self::Error> {
-            buf.reserve(data.len());
-            buf.put(&data[..]);
-            Ok(())
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::super::audio_meta::{get_audio_properties, MediaInfo};
@@ -588,6 +865,83 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_flac_vorbis_args() {
+        let flac: TranscodingFormat =
+            serde_yaml::from_str("flac:\n  compression_level: 5\n  bit_depth: 24bit").unwrap();
+        let args = flac.args();
+        assert_eq!("flac", args.format);
+        assert_eq!(
+            vec![
+                "-sample_fmt",
+                "s32",
+                "-bits_per_raw_sample",
+                "24",
+                "-compression_level",
+                "5"
+            ],
+            args.quality_args
+        );
+
+        let vorbis: TranscodingFormat =
+            serde_yaml::from_str("vorbis-in-ogg:\n  quality: 5").unwrap();
+        let args = vorbis.args();
+        assert_eq!("ogg", args.format);
+        assert_eq!(vec!["-q:a", "5"], args.quality_args);
+        assert_eq!(160, vorbis.bitrate());
+        assert_eq!("audio/ogg", vorbis.mime().as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_loudnorm_args() {
+        crate::config::init::init_default_config();
+        let profile: TranscodingProfile = serde_yaml::from_str(
+            "opus-in-ogg:\n  bitrate: 24\n  compression_level: 10\n  cutoff: WideBand\nloudnorm: true",
+        )
+        .unwrap();
+        assert!(profile.loudnorm);
+        assert_eq!(24, profile.format.bitrate());
+        let t = Transcoder::from_profile(profile);
+        let cmd = format!("{:?}", t.build_command("01-file.mp3", None, None));
+        assert!(cmd.contains("\"-af\" \"loudnorm=I=-16:TP=-1.5:LRA=11,aresample=48000\""));
+        #[cfg(feature = "transcoding-cache")]
+        assert!(t
+            .cache_key("01-file.mp3", QualityLevel::Low, None)
+            .await
+            .contains("/norm#"));
+
+        let t = t.with_filters(AudioFilters {
+            normalize: Some(false),
+            ..Default::default()
+        });
+        let cmd = format!("{:?}", t.build_command("01-file.mp3", None, None));
+        assert!(!cmd.contains("loudnorm"));
+
+        let plain: TranscodingProfile =
+            serde_yaml::from_str("mp3:\n  bitrate: 64\n  compression_level: 5").unwrap();
+        assert!(!plain.loudnorm);
+    }
+
+    #[tokio::test]
+    async fn test_speed_args() {
+        crate::config::init::init_default_config();
+        let t = Transcoder::new(TranscodingFormat::default_level(QualityLevel::Low)).with_filters(
+            AudioFilters {
+                speed: Some(1.5),
+                ..Default::default()
+            },
+        );
+        let cmd = format!("{:?}", t.build_command("01-file.mp3", Some(10.0), None));
+        assert!(cmd.contains("\"-af\" \"atempo=1.5\""));
+        // seek is converted to input time
+        assert!(cmd.contains("\"-ss\" \" 15\""));
+        #[cfg(feature = "transcoding-cache")]
+        assert!(t
+            .cache_key("01-file.mp3", QualityLevel::Low, None)
+            .await
+            .contains("/speed=1.5#"));
+    }
+
     #[tokio::test]
     async fn test_transcode() {
         dummy_transcode(
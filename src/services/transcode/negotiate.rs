@@ -0,0 +1,205 @@
+use super::{QualityLevel, TranscodingFormat};
+use crate::config::get_config;
+use crate::util::guess_mime_type;
+use std::path::Path;
+
+/// Value of `trans` parameter, which asks server to choose transcoding
+pub const AUTO_PARAM: &str = "auto";
+
+/// No transcoding nor original file can be played by client
+#[derive(Debug, PartialEq)]
+pub struct NotAcceptable;
+
+/// What client can play - codecs (from `caps` parameter or `Accept` header) and available bandwidth
+#[derive(Debug, Default)]
+pub struct ClientCaps {
+    // lowercase codec names, format names or mime types, empty means anything
+    codecs: Vec<String>,
+    // in kbps
+    bandwidth: Option<u32>,
+}
+
+impl ClientCaps {
+    pub fn new(caps: Option<&str>, accept: Option<&str>, bandwidth: Option<u32>) -> Self {
+        let mut codecs: Vec<String> = caps
+            .into_iter()
+            .flat_map(|c| c.split(','))
+            .map(|c| c.trim().to_lowercase())
+            .filter(|c| !c.is_empty())
+            .collect();
+        codecs.extend(
+            accept
+                .into_iter()
+                .flat_map(|a| a.split(','))
+                .filter_map(parse_accepted_mime),
+        );
+        ClientCaps { codecs, bandwidth }
+    }
+
+    fn supports(&self, names: &[&str]) -> bool {
+        self.codecs.is_empty() || names.iter().any(|n| self.codecs.iter().any(|c| c == n))
+    }
+
+    fn fits(&self, bitrate: u32) -> bool {
+        self.bandwidth.map(|bw| bitrate <= bw).unwrap_or(true)
+    }
+
+    /// Chooses transcoding for given file, None means that file can be sent as it is,
+    /// `bitrate` is bitrate of original file in kbps, if known
+    pub fn choose<P: AsRef<Path>>(
+        &self,
+        file: P,
+        bitrate: Option<u32>,
+    ) -> Result<Option<QualityLevel>, NotAcceptable> {
+        let original_supported = self.supports(&original_names(file.as_ref()).as_names());
+        let original_fits = match (self.bandwidth, bitrate) {
+            (Some(_), None) => false,
+            (_, Some(b)) => self.fits(b),
+            (None, None) => true,
+        };
+        if original_supported && original_fits {
+            return Ok(None);
+        }
+
+        let transcoding = &get_config().transcoding;
+        let candidates = [QualityLevel::Low, QualityLevel::Medium, QualityLevel::High]
+            .iter()
+            .map(|l| (*l, transcoding.get(*l)))
+            .chain(
+                transcoding
+                    .profiles
+                    .iter()
+                    .map(|(name, p)| (QualityLevel::Profile(name.as_str()), p.format.clone())),
+            )
+            .filter(|(_, f)| self.supports(&format_names(f)))
+            .map(|(l, f)| (l, f.bitrate()))
+            .collect::<Vec<_>>();
+
+        let best = candidates
+            .iter()
+            .filter(|(_, b)| self.fits(*b))
+            .max_by_key(|(_, b)| *b)
+            .or_else(|| candidates.iter().min_by_key(|(_, b)| *b))
+            .map(|(l, _)| *l);
+
+        match best {
+            Some(l) => Ok(Some(l)),
+            None if original_supported => Ok(None),
+            None => {
+                debug!("No transcoding matches client capabilities {:?}", self);
+                Err(NotAcceptable)
+            }
+        }
+    }
+}
+
+fn parse_accepted_mime(item: &str) -> Option<String> {
+    let mut parts = item.split(';');
+    let mime = parts.next()?.trim().to_lowercase();
+    if mime.is_empty() || mime.contains('*') {
+        return None;
+    }
+    let refused = parts.any(|p| {
+        let p = p.trim();
+        p.starts_with("q=") && p[2..].parse::<f32>().map(|q| q <= 0.0).unwrap_or(false)
+    });
+    if refused {
+        None
+    } else {
+        Some(mime)
+    }
+}
+
+fn format_names(f: &TranscodingFormat) -> [&'static str; 3] {
+    let codec = match f {
+        TranscodingFormat::OpusInOgg(_) | TranscodingFormat::OpusInWebm(_) => "opus",
+        TranscodingFormat::Mp3(_) => "mp3",
+        TranscodingFormat::AacInAdts(_) => "aac",
+        TranscodingFormat::VorbisInOgg(_) => "vorbis",
+        TranscodingFormat::Flac(_) => "flac",
+        TranscodingFormat::Remux => "",
+    };
+    let mime = match f {
+        TranscodingFormat::Remux => "",
+        _ => f.mime_str(),
+    };
+    [f.format_name(), codec, mime]
+}
+
+struct OriginalNames {
+    extension: String,
+    mime: String,
+}
+
+impl OriginalNames {
+    fn as_names(&self) -> [&str; 3] {
+        let codec = match self.extension.as_str() {
+            "m4a" | "m4b" => "aac",
+            "ogg" | "oga" => "vorbis",
+            e => e,
+        };
+        [&self.extension, codec, &self.mime]
+    }
+}
+
+fn original_names(file: &Path) -> OriginalNames {
+    OriginalNames {
+        extension: file
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default(),
+        mime: guess_mime_type(file).essence_str().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::init::init_default_config;
+
+    #[test]
+    fn test_accept_parsing() {
+        let caps = ClientCaps::new(
+            Some("Opus, mp3"),
+            Some("audio/webm,audio/ogg;q=0.9,audio/flac;q=0,audio/*;q=0.8,*/*"),
+            None,
+        );
+        assert_eq!(vec!["opus", "mp3", "audio/webm", "audio/ogg"], caps.codecs);
+    }
+
+    #[test]
+    fn test_choose_transcoding() {
+        init_default_config();
+        let any = ClientCaps::default();
+        assert_eq!(Ok(None), any.choose("usak/chapter1.mp3", Some(128)));
+
+        let mp3_only = ClientCaps::new(Some("mp3"), None, None);
+        assert_eq!(Ok(None), mp3_only.choose("usak/chapter1.mp3", Some(128)));
+        // no default level is mp3 and client cannot play flac
+        assert_eq!(
+            Err(NotAcceptable),
+            mp3_only.choose("usak/chapter1.flac", Some(800))
+        );
+        // original is better then transcoding, which client cannot play
+        let mp3_slow = ClientCaps::new(Some("mp3"), None, Some(64));
+        assert_eq!(Ok(None), mp3_slow.choose("usak/chapter1.mp3", Some(128)));
+
+        let opus = ClientCaps::new(None, Some("audio/ogg"), Some(50));
+        assert_eq!(Ok(None), opus.choose("usak/chapter1.opus", Some(48)));
+        assert_eq!(
+            Ok(Some(QualityLevel::Medium)),
+            opus.choose("usak/chapter1.mp3", Some(128))
+        );
+        let slow = ClientCaps::new(Some("opus"), None, Some(20));
+        assert_eq!(
+            Ok(Some(QualityLevel::Low)),
+            slow.choose("usak/chapter1.opus", Some(64))
+        );
+        // unknown bitrate cannot be trusted to fit into bandwidth
+        let fast = ClientCaps::new(None, None, Some(1000));
+        assert_eq!(
+            Ok(Some(QualityLevel::High)),
+            fast.choose("usak/chapter1.mp3", None)
+        );
+    }
+}
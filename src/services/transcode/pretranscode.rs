@@ -0,0 +1,258 @@
+use super::{cache, filters::AudioFilters, queue::TranscodingQueue, QualityLevel};
+use super::{TimeSpan, Transcoder};
+use crate::config::{get_config, PretranscodeTask};
+use crate::error::{bail, Result};
+use crate::services::{
+    audio_folder::{list_dir, parse_chapter_path},
+    types::FoldersOrdering,
+    Counter,
+};
+use futures::prelude::*;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::task::spawn_blocking as blocking;
+
+lazy_static! {
+    // cache keys of files being pretranscoded
+    static ref PENDING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Transcodes files following `file` in its folder into transcoding cache in background,
+/// so they are ready when client continues to next file
+pub fn pretranscode_next(
+    file: PathBuf,
+    quality: QualityLevel,
+    filters: AudioFilters,
+    transcodings: Counter,
+) {
+    let count = get_config().transcoding.cache.pretranscode_next as usize;
+    if count == 0 || quality == QualityLevel::Passthrough || get_config().transcoding.cache.disabled
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        let next = match blocking(move || next_files(&file, count)).await {
+            Ok(Ok(next)) => next,
+            Ok(Err(e)) => {
+                warn!("Cannot list next files for pretranscoding: {}", e);
+                return;
+            }
+            Err(e) => {
+                error!("Panic when listing next files: {}", e);
+                return;
+            }
+        };
+        for f in next {
+            if !pretranscode(f, quality, filters, &transcodings).await {
+                break;
+            }
+        }
+    });
+}
+
+// next files in same order as client sees them in folder listing
+fn next_files(file: &Path, count: usize) -> io::Result<Vec<PathBuf>> {
+    let (dir, name) = match (file.parent(), file.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Ok(vec![]),
+    };
+    let folder = list_dir(dir, "", FoldersOrdering::Alphabetical)?;
+    Ok(folder
+        .files
+        .into_iter()
+        .filter(|f| f.section.is_none())
+        .skip_while(|f| f.path.as_os_str() != name)
+        .skip(1)
+        .take(count)
+        .map(|f| dir.join(f.path))
+        .collect())
+}
+
+async fn is_cached(
+    transcoder: &Transcoder,
+    file: &Path,
+    quality: QualityLevel,
+    span: Option<TimeSpan>,
+) -> Result<bool> {
+    let key = transcoder.cache_key(file, quality, span).await;
+    Ok(cache::lookup(key).await?.is_some())
+}
+
+// returns false, if next files should not be pretranscoded
+async fn pretranscode(
+    file: PathBuf,
+    quality: QualityLevel,
+    filters: AudioFilters,
+    transcodings: &Counter,
+) -> bool {
+    let transcoder = get_config().transcoder(quality).with_filters(filters);
+    match is_cached(&transcoder, &file, quality, None).await {
+        Ok(true) => return true,
+        Ok(false) => (),
+        Err(e) => {
+            error!("Cache lookup error: {}", e);
+            return false;
+        }
+    }
+    let key = transcoder.cache_key(&file, quality, None).await;
+    if !PENDING.lock().unwrap().insert(key.clone()) {
+        return true;
+    }
+    let done = if transcodings.try_acquire_background().is_some() {
+        debug!("Pretranscoding {:?}", file);
+        match transcoder
+            .transcode_to_cache(file, None, transcodings.clone(), quality)
+            .await
+        {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Pretranscoding failed: {}", e);
+                false
+            }
+        }
+    } else {
+        debug!("No free transcoding slot for pretranscoding of {:?}", file);
+        false
+    };
+    PENDING.lock().unwrap().remove(&key);
+    done
+}
+
+struct PretranscodeItem {
+    file: PathBuf,
+    span: Option<TimeSpan>,
+    // in ms
+    duration: u64,
+}
+
+fn collect_items(
+    base_dir: &Path,
+    path: &Path,
+    items: &mut Vec<PretranscodeItem>,
+) -> io::Result<()> {
+    let folder = list_dir(base_dir, path, FoldersOrdering::Alphabetical)?;
+    for f in folder.files {
+        let (file, span) = parse_chapter_path(&f.path);
+        let duration = f
+            .meta
+            .map(|m| u64::from(m.duration) * 1000)
+            .or_else(|| span.and_then(|s| s.duration))
+            .unwrap_or(0);
+        items.push(PretranscodeItem {
+            file: base_dir.join(file),
+            span,
+            duration,
+        })
+    }
+    for sub in folder.subfolders {
+        if let Err(e) = collect_items(base_dir, &sub.path, items) {
+            warn!("Cannot list folder {:?}: {}", sub.path, e);
+        }
+    }
+    Ok(())
+}
+
+/// Counts of files processed by bulk pretranscoding
+#[derive(Debug, Default)]
+pub struct PretranscodeStats {
+    pub transcoded: usize,
+    pub cached: usize,
+    pub failed: usize,
+    /// not transcoded, because they would not fit into cache
+    pub skipped: usize,
+}
+
+/// Transcodes all files and chapters of folder in collection (with all subfolders)
+/// into transcoding cache, progress is printed to stdout
+pub async fn pretranscode_collection(task: &PretranscodeTask) -> Result<PretranscodeStats> {
+    let cfg = get_config();
+    let quality = match QualityLevel::from_param(&task.quality) {
+        Some(q) => q,
+        None => bail!("Unknown transcoding {}", task.quality),
+    };
+    let base_dir = cfg.base_dirs[task.collection].clone();
+    let path = task.path.clone();
+    let mut items = blocking(move || {
+        let mut items = vec![];
+        collect_items(&base_dir, &path, &mut items).map(|_| items)
+    })
+    .await??;
+
+    // whole batch must fit into cache, otherwise it would evict its own files
+    let bitrate = u64::from(cfg.transcoding.get(quality).bitrate());
+    let max_size = u64::from(cfg.transcoding.cache.max_size) * 1024 * 1024;
+    let max_files = cfg.transcoding.cache.max_files as usize;
+    let mut size = 0;
+    let fitting = items
+        .iter()
+        .take(max_files)
+        .take_while(|i| {
+            size += bitrate * i.duration / 8;
+            size <= max_size
+        })
+        .count();
+    let stats = PretranscodeStats {
+        skipped: items.len() - fitting,
+        ..Default::default()
+    };
+    if stats.skipped > 0 {
+        warn!(
+            "Only {} of {} files fit into transcoding cache",
+            fitting,
+            items.len()
+        );
+    }
+    items.truncate(fitting);
+
+    let total = items.len();
+    println!("Pretranscoding {} files", total);
+    let queue = Arc::new(TranscodingQueue::new(task.parallel));
+    let processed = AtomicUsize::new(0);
+    let stats = Mutex::new(stats);
+    stream::iter(items)
+        .for_each_concurrent(task.parallel, |item| {
+            let queue = queue.clone();
+            let processed = &processed;
+            let stats = &stats;
+            async move {
+                let transcoder = get_config().transcoder(quality);
+                let res = match is_cached(&transcoder, &item.file, quality, item.span).await {
+                    Ok(true) => Ok(false),
+                    Ok(false) => match queue.acquire(None, &item.file, false).await {
+                        Ok(_) => transcoder
+                            .transcode_to_cache(item.file.clone(), item.span, queue, quality)
+                            .await
+                            .map(|_| true),
+                        Err(e) => Err(crate::error::Error::msg(format!(
+                            "No transcoding slot: {:?}",
+                            e
+                        ))),
+                    },
+                    Err(e) => Err(e),
+                };
+                let n = processed.fetch_add(1, Ordering::SeqCst) + 1;
+                let mut stats = stats.lock().unwrap();
+                let status = match res {
+                    Ok(true) => {
+                        stats.transcoded += 1;
+                        "transcoded".to_string()
+                    }
+                    Ok(false) => {
+                        stats.cached += 1;
+                        "already in cache".to_string()
+                    }
+                    Err(e) => {
+                        stats.failed += 1;
+                        format!("failed: {}", e)
+                    }
+                };
+                let span = item.span.map(|s| format!(" {}", s)).unwrap_or_default();
+                println!("[{}/{}] {:?}{} {}", n, total, item.file, span, status);
+            }
+        })
+        .await;
+    Ok(stats.into_inner().unwrap())
+}
@@ -0,0 +1,303 @@
+use crate::config::get_config;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{timeout_at, Instant};
+
+#[derive(Debug, PartialEq)]
+pub enum QueueError {
+    Full,
+    Timeout,
+}
+
+struct Waiter {
+    id: u64,
+    client: Option<IpAddr>,
+    priority: bool,
+}
+
+#[derive(Default)]
+struct QueueState {
+    waiting: Vec<Waiter>,
+    next_id: u64,
+    // last transcoded file of each client and when it started, used to prioritize continuation
+    // of playback
+    last_file: HashMap<IpAddr, (PathBuf, Instant)>,
+}
+
+impl QueueState {
+    // order of waiter - priority first, then position within its client's waiters (so clients
+    // take turns) and then arrival
+    fn order(&self, idx: usize) -> (bool, usize, u64) {
+        let w = &self.waiting[idx];
+        let client_position = self.waiting[..idx]
+            .iter()
+            .filter(|o| o.client == w.client)
+            .count();
+        (!w.priority, client_position, w.id)
+    }
+
+    fn is_next(&self, id: u64) -> bool {
+        (0..self.waiting.len())
+            .min_by_key(|&i| self.order(i))
+            .map(|i| self.waiting[i].id == id)
+            .unwrap_or(false)
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.waiting.retain(|w| w.id != id)
+    }
+
+    fn remember(&mut self, client: IpAddr, file: &Path, now: Instant) {
+        // after max runtime transcoding of the file is surely finished, so it can be forgotten
+        let max_age =
+            Duration::from_secs(u64::from(get_config().transcoding.max_runtime_hours) * 3600);
+        self.last_file
+            .retain(|_, (_, started)| now.duration_since(*started) < max_age);
+        self.last_file.insert(client, (file.to_owned(), now));
+    }
+}
+
+/// Limits number of running transcodings, requests over limit wait in bounded fair queue
+pub struct TranscodingQueue {
+    running: AtomicUsize,
+    max_running: usize,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+// removes waiter from queue, even if waiting future is dropped
+struct WaiterGuard<'a> {
+    queue: &'a TranscodingQueue,
+    id: u64,
+}
+
+impl<'a> Drop for WaiterGuard<'a> {
+    fn drop(&mut self) {
+        self.queue
+            .state
+            .lock()
+            .expect("Poisoned lock")
+            .remove(self.id);
+        // next in queue might changed
+        self.queue.notify.notify_waiters();
+    }
+}
+
+impl TranscodingQueue {
+    pub fn new(max_running: usize) -> Self {
+        TranscodingQueue {
+            running: AtomicUsize::new(0),
+            max_running,
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Limit is given on start, it's not changed by config reload
+    pub fn max_running(&self) -> usize {
+        self.max_running
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.state.lock().expect("Poisoned lock").waiting.len()
+    }
+
+    fn try_reserve(&self) -> Option<usize> {
+        self.try_reserve_up_to(self.max_running)
+    }
+
+    fn try_reserve_up_to(&self, limit: usize) -> Option<usize> {
+        let mut running = self.running.load(Ordering::SeqCst);
+        loop {
+            if running >= limit {
+                return None;
+            }
+            match self.running.compare_exchange(
+                running,
+                running + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(running + 1),
+                Err(curr) => running = curr,
+            }
+        }
+    }
+
+    fn reserved(state: &mut QueueState, client: Option<IpAddr>, file: &Path) {
+        if let Some(client) = client {
+            state.remember(client, file, Instant::now());
+        }
+    }
+
+    /// Waits for free transcoding slot, returns number of running transcodings (including this one).
+    /// `continuation` marks request, which continues already playing file, so it's prioritized
+    pub async fn acquire(
+        &self,
+        client: Option<IpAddr>,
+        file: &Path,
+        continuation: bool,
+    ) -> Result<usize, QueueError> {
+        let cfg = &get_config().transcoding.queue;
+        let id = {
+            let mut state = self.state.lock().expect("Poisoned lock");
+            if state.waiting.is_empty() {
+                if let Some(running) = self.try_reserve() {
+                    TranscodingQueue::reserved(&mut state, client, file);
+                    return Ok(running);
+                }
+            }
+            if state.waiting.len() >= cfg.max_length {
+                warn!("Transcoding queue is full");
+                return Err(QueueError::Full);
+            }
+            if client.is_some()
+                && state.waiting.iter().filter(|w| w.client == client).count() >= cfg.max_per_client
+            {
+                warn!(
+                    "Client {:?} has too many requests in transcoding queue",
+                    client
+                );
+                return Err(QueueError::Full);
+            }
+            let priority = continuation
+                || client
+                    .and_then(|c| state.last_file.get(&c))
+                    .map(|(f, _)| f == file)
+                    .unwrap_or(false);
+            let id = state.next_id;
+            state.next_id += 1;
+            state.waiting.push(Waiter {
+                id,
+                client,
+                priority,
+            });
+            debug!(
+                "Transcoding of {:?} queued, waiting {}",
+                file,
+                state.waiting.len()
+            );
+            id
+        };
+        let _guard = WaiterGuard { queue: self, id };
+        let deadline = Instant::now() + Duration::from_secs(u64::from(cfg.timeout));
+
+        loop {
+            // must be created before checking, so no notification is missed
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().expect("Poisoned lock");
+                if state.is_next(id) {
+                    if let Some(running) = self.try_reserve() {
+                        state.remove(id);
+                        TranscodingQueue::reserved(&mut state, client, file);
+                        return Ok(running);
+                    }
+                }
+            }
+            if timeout_at(deadline, notified).await.is_err() {
+                warn!("Timeout when waiting in transcoding queue for {:?}", file);
+                return Err(QueueError::Timeout);
+            }
+        }
+    }
+
+    /// Reserves slot for low priority background transcoding without waiting - only if no request
+    /// is waiting in queue and at least one slot remains free for client requests
+    pub fn try_acquire_background(&self) -> Option<usize> {
+        let state = self.state.lock().expect("Poisoned lock");
+        if !state.waiting.is_empty() {
+            return None;
+        }
+        self.try_reserve_up_to(self.max_running.saturating_sub(1))
+    }
+
+    /// Frees transcoding slot, must be called when transcoding ends
+    pub fn release(&self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::init::init_default_config;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_queue_order() {
+        let a: Option<IpAddr> = Some([10, 0, 0, 1].into());
+        let b: Option<IpAddr> = Some([10, 0, 0, 2].into());
+        let mut state = QueueState::default();
+        for (id, client, priority) in &[(0, a, false), (1, a, false), (2, b, false), (3, b, true)] {
+            state.waiting.push(Waiter {
+                id: *id,
+                client: *client,
+                priority: *priority,
+            });
+        }
+        assert!(state.is_next(3));
+        state.remove(3);
+        assert!(state.is_next(0));
+        state.remove(0);
+        // b has its first request waiting, while this is second request of a
+        assert!(state.is_next(2));
+    }
+
+    #[tokio::test]
+    async fn test_queue_acquire() {
+        init_default_config();
+        let a: Option<IpAddr> = Some([10, 0, 0, 1].into());
+        let q = Arc::new(TranscodingQueue::new(1));
+        assert_eq!(Ok(1), q.acquire(a, Path::new("a.mp3"), false).await);
+        let q2 = q.clone();
+        let waiting = tokio::spawn(async move { q2.acquire(a, Path::new("b.mp3"), false).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(1, q.waiting());
+        q.release();
+        assert_eq!(Ok(1), waiting.await.unwrap());
+        assert_eq!(0, q.waiting());
+        assert_eq!(1, q.running());
+        assert_eq!(1, q.state.lock().unwrap().last_file.len());
+    }
+
+    #[test]
+    fn test_last_file_expires() {
+        init_default_config();
+        let a: IpAddr = [10, 0, 0, 1].into();
+        let b: IpAddr = [10, 0, 0, 2].into();
+        let mut state = QueueState::default();
+        let now = Instant::now();
+        state.remember(a, Path::new("a.mp3"), now);
+        state.remember(b, Path::new("b.mp3"), now + Duration::from_secs(3600));
+        assert_eq!(2, state.last_file.len());
+        state.remember(b, Path::new("c.mp3"), now + Duration::from_secs(25 * 3600));
+        assert!(!state.last_file.contains_key(&a));
+        assert_eq!(Path::new("c.mp3"), state.last_file[&b].0);
+    }
+
+    #[test]
+    fn test_background_acquire() {
+        let q = TranscodingQueue::new(2);
+        assert_eq!(Some(1), q.try_acquire_background());
+        // last slot is left for client requests
+        assert_eq!(None, q.try_acquire_background());
+        q.release();
+        q.state.lock().unwrap().waiting.push(Waiter {
+            id: 0,
+            client: None,
+            priority: false,
+        });
+        assert_eq!(None, q.try_acquire_background());
+    }
+}
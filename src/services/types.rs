@@ -1,8 +1,9 @@
-use super::transcode::{QualityLevel, TranscodingFormat};
+use super::transcode::{queue::TranscodingQueue, QualityLevel, TranscodingFormat};
 use crate::config::get_config;
 use crate::util::{guess_mime_type, os_to_string};
 use mime::Mime;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use unicase::UniCase;
@@ -137,6 +138,7 @@ pub struct Collections {
     pub shared_positions: bool,
     pub count: u32,
     pub names: Vec<&'static str>,
+    pub numbers: Vec<u32>,
 }
 
 #[derive(Debug, Serialize)]
@@ -157,23 +159,77 @@ impl From<TranscodingFormat> for TranscodingSummary {
 #[derive(Debug, Serialize)]
 pub struct Transcodings {
     pub max_transcodings: usize,
+    pub running_transcodings: usize,
+    /// number of requests waiting for transcoding
+    pub queue_length: usize,
+    pub max_queue_length: usize,
     pub low: TranscodingSummary,
     pub medium: TranscodingSummary,
     pub high: TranscodingSummary,
+    pub profiles: BTreeMap<&'static str, TranscodingSummary>,
 }
 
 impl Transcodings {
-    pub fn new() -> Self {
+    pub fn new(queue: &TranscodingQueue) -> Self {
         let cfg = get_config();
         Transcodings {
-            max_transcodings: cfg.transcoding.max_parallel_processes,
+            max_transcodings: queue.max_running(),
+            running_transcodings: queue.running(),
+            queue_length: queue.waiting(),
+            max_queue_length: cfg.transcoding.queue.max_length,
             low: cfg.transcoding.get(QualityLevel::Low).into(),
             medium: cfg.transcoding.get(QualityLevel::Medium).into(),
             high: cfg.transcoding.get(QualityLevel::High).into(),
+            profiles: cfg
+                .transcoding
+                .profiles
+                .iter()
+                .map(|(name, profile)| (name.as_str(), profile.format.clone().into()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "transcoding-cache")]
+#[derive(Debug, Serialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub size: u64,
+    /// unix time (in secs) of last access
+    pub last_access: Option<u64>,
+}
+
+#[cfg(feature = "transcoding-cache")]
+impl From<simple_file_cache::EntryInfo> for CacheEntry {
+    fn from(e: simple_file_cache::EntryInfo) -> Self {
+        CacheEntry {
+            key: e.key,
+            size: e.size,
+            last_access: e
+                .last_access
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
         }
     }
 }
 
+/// Transcoding cache usage with entries from least recently used
+#[cfg(feature = "transcoding-cache")]
+#[derive(Debug, Serialize)]
+pub struct CacheInfo {
+    pub files: u64,
+    pub max_files: u64,
+    pub size: u64,
+    pub max_size: u64,
+    pub entries: Vec<CacheEntry>,
+}
+
+#[cfg(feature = "transcoding-cache")]
+#[derive(Debug, Serialize)]
+pub struct CachePurged {
+    pub removed: u64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AudioFolder {
     pub files: Vec<AudioFile>,
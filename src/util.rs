@@ -2,11 +2,13 @@ use headers::{Header, HeaderMapExt};
 use hyper::http::response::Builder;
 use mime_guess::{self, Mime};
 use std::cmp::{max, min};
-use std::fs::DirEntry;
+use std::fs::{self, DirEntry};
+use std::io::Write;
 use std::{
     io,
     ops::{Bound, RangeBounds},
     path::Path,
+    time::UNIX_EPOCH,
 };
 
 pub fn guess_mime_type<P: AsRef<Path>>(path: P) -> Mime {
@@ -30,6 +32,31 @@ pub fn parent_dir_exists<P: AsRef<Path>>(p: &P) -> bool {
     }
 }
 
+/// Version of file content - its size and modification time (in ns) as `size|mtime`
+pub fn file_version(meta: &fs::Metadata) -> String {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("{}|{}", meta.len(), mtime)
+}
+
+/// Writes file via temporary file and rename, so file is never left partially written
+pub fn write_atomically<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = Path::new(&tmp_name);
+    {
+        let mut f = fs::File::create(tmp_path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+    }
+    fs::rename(tmp_path, path)
+}
+
 pub fn checked_dec(x: u64) -> u64 {
     if x > 0 {
         x - 1